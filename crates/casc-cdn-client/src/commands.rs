@@ -0,0 +1,581 @@
+//! The subcommands, each printing text or with `--json` a JSON document
+
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+use anyhow::{Context, Result, bail};
+use casc::{
+    CascClient, ContentKey, EncodingKey, FileDataID, Md5Key, PipeSeparatedVars,
+    audit::AuditOptions,
+    bgdl,
+    cache::CacheByKey,
+    cdn::{CdnPool, Mirror},
+    diff::{self, Change, FileVersion},
+    download,
+    extract::ExtractOptions,
+    local, product, ribbit, source, tact_psv,
+    transport::Transport,
+};
+use serde_json::{Value, json};
+
+/// Global options shared by every command
+pub struct Cli {
+    pub product: String,
+    pub region: String,
+    pub cache: CacheByKey,
+    pub transport: Arc<dyn Transport>,
+    pub mirrors: Vec<Mirror>,
+    /// A local install's `Data/data` directory to read blobs from first
+    pub local: Option<PathBuf>,
+    pub download_options: download::DownloadOptions,
+    pub json: bool,
+}
+
+impl Cli {
+    fn psv(&self, endpoint: &str) -> Result<PipeSeparatedVars> {
+        tact_psv(
+            &*self.transport,
+            &self.cache,
+            &self.product,
+            &self.region,
+            endpoint,
+        )
+    }
+
+    fn cdn(&self) -> Result<CdnPool> {
+        let cdns = self.psv("cdns")?;
+        CdnPool::from_cdns(self.transport.clone(), &cdns, &self.region, &self.mirrors)
+    }
+
+    fn client(&self) -> Result<CascClient> {
+        self.client_for(None)
+    }
+
+    /// A client for the build `version` names, see [`casc::find_version`]
+    fn client_for(&self, version: Option<&str>) -> Result<CascClient> {
+        self.with_local(self.builder(version).build()?)
+    }
+
+    fn builder(&self, version: Option<&str>) -> casc::CascClientBuilder {
+        let mut builder = CascClient::builder(&self.product)
+            .region(&self.region)
+            .cache(self.cache.clone())
+            .transport(self.transport.clone());
+        for mirror in &self.mirrors {
+            builder = builder.mirror(mirror.clone());
+        }
+        if let Some(version) = version {
+            builder = builder.version(version);
+        }
+        builder
+    }
+
+    /// Chains `--local` in front of the client's source
+    fn with_local(&self, client: CascClient) -> Result<CascClient> {
+        Ok(match &self.local {
+            Some(local) => {
+                let local = Arc::new(local::LocalCascSource::open(local)?);
+                let fallback = client.source();
+                client.with_source(Arc::new(source::ChainedSource::new(vec![local, fallback])))
+            }
+            None => client,
+        })
+    }
+
+    /// Prints `value` as JSON, or runs `text` to print it for humans
+    fn output(&self, value: impl FnOnce() -> Value, text: impl FnOnce()) -> Result<()> {
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(&value())?);
+        } else {
+            text();
+        }
+        Ok(())
+    }
+
+    fn print_psv(&self, psv: &PipeSeparatedVars) -> Result<()> {
+        let headings = psv
+            .headings()
+            .map(|x| x.split('!').next().unwrap_or(x))
+            .collect::<Vec<_>>();
+        self.output(
+            || {
+                psv.entries()
+                    .map(|row| -> Value {
+                        headings
+                            .iter()
+                            .zip(row)
+                            .map(|(k, v)| (k.to_string(), Value::from(v)))
+                            .collect::<serde_json::Map<_, _>>()
+                            .into()
+                    })
+                    .collect()
+            },
+            || {
+                println!("{}", headings.join("|"));
+                for row in psv.entries() {
+                    println!("{}", row.collect::<Vec<_>>().join("|"));
+                }
+            },
+        )
+    }
+}
+
+pub fn versions(cli: &Cli) -> Result<()> {
+    cli.print_psv(&cli.psv("versions")?)
+}
+
+pub fn cdns(cli: &Cli) -> Result<()> {
+    cli.print_psv(&cli.psv("cdns")?)
+}
+
+/// Prints a build or CDN config by key
+pub fn config(cli: &Cli, key: &str) -> Result<()> {
+    let key = ContentKey::from_str(key)?.to_string();
+    let data = cli.cache.get(&cli.cdn()?, "config", &key)?;
+    let text = String::from_utf8(data).context("config is not utf-8")?;
+    let config = casc::config::Config::parse(&text)?;
+    cli.output(
+        || {
+            config
+                .iter()
+                .map(|(k, v)| (k.to_owned(), Value::from(v)))
+                .collect()
+        },
+        || print!("{text}"),
+    )
+}
+
+/// Lists install entries, or root or TVFS entries with `root` or `tvfs`
+pub fn ls(cli: &Cli, what: Option<&str>) -> Result<()> {
+    let client = cli.client()?;
+    match what.unwrap_or("install") {
+        "install" => {
+            let files = &client.install().files;
+            cli.output(
+                || {
+                    files
+                        .iter()
+                        .map(|x| json!({"name": x.name, "ckey": x.key, "size": x.size}))
+                        .collect()
+                },
+                || {
+                    for x in files {
+                        println!("{} {:>10} {}", x.key, x.size, x.name);
+                    }
+                },
+            )
+        }
+        "root" => {
+            let root = client.root()?;
+            cli.output(
+                || {
+                    root.entries
+                        .iter()
+                        .map(|x| {
+                            json!({
+                                "fdid": x.fdid,
+                                "ckey": x.ckey,
+                                "locale_flags": x.locale_flags,
+                                "content_flags": x.content_flags,
+                                "name_hash": x.name_hash.map(|x| format!("{x:016x}")),
+                            })
+                        })
+                        .collect()
+                },
+                || {
+                    for x in &root.entries {
+                        let name_hash = x.name_hash.map(|x| format!("{x:016x}"));
+                        println!(
+                            "{:>9} {} {:08x} {:08x} {}",
+                            x.fdid,
+                            x.ckey,
+                            x.locale_flags,
+                            x.content_flags,
+                            name_hash.as_deref().unwrap_or("-"),
+                        );
+                    }
+                },
+            )
+        }
+        "tvfs" => {
+            let files = client.tvfs_files()?;
+            cli.output(
+                || {
+                    files
+                        .iter()
+                        .map(|x| {
+                            let spans = x
+                                .spans
+                                .iter()
+                                .map(|x| json!({"ekey": x.ekey, "content_size": x.content_size, "encoded_size": x.encoded_size}))
+                                .collect::<Vec<_>>();
+                            json!({"path": x.path, "content_size": x.content_size(), "spans": spans})
+                        })
+                        .collect()
+                },
+                || {
+                    for x in &files {
+                        let ekeys = x.spans.iter().map(|x| x.ekey.to_string()).collect::<Vec<_>>();
+                        println!("{:>10} {} {}", x.content_size(), ekeys.join(","), x.path);
+                    }
+                },
+            )
+        }
+        other => bail!("unknown listing {other}, expected install, root or tvfs"),
+    }
+}
+
+/// Writes a file to stdout, named by ckey, ekey, FileDataID or path
+pub fn cat(cli: &Cli, target: &str) -> Result<()> {
+    let client = cli.client()?;
+    let data = if let Ok(key) = ContentKey::from_str(target) {
+        // a hex key is a ckey if encoding knows it, otherwise an ekey
+        match client.encoding().c2e(key) {
+            Ok(_) => client.get_by_ckey(key)?,
+            Err(_) => client.get_by_ekey(key.cast::<EncodingKey>())?,
+        }
+    } else if let Ok(fdid) = target.parse::<u32>() {
+        let root = client.root()?;
+        let entry = root
+            .by_fdid(FileDataID(fdid))
+            .with_context(|| format!("FileDataID {fdid} not in root"))?;
+        client.get_by_ckey(entry.ckey)?
+    } else if let Some(file) = client
+        .install()
+        .files
+        .iter()
+        .find(|x| x.name.eq_ignore_ascii_case(target))
+    {
+        client.get_by_ckey(file.key)?
+    } else {
+        let root = client.root()?;
+        let entry = root
+            .by_path(target)
+            .with_context(|| format!("{target} not in install or root"))?;
+        client.get_by_ckey(entry.ckey)?
+    };
+    std::io::Write::write_all(&mut std::io::stdout().lock(), &data)?;
+    Ok(())
+}
+
+/// Writes the install entries `options` selects under its output directory
+pub fn extract(cli: &Cli, options: &ExtractOptions) -> Result<()> {
+    let report = cli.client()?.extract(options)?;
+    cli.output(
+        || {
+            json!({
+                "written": report.written.iter().map(|(name, _)| name).collect::<Vec<_>>(),
+                "up_to_date": report.up_to_date.iter().map(|(name, _)| name).collect::<Vec<_>>(),
+                "written_bytes": report.written_bytes,
+            })
+        },
+        || {
+            println!(
+                "Wrote {} files ({} bytes) to {}, {} already up to date",
+                report.written.len(),
+                report.written_bytes,
+                options.output.display(),
+                report.up_to_date.len(),
+            )
+        },
+    )
+}
+
+/// Copies a whole build into `output` in the CDN's layout
+pub fn mirror(cli: &Cli, version: Option<&str>, output: &Path) -> Result<()> {
+    let report = casc::mirror::mirror(
+        cli.transport.clone(),
+        cli.cache.clone(),
+        &cli.product,
+        &cli.region,
+        version,
+        &cli.mirrors,
+        output,
+        &cli.download_options,
+    )?;
+    let version = report.version.as_ref().map(|x| x.versions_name.as_str());
+    cli.output(
+        || {
+            json!({
+                "version": version,
+                "root": report.root.display().to_string(),
+                "files": report.files,
+                "already_present": report.already_present,
+                "copied_from_cache": report.copied_from_cache,
+                "downloaded": report.downloaded,
+                "bytes": report.bytes,
+                "failed": report.failed.iter().map(|(path, e)| json!({"path": path, "error": e})).collect::<Vec<_>>(),
+            })
+        },
+        || {
+            println!(
+                "Mirrored {} to {}: {} files, {} already present, {} from cache, {} downloaded ({} bytes)",
+                version.unwrap_or_default(),
+                report.root.display(),
+                report.files,
+                report.already_present,
+                report.copied_from_cache,
+                report.downloaded,
+                report.bytes,
+            );
+            for (path, e) in &report.failed {
+                println!("failed {path}: {e}");
+            }
+        },
+    )?;
+    if !report.failed.is_empty() {
+        bail!("{} files could not be mirrored", report.failed.len());
+    }
+    Ok(())
+}
+
+fn change_json<K: std::fmt::Display>(x: &Change<K>) -> Value {
+    let version = |x: Option<FileVersion>| x.map(|x| json!({"ckey": x.ckey, "size": x.size}));
+    json!({
+        "id": x.id.to_string(),
+        "change": x.kind().to_string(),
+        "old": version(x.old),
+        "new": version(x.new),
+    })
+}
+
+fn summary_json<K>(changes: &[Change<K>]) -> Value {
+    let (added, removed, modified) = diff::count(changes);
+    json!({"added": added, "removed": removed, "modified": modified})
+}
+
+fn print_changes<K: std::fmt::Display>(title: &str, changes: &[Change<K>]) {
+    let (added, removed, modified) = diff::count(changes);
+    println!("{title}: {added} added, {removed} removed, {modified} modified");
+    for x in changes {
+        let size = |x: Option<FileVersion>| x.map_or("-".to_owned(), |x| x.size.to_string());
+        println!(
+            "  {:<8} {:>10} -> {:<10} {}",
+            x.kind(),
+            size(x.old),
+            size(x.new),
+            x.id
+        );
+    }
+}
+
+/// Compares two builds, the current one if `new` is omitted
+pub fn diff(cli: &Cli, old: &str, new: Option<&str>) -> Result<()> {
+    let diff = diff::diff(&cli.client_for(Some(old))?, &cli.client_for(new)?)?;
+    let encoding = &diff.encoding;
+    cli.output(
+        || {
+            json!({
+                "old": diff.old.versions_name,
+                "new": diff.new.versions_name,
+                "download_bytes": diff.download_bytes(),
+                "encoding": {
+                    "added": encoding.added,
+                    "removed": encoding.removed,
+                    "added_bytes": encoding.added_bytes,
+                    "removed_bytes": encoding.removed_bytes,
+                },
+                "install": {
+                    "summary": summary_json(&diff.install),
+                    "changes": diff.install.iter().map(change_json).collect::<Vec<_>>(),
+                },
+                "root": diff.root.as_ref().map(|root| json!({
+                    "summary": summary_json(root),
+                    "changes": root.iter().map(change_json).collect::<Vec<_>>(),
+                })),
+            })
+        },
+        || {
+            println!("{} -> {}", diff.old.versions_name, diff.new.versions_name);
+            println!(
+                "encoding: {} ekeys added ({} bytes), {} removed ({} bytes)",
+                encoding.added, encoding.added_bytes, encoding.removed, encoding.removed_bytes
+            );
+            println!("download: {} bytes", diff.download_bytes());
+            print_changes("install", &diff.install);
+            if let Some(root) = &diff.root {
+                print_changes("root", root);
+            }
+        },
+    )
+}
+
+/// Prints the build's configs and encoding and install statistics
+pub fn info(cli: &Cli) -> Result<()> {
+    let client = cli.client()?;
+    let encoding = client.encoding();
+    let install = &client.install().files;
+    let content_size = encoding.ckeys().map(|(_, _, size)| size).sum::<u64>();
+    let encoded_size = encoding.ekeys().map(|(_, size)| size).sum::<u64>();
+    let install_size = install.iter().map(|x| u64::from(x.size)).sum::<u64>();
+    let value = json!({
+        "product": client.product().code,
+        "build_name": client.build_config().get("build-name"),
+        "cdn": client.cdn().primary(),
+        "archives": client.cdn_config().words("archives").count(),
+        "encoding": {
+            "ckeys": encoding.ckey_count(),
+            "ekeys": encoding.ekey_count(),
+            "content_bytes": content_size,
+            "encoded_bytes": encoded_size,
+        },
+        "install": {
+            "files": install.len(),
+            "bytes": install_size,
+        },
+    });
+    cli.output(
+        || value.clone(),
+        || {
+            println!("product      {}", client.product().code);
+            if let Some(name) = client.build_config().get("build-name") {
+                println!("build        {name}");
+            }
+            println!("cdn          {}", client.cdn().primary());
+            println!(
+                "archives     {}",
+                client.cdn_config().words("archives").count()
+            );
+            println!(
+                "encoding     {} ckeys, {} ekeys, {content_size} bytes, {encoded_size} encoded",
+                encoding.ckey_count(),
+                encoding.ekey_count(),
+            );
+            println!("install      {} files, {install_size} bytes", install.len());
+        },
+    )
+}
+
+/// Serves the cache directory as a CDN on `addr` until killed
+pub fn serve(cli: &Cli, addr: &str) -> Result<()> {
+    casc::serve::CdnServer::bind(addr, cli.cache.clone(), &cli.region)?.run()
+}
+
+/// Checks the cache, or the whole build or its install files against the CDN
+pub fn verify(cli: &Cli, what: Option<&str>, fetch: bool) -> Result<()> {
+    match what {
+        None | Some("cache") => return verify_cache(cli),
+        Some("build" | "install") => {}
+        Some(other) => bail!("can't verify {other}, expected cache, build or install"),
+    }
+    let client = cli.with_local(cli.builder(None).build()?.with_archive_index()?)?;
+    let ckeys =
+        (what == Some("install")).then(|| client.install().files.iter().map(|x| x.key).collect());
+    let options = AuditOptions {
+        ckeys,
+        fetch,
+        concurrency: cli.download_options.concurrency,
+    };
+    let report = client.audit(&options)?;
+    cli.output(
+        || {
+            json!({
+                "checked": report.checked,
+                "archived": report.archived,
+                "loose": report.loose,
+                "bytes": report.bytes,
+                "failures": report.failures.iter().map(|x| json!({
+                    "ckey": x.ckey.to_string(),
+                    "ekey": x.ekey.map(|x| x.to_string()),
+                    "error": x.error,
+                })).collect::<Vec<_>>(),
+            })
+        },
+        || {
+            for x in &report.failures {
+                println!("failed {} {}", x.ckey, x.error);
+            }
+            println!(
+                "{} ckeys checked, {} archived, {} loose, {} bytes fetched, {} failed",
+                report.checked,
+                report.archived,
+                report.loose,
+                report.bytes,
+                report.failures.len()
+            );
+        },
+    )?;
+    if !report.failures.is_empty() {
+        bail!("{} ckeys failed verification", report.failures.len());
+    }
+    Ok(())
+}
+
+/// Checks every cached config and data entry against its key
+fn verify_cache(cli: &Cli) -> Result<()> {
+    // archives can't be checked by name, only CDN configs say which they are
+    cli.cache.add_cached_archives()?;
+    let mut checked = 0;
+    let mut corrupt = vec![];
+    for kind in ["config", "data"] {
+        for key in cli.cache.keys(kind)? {
+            // not `read`, which would evict corrupt entries before we see them
+            let Some(mut file) = cli.cache.open(kind, &key)? else {
+                continue;
+            };
+            let mut data = vec![];
+            file.read_to_end(&mut data)?;
+            checked += 1;
+            if let Err(e) = cli.cache.verify(kind, &key, &data) {
+                tracing::warn!("{e:#}");
+                corrupt.push(format!("{kind}/{key}"));
+            }
+        }
+    }
+    cli.output(
+        || json!({"checked": checked, "corrupt": corrupt}),
+        || {
+            for entry in &corrupt {
+                println!("corrupt {entry}");
+            }
+            println!("{checked} entries checked, {} corrupt", corrupt.len());
+        },
+    )?;
+    if !corrupt.is_empty() {
+        bail!("{} corrupt cache entries", corrupt.len());
+    }
+    Ok(())
+}
+
+/// Lists every product Ribbit knows about
+pub fn summary(cli: &Cli) -> Result<()> {
+    let summary = product::ProductSummary::fetch(&ribbit::RibbitClient::for_region(
+        &cli.region,
+        ribbit::Version::V1,
+    ))?;
+    let products = summary.products();
+    cli.output(
+        || json!(products),
+        || {
+            for product in &products {
+                println!("{product}");
+            }
+        },
+    )
+}
+
+/// Prefetches builds the `bgdl` endpoint announces
+pub fn bgdl(cli: &Cli) -> Result<()> {
+    for pending in bgdl::pending_builds(&*cli.transport, &cli.cache, &cli.product, &cli.region)? {
+        let stats = bgdl::prefetch(
+            cli.transport.clone(),
+            cli.cache.clone(),
+            &cli.product,
+            &cli.region,
+            &cli.mirrors,
+            &pending,
+            &cli.download_options,
+        )?;
+        tracing::info!("Prefetched {stats:?}");
+    }
+    Ok(())
+}
+
+/// Downloads the client executables to `root/`
+pub fn binaries(cli: &Cli) -> Result<()> {
+    cli.client()?.get_client_binaries(&cli.download_options)
+}
@@ -1,398 +1,81 @@
-pub(crate) fn md5hash(p: &[u8]) -> u128 {
-    use md5::{Digest, Md5, digest::FixedOutput};
-    let mut hasher = Md5::new();
-    hasher.update(p);
-    u128::from_be_bytes(hasher.finalize_fixed().into())
-}
-
-use derive_more::Display;
-use reqwest::{Error, blocking::Response};
-
-#[derive(Clone, Copy, Debug, Display, Eq, Hash, PartialEq)]
-#[display("{:032x}", _0)]
-pub(crate) struct ArchiveKey(pub(crate) u128);
-
-#[derive(Clone, Copy, Debug, Display, Eq, Hash, PartialEq)]
-#[display("{:032x}", _0)]
-pub(crate) struct ContentKey(pub(crate) u128);
-
-#[derive(Clone, Copy, Debug, Display, Eq, Hash, PartialEq, Default)]
-#[display("{:032x}", _0)]
-#[repr(transparent)]
-pub(crate) struct EncodingKey(pub(crate) u128);
-
-#[derive(Clone, Copy, Eq, Hash, PartialEq)]
-pub(crate) struct FileDataID(pub(crate) u32);
-
-pub mod blte;
-pub mod install;
-
-static APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"),);
-
-#[tracing::instrument]
-fn fetch(url: &str) -> Result<Response, Error> {
-    tracing::debug!("Fetching");
-    reqwest::blocking::ClientBuilder::new()
-        // .http3_prior_knowledge()
-        .user_agent(APP_USER_AGENT)
-        .build()
-        .unwrap()
-        .get(url)
-        // .version(reqwest::Version::HTTP_2)
-        .send()
-}
-
-#[derive(Debug)]
-struct CacheByKey {
-    path: PathBuf,
-}
-
-impl CacheByKey {
-    #[tracing::instrument(err, skip(self))]
-    fn get(&self, url: &str, kind: &str, key: &str) -> Result<Vec<u8>> {
-        tracing::info!("Retrieving {kind}/{key}");
-        let formatted_key = format_hex_key(key);
-        let mut keyed_path = self.path.join(kind);
-        keyed_path.push(&formatted_key);
-        let file = std::fs::read(&keyed_path);
-        if let Ok(file) = file {
-            tracing::debug!("Cache hit");
-            return Ok(file);
-        }
-        tracing::debug!("Cache miss");
-        let data = reqwest::blocking::ClientBuilder::new()
-            // .http3_prior_knowledge()
-            .user_agent(APP_USER_AGENT)
-            .build()
-            .unwrap()
-            .get(url)
-            .send()?
-            .error_for_status()?
-            .bytes()?;
-        std::fs::create_dir_all(keyed_path.parent().unwrap())?;
-        std::fs::write(keyed_path, &data)?;
-        Ok(data.to_vec())
-    }
-
-    fn new(arg: impl AsRef<Path>) -> Self {
-        Self {
-            path: arg.as_ref().to_owned(),
-        }
-    }
-}
-
-struct PipeSeparatedVars {
-    storage: String,
-    headings: Vec<Range<usize>>,
-    entries: Vec<Vec<Range<usize>>>,
-    meta: String,
-}
-
-impl PipeSeparatedVars {
-    fn headings(&self) -> impl Iterator<Item = &str> {
-        self.headings.iter().map(|x| &self.storage[x.to_owned()])
-    }
-
-    fn entries(&self) -> impl Iterator<Item = impl Iterator<Item = &str>> {
-        self.entries
-            .iter()
-            .map(|x| x.iter().map(|y| &self.storage[y.to_owned()]))
-    }
-}
-
-impl Debug for PipeSeparatedVars {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("PipeSeparatedVars")
-            //.field("storage", &self.storage)
-            .field("meta", &self.meta)
-            .field("headings", &self.headings().collect::<Vec<_>>())
-            .field(
-                "entries",
-                &self
-                    .entries()
-                    .map(|x| x.collect::<Vec<_>>().join(" | "))
-                    .collect::<Vec<_>>(),
-            )
-            .finish()
-    }
-}
-
-fn format_hex_key(hex: &str) -> String {
-    format!("{}/{}/{hex}", &hex[0..2], &hex[2..4])
-}
-
-fn trimmed_index(backing: &str, needle: &str) -> Range<usize> {
-    let needle = needle.trim();
-    let start = unsafe { needle.as_ptr().byte_offset_from(backing.as_ptr()) } as usize;
-    assert!(start < backing.len() && start + needle.len() < backing.len());
-    start..start + needle.len()
-}
-
-fn load_pipe_separated_vars(backing: String) -> PipeSeparatedVars {
-    let mut lines = backing.lines();
-    let header = lines.next().unwrap();
-    let headings: Vec<_> = header
-        .split('|')
-        .map(|x| trimmed_index(&backing, x))
-        .collect();
-    let mut meta = "".to_owned();
-    let mut entries = vec![];
-
-    for line in lines {
-        let parts: Vec<_> = line
-            .split('|')
-            .map(|x| trimmed_index(&backing, x))
-            .collect();
-
-        if parts.len() != headings.len() || line.starts_with("##") {
-            meta += line;
-            meta += "\n";
-        } else {
-            entries.push(parts)
-        }
-    }
-
-    PipeSeparatedVars {
-        storage: backing,
-        meta,
-        headings,
-        entries,
-    }
-}
-
-fn pick_cdn(cdns: &PipeSeparatedVars) -> String {
-    for ele in cdns.entries() {
-        let ele = ele.collect::<Vec<_>>();
-        if ele[0] == "us" {
-            let mut https_only = ele[3].split(' ').filter(|x| x.contains("https://"));
-            let mut https_only_blizzard = ele[3]
-                .split(' ')
-                .filter(|x| x.contains("https://"))
-                .filter(|x| x.contains("cdn.blizzard.com"));
-            let mut url = https_only_blizzard
-                .next()
-                .unwrap_or_else(|| https_only.next().unwrap());
-            if let Some(idx) = url.find('?') {
-                url = &url[0..idx];
-            }
-            return format!("{url}{}/", &ele[1]);
-        }
-    }
-
-    panic!();
-}
-
-#[derive(Clone, Copy, Debug, Display, Eq, Hash, PartialEq)]
-#[display("{:032x}", _0)]
-pub struct Key(u128);
-
-impl Key {
-    fn from_str(s: &str) -> Result<Self> {
-        let k = hex::decode(s)?;
-        Ok(Self(u128::from_be_bytes(k.try_into().unwrap())))
-    }
-
-    fn as_hex_string(&self) -> String {
-        hex::encode(self.0.to_be_bytes())
-    }
-}
-
-struct FileKeys {
-    ckey: Key,
-    ekey: Key,
-}
-
-impl FileKeys {
-    fn from_str(s: &str) -> Result<Self> {
-        let mut split = s.split(' ');
-        let ckey = Key::from_str(split.next().context("no ckey")?)?;
-        let ekey = Key::from_str(split.next().context("no ckey")?)?;
-
-        Ok(Self { ckey, ekey })
-    }
-}
-
-impl Debug for FileKeys {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        writeln!(
-            f,
-            "{{c {} e {}}}",
-            &self.ckey.as_hex_string(),
-            &self.ekey.as_hex_string()
-        )
-    }
-}
+//! Command line client, see the `casc` crate for the library
 
-struct CascClient {
-    cdn_prefix: String,
-    encoding: encoding::Encoding,
-    install: install::Install,
-    cache: CacheByKey,
-}
-
-impl std::fmt::Debug for CascClient {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("CascClient")
-            .field("cdn_prefix", &self.cdn_prefix)
-            //.field("encoding", &self.encoding)
-            //.field("install", &self.install)
-            .field("cache", &self.cache)
-            .finish()
-    }
-}
-
-impl CascClient {
-    fn get_by_ckey(&self, ckey: Key) -> Result<Vec<u8>> {
-        let ekey = self
-            .encoding
-            .c2e(ContentKey(ckey.0))
-            .ok()
-            .map(|k| Key(k.0))
-            .context("Unknown ckey")?;
-        self.get_by_ekey(ekey)
-    }
-
-    fn get_by_keys(&self, k: FileKeys) -> Result<Vec<u8>> {
-        let ckey = k.ckey;
-        let ekey_verify = self.encoding.c2e(ContentKey(ckey.0)).ok().map(|k| Key(k.0));
-        if let Some(ekey_verify) = ekey_verify {
-            ensure!(ekey_verify == k.ekey);
-        }
-
-        self.get_by_ekey(k.ekey)
-    }
-
-    fn get_by_ekey(&self, ekey: Key) -> Result<Vec<u8>> {
-        let exe_file_path = format!(
-            "{}data/{}",
-            self.cdn_prefix,
-            format_hex_key(&ekey.as_hex_string())
-        );
-        let bytes = self
-            .cache
-            .get(&exe_file_path, "data", &ekey.as_hex_string())?;
-        let blted = blte::parse(ekey.0, &bytes)?;
-
-        Ok(blted)
-    }
-
-    #[tracing::instrument(err)]
-    fn get_client_binaries(&self) -> Result<()> {
-        for exe in self
-            .install
-            .files
-            .iter()
-            .filter(|x| x.name.ends_with("Wow.exe"))
-        {
-            let ckey = exe.key;
-            //let ekey = Key(self.encoding.c2e(ContentKey(ckey.0))?.0);
-
-            tracing::debug!(
-                exe_name = exe.name,
-                ckey = ckey.as_hex_string(),
-                "Downloading exe {} ckey {}",
-                exe.name,
-                ckey,
-            );
-
-            // let exe_file_path = format!(
-            //     "{}data/{}",
-            //     self.cdn_prefix,
-            //     format_hex_key(&ekey.to_string())
-            // );
-            // let exe_data = self.cache.get(&exe_file_path, "data", &ekey.to_string())?;
-
-            //let install_decompressed = blte::parse(ekey.0, &exe_data)?;
-            let install_decompressed = self
-                .get_by_ckey(ckey)
-                .with_context(|| format!("get_by_ckey failed for {}", exe.name))?;
-            let path = PathBuf::from(format!("root/{}", exe.name));
-            std::fs::create_dir_all(path.parent().unwrap())?;
-            std::fs::write(&path, install_decompressed)?;
-
-            use std::ops::Deref;
-            tracing::info!(
-                path = path.to_string_lossy().deref(),
-                exe_name = exe.name,
-                "Downloaded"
-            );
-        }
-
-        Ok(())
-        //dbg!(&cdns, &versions, cdn);
-    }
-}
-
-#[tracing::instrument(err)]
-fn cdn_casc_client(game: &str, region: &str) -> Result<CascClient> {
-    let cdns = fetch(&format!("http://us.patch.battle.net:1119/{game}/cdns"))?;
-    let versions = fetch(&format!("http://us.patch.battle.net:1119/{game}/versions"))?;
-
-    let cdns = load_pipe_separated_vars(cdns.text()?);
-    let versions = load_pipe_separated_vars(versions.text()?);
-
-    let version_entry = versions
-        .entries()
-        .map(|x| x.collect::<Vec<_>>())
-        .find(|x| x[0] == region)
-        .context("no version found")?;
-
-    tracing::debug!("{cdns:#?} {versions:#?}");
-
-    let cdn = pick_cdn(&cdns);
-    let (build_cfg_key, cdn_cfg_key) = (version_entry[1], version_entry[2]);
-
-    tracing::info!(cdn = &cdn, "Picked CDN");
-
-    let cdn_cfg = fetch(&format!("{cdn}config/{}", format_hex_key(cdn_cfg_key)))?.text()?;
-    let build_cfg = fetch(&format!("{cdn}config/{}", format_hex_key(build_cfg_key)))?.text()?;
-
-    let build_cfg_mini = &build_cfg[0..build_cfg.find("vfs-").unwrap_or(build_cfg.len())];
-
-    tracing::debug!("{cdn_cfg} {build_cfg_mini}");
-
-    let i = ini::Ini::load_from_str(&build_cfg)?;
-    let sec = i.section(Option::<&str>::None).context("Invalid INI")?;
+mod commands;
 
-    let encoding = FileKeys::from_str(sec.get("encoding").context("Missing encoding")?)?;
-    tracing::info!("Encoding keys: {encoding:?}");
-
-    let cache = CacheByKey::new("cache");
-
-    let encoding_file_path = format!(
-        "{cdn}data/{}",
-        format_hex_key(&encoding.ekey.as_hex_string())
-    );
-    let encoding_data = cache.get(&encoding_file_path, "data", &encoding.ekey.as_hex_string())?;
-
-    let encoding_decompressed = blte::parse(encoding.ekey.0, &encoding_data)?;
-
-    let encoding_parsed: encoding::Encoding = encoding::parse(&encoding_decompressed)?;
-    tracing::info!("Parsed encoding. {}", encoding_parsed);
-
-    let install = FileKeys::from_str(sec.get("install").context("Missing install")?)?;
-    tracing::info!("Install keys: {install:?}");
-
-    let encoding_install_key = encoding_parsed.c2e(ContentKey(install.ckey.0)).ok();
-    if let Some(encoding_install_key) = encoding_install_key {
-        tracing::info!("Verifying encoding and install ekey agree");
-        ensure!(Key(encoding_install_key.0) == install.ekey);
-    }
+use std::{
+    path::PathBuf,
+    sync::{Arc, OnceLock},
+    time::Instant,
+};
 
-    let install_file_path = format!(
-        "{cdn}data/{}",
-        format_hex_key(&install.ekey.as_hex_string())
-    );
-    let install_data = cache.get(&install_file_path, "data", &install.ekey.as_hex_string())?;
-    let install_decompressed = blte::parse(install.ekey.0, &install_data)?;
-    let install = install::parse(&install_decompressed)?;
+use anyhow::{Context, Result, bail};
+use casc::{
+    cache::CacheByKey,
+    download,
+    extract::ExtractOptions,
+    transport::{self, Transport},
+};
 
-    Ok(CascClient {
-        encoding: encoding_parsed,
-        install,
-        cache,
-        cdn_prefix: cdn,
-    })
+const USAGE: &str = "\
+usage: casc-cdn-client [options] <command> [args]
+
+commands:
+  versions              the product's versions response
+  cdns                  the product's cdns response
+  config <key>          a build or CDN config
+  ls [install|root|tvfs]
+                        install, root or TVFS manifest entries
+  cat <ckey|ekey|fdid|path>
+                        a file's content, to stdout
+  extract [glob...] -o <dir>
+                        install entries matching any glob, all without one
+  mirror [version] -o <dir>
+                        a whole build in the CDN layout, the current one by default
+  diff <old> [new]      what changed between two builds, new defaults to the current one
+  info                  configs, encoding and install statistics
+  serve [addr]          serve the cache as a CDN, default 127.0.0.1:8080
+  verify [cache|build|install]
+                        check cached entries against their keys, or that
+                        every build or install file is on the CDN and
+                        hashes to its keys
+  binaries              download the client executables to root/ (default)
+  bgdl                  prefetch builds announced for background download
+  summary               every product Ribbit knows about
+
+options:
+  --product <code>      product to use, default wow
+  --region <region>     region to use, default us
+  --cache-dir <dir>     cache directory, default cache
+  --json                print JSON instead of text
+  -o <dir>              extract or mirror output directory
+  --exclude <glob>      skip extracting matching names, may be repeated
+  --mirror <url>        extra CDN host, may be repeated
+  --tact <url>          send versions and cdns requests here, e.g. a serve URL
+  --local <dir>         read blobs from a local install's Data/data first
+  --offline             only use what is already cached
+  --verify-cache        hash check cache hits
+  --index-only          verify build files can be located without fetching them
+  --cache-quota <size>  keep data entries under a size such as 20G
+  --jobs <n>            concurrent downloads
+  --host-limit <n>      concurrent requests per CDN host
+";
+
+/// Parses a byte count with an optional `K`, `M`, `G` or `T` suffix
+fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let (digits, shift) = match s.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&s[..i], 10),
+        Some((i, 'M' | 'm')) => (&s[..i], 20),
+        Some((i, 'G' | 'g')) => (&s[..i], 30),
+        Some((i, 'T' | 't')) => (&s[..i], 40),
+        _ => (s, 0),
+    };
+    let n: u64 = digits
+        .parse()
+        .with_context(|| format!("invalid size {s}"))?;
+    n.checked_shl(shift)
+        .filter(|x| x >> shift == n)
+        .with_context(|| format!("size {s} too large"))
 }
 
 static START_TIME: OnceLock<Instant> = OnceLock::new();
@@ -405,6 +88,7 @@ fn main() -> Result<()> {
         };
     tracing_subscriber::fmt()
         .compact()
+        .with_writer(std::io::stderr)
         .with_timer(timer)
         .with_env_filter(
             tracing_subscriber::EnvFilter::builder()
@@ -413,113 +97,126 @@ fn main() -> Result<()> {
         )
         .init();
 
-    let client = cdn_casc_client("wow", "us")?;
-    client.get_client_binaries()?;
-
-    Ok(())
-}
-
-use std::{
-    collections::HashMap,
-    convert::TryInto,
-    fmt::Debug,
-    io::Read,
-    ops::Range,
-    path::{Path, PathBuf},
-    sync::OnceLock,
-    time::Instant,
-};
-
-use anyhow::{Context, Result, ensure};
-use bytes::Buf;
-
-#[derive(Debug)]
-pub(crate) struct Index {
-    pub(crate) map: HashMap<EncodingKey, (ArchiveKey, usize, usize)>,
-}
-
-pub mod encoding;
+    let mut mirrors = vec![];
+    let mut tact = None;
+    let mut download_options = download::DownloadOptions::default();
+    let mut verify_cache = false;
+    let mut index_only = false;
+    let mut offline = false;
+    let mut cache_quota = None;
+    let mut cache_dir = PathBuf::from("cache");
+    let mut product = "wow".to_owned();
+    let mut region = "us".to_owned();
+    let mut json = false;
+    let mut output = None;
+    let mut exclude = vec![];
+    let mut local = None;
+    let mut positional = vec![];
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--mirror" {
+            mirrors.push(args.next().context("--mirror needs a URL")?.parse()?);
+        } else if arg == "--tact" {
+            tact = Some(args.next().context("--tact needs a URL")?);
+        } else if arg == "--cache-quota" {
+            cache_quota = Some(parse_size(
+                &args.next().context("--cache-quota needs a size")?,
+            )?);
+        } else if arg == "--cache-dir" {
+            cache_dir = args.next().context("--cache-dir needs a directory")?.into();
+        } else if arg == "--product" {
+            product = args.next().context("--product needs a product code")?;
+        } else if arg == "--region" {
+            region = args.next().context("--region needs a region")?;
+        } else if arg == "--json" {
+            json = true;
+        } else if arg == "-o" || arg == "--output" {
+            output = Some(PathBuf::from(args.next().context("-o needs a directory")?));
+        } else if arg == "--exclude" {
+            exclude.push(args.next().context("--exclude needs a glob")?);
+        } else if arg == "--local" {
+            local = Some(PathBuf::from(
+                args.next().context("--local needs a Data/data directory")?,
+            ));
+        } else if arg == "--offline" {
+            offline = true;
+        } else if arg == "--verify-cache" {
+            verify_cache = true;
+        } else if arg == "--index-only" {
+            index_only = true;
+        } else if arg == "--jobs" {
+            download_options.concurrency = args.next().context("--jobs needs a count")?.parse()?;
+        } else if arg == "--host-limit" {
+            download_options.per_host =
+                Some(args.next().context("--host-limit needs a count")?.parse()?);
+        } else if arg == "-h" || arg == "--help" {
+            print!("{USAGE}");
+            return Ok(());
+        } else if arg.starts_with('-') {
+            bail!("unknown option {arg}\n\n{USAGE}");
+        } else {
+            positional.push(arg);
+        }
+    }
+    let mut transport: Arc<dyn Transport> = if offline {
+        Arc::new(transport::OfflineTransport)
+    } else {
+        Arc::new(transport::HttpTransport::new(Default::default())?)
+    };
+    if let Some(tact) = tact {
+        transport = Arc::new(transport::TactRedirect::new(transport, &tact));
+    }
+    let mut cache = CacheByKey::new(cache_dir)
+        .with_verified_reads(verify_cache)
+        .with_offline(offline);
+    if let Some(quota) = cache_quota {
+        cache = cache.with_quota(quota);
+    }
+    let cli = commands::Cli {
+        product,
+        region,
+        cache,
+        transport,
+        mirrors,
+        local,
+        download_options,
+        json,
+    };
 
-pub(crate) fn parse_index(name: ArchiveKey, data: &[u8]) -> Result<Index> {
-    ensure!(data.len() >= 28, "truncated archive index data");
-    let non_footer_size = data.len() - 28;
-    let bytes_per_block = 4096 + 24;
-    let num_blocks = non_footer_size / bytes_per_block;
-    ensure!(
-        non_footer_size % bytes_per_block == 0,
-        "invalid archive index format"
-    );
-    let mut footer = &data[non_footer_size..];
-    //ensure!(md5hash(footer) == name.0, "bad footer name");
-    let toc_size = num_blocks * 24;
-    let toc = &data[non_footer_size - toc_size..non_footer_size];
-    ensure!(
-        (md5hash(toc) >> 64) as u64 == footer.get_u64(),
-        "archive index toc checksum"
-    );
-    ensure!(footer.get_u8() == 1, "unexpected archive index version");
-    ensure!(
-        footer.get_u8() == 0,
-        "unexpected archive index nonzero byte"
-    );
-    ensure!(
-        footer.get_u8() == 0,
-        "unexpected archive index nonzero byte"
-    );
-    ensure!(footer.get_u8() == 4, "unexpected archive index block size");
-    ensure!(
-        footer.get_u8() == 4,
-        "unexpected archive index offset bytes"
-    );
-    ensure!(footer.get_u8() == 4, "unexpected archive index size bytes");
-    ensure!(footer.get_u8() == 16, "unexpected archive index key size");
-    ensure!(
-        footer.get_u8() == 8,
-        "unexpected archive index checksum size"
-    );
-    let num_elements = footer.get_u32_le().try_into()?;
-    let footer_checksum = footer.get_u64();
-    assert!(!footer.has_remaining());
-    {
-        let mut footer_to_check = data[non_footer_size + 8..non_footer_size + 20].to_vec();
-        footer_to_check.resize(20, 0);
-        ensure!(
-            (md5hash(&footer_to_check) >> 64) as u64 == footer_checksum,
-            "archive index footer checksum"
-        );
+    let mut args = positional.into_iter();
+    let command = args.next().unwrap_or_else(|| "binaries".to_owned());
+    let mut arg = |name: &str| {
+        args.next()
+            .with_context(|| format!("{command} needs {name}\n\n{USAGE}"))
     };
-    let mut map = HashMap::<EncodingKey, (ArchiveKey, usize, usize)>::new();
-    let mut p = &data[..non_footer_size - toc_size];
-    let mut entries = &toc[..(16 * num_blocks)];
-    let mut blockhashes = &toc[(16 * num_blocks)..];
-    for _ in 0..num_blocks {
-        let mut block = &p[..4096];
-        let block_checksum = blockhashes.get_u64();
-        ensure!(
-            (md5hash(block) >> 64) as u64 == block_checksum,
-            "archive index block checksum"
-        );
-        let last_ekey = EncodingKey(entries.get_u128());
-        let mut found = false;
-        while block.remaining() >= 24 {
-            let ekey = EncodingKey(block.get_u128());
-            let size = block.get_u32().try_into()?;
-            let offset = block.get_u32().try_into()?;
-            ensure!(
-                map.insert(ekey, (name, size, offset)).is_none(),
-                "duplicate key in index"
-            );
-            if ekey == last_ekey {
-                found = true;
-                break;
-            }
+    match command.as_str() {
+        "versions" => commands::versions(&cli),
+        "cdns" => commands::cdns(&cli),
+        "config" => commands::config(&cli, &arg("a config key")?),
+        "ls" => commands::ls(&cli, args.next().as_deref()),
+        "cat" => commands::cat(&cli, &arg("a key, FileDataID or path")?),
+        "extract" => {
+            let options = ExtractOptions {
+                include: args.collect(),
+                exclude,
+                output: output.context("extract needs -o <dir>")?,
+            };
+            commands::extract(&cli, &options)
+        }
+        "mirror" => {
+            let output = output.context("mirror needs -o <dir>")?;
+            commands::mirror(&cli, args.next().as_deref(), &output)
+        }
+        "diff" => {
+            let old = arg("a build")?;
+            commands::diff(&cli, &old, args.next().as_deref())
         }
-        ensure!(found, "last ekey mismatch");
-        p.advance(4096);
+        "info" => commands::info(&cli),
+        "serve" => commands::serve(&cli, args.next().as_deref().unwrap_or("127.0.0.1:8080")),
+        "verify" => commands::verify(&cli, args.next().as_deref(), !index_only),
+        "binaries" => commands::binaries(&cli),
+        "bgdl" => commands::bgdl(&cli),
+        "summary" => commands::summary(&cli),
+        other => bail!("unknown command {other}\n\n{USAGE}"),
     }
-    assert!(!p.has_remaining());
-    assert!(!entries.has_remaining());
-    assert!(!blockhashes.has_remaining());
-    ensure!(map.len() == num_elements, "num_elements wrong in index");
-    Ok(Index { map })
 }
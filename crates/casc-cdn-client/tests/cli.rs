@@ -0,0 +1,71 @@
+//! The command line client against a synthetic build on a loopback mock CDN
+
+use std::process::{Command, Output};
+
+use anyhow::{Result, ensure};
+use casc::testing::{MockCdn, ScratchDir, SyntheticBuild};
+
+/// Runs the client with `args` against `cdn`, caching in `cache`
+fn run(cdn: &MockCdn, cache: &ScratchDir, args: &[&str]) -> Result<Output> {
+    let output = Command::new(env!("CARGO_BIN_EXE_casc-cdn-client"))
+        .args(["--product", "casctest", "--tact", &cdn.url(), "--cache-dir"])
+        .arg(cache.path())
+        .args(args)
+        .output()?;
+    ensure!(
+        output.status.success(),
+        "{args:?} failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    Ok(output)
+}
+
+#[test]
+fn cat_reads_archived_files() -> Result<()> {
+    let cdn = MockCdn::start(&SyntheticBuild::sample())?;
+    let cache = ScratchDir::new("cli")?;
+    let file = cdn.build.file("Game.exe").unwrap();
+    assert!(file.archived);
+
+    assert_eq!(run(&cdn, &cache, &["cat", "Game.exe"])?.stdout, file.data);
+    let ckey = file.ckey.to_string();
+    assert_eq!(run(&cdn, &cache, &["cat", &ckey])?.stdout, file.data);
+    Ok(())
+}
+
+#[test]
+fn extract_writes_archived_files() -> Result<()> {
+    let cdn = MockCdn::start(&SyntheticBuild::sample())?;
+    let cache = ScratchDir::new("cli")?;
+    let output = ScratchDir::new("extract")?;
+    let dir = output.path().to_str().unwrap();
+    run(&cdn, &cache, &["extract", "-o", dir])?;
+
+    for file in &cdn.build.files {
+        let path = output.path().join(file.name.replace('\\', "/"));
+        assert_eq!(std::fs::read(path)?, file.data, "{}", file.name);
+    }
+    Ok(())
+}
+
+#[test]
+fn cat_refetches_corrupt_cache_entries() -> Result<()> {
+    let cdn = MockCdn::start(&SyntheticBuild::sample())?;
+    let cache = ScratchDir::new("cli")?;
+    let file = cdn.build.file("Data\\readme.txt").unwrap();
+    let ckey = file.ckey.to_string();
+    run(&cdn, &cache, &["cat", &ckey])?;
+
+    let key = file.ekey.to_string();
+    let path = cache
+        .path()
+        .join("data")
+        .join(&key[0..2])
+        .join(&key[2..4])
+        .join(&key);
+    let mut blob = std::fs::read(&path)?;
+    *blob.last_mut().unwrap() ^= 0xff;
+    std::fs::write(&path, &blob)?;
+    assert_eq!(run(&cdn, &cache, &["cat", &ckey])?.stdout, file.data);
+    Ok(())
+}
@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // parse against the blob's own header hash so inputs get past the checksum
+    if let Ok(ekey) = casc::blte::header_hash(data) {
+        let _ = casc::blte::parse(ekey, data);
+    }
+});
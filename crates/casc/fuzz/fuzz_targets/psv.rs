@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(text) = std::str::from_utf8(data) {
+        let psv = casc::load_pipe_separated_vars(text.to_owned());
+        let _ = casc::version_entries(&psv);
+    }
+});
@@ -0,0 +1,325 @@
+#!/usr/bin/env python3
+"""Seeds corpus/<target>/ for the fuzz targets.
+
+Writes small well-formed files in the layouts real builds use. The corpus
+checked in is only these synthetic seeds: no bytes of real builds are
+committed, they stand in for the real header prefixes.
+
+Given a cache directory, and optionally a local install's Data/data
+directory, it also adds prefixes of real files to your local corpus: BLTE
+blobs, the decoded encoding file, archive indexes, `.idx` files, configs
+and TACT responses:
+
+    python3 seed_corpus.py [path/to/cache [path/to/Data/data]]
+"""
+
+import hashlib
+import struct
+import sys
+import zlib
+from pathlib import Path
+
+CORPUS = Path(__file__).parent / "corpus"
+
+
+def md5(data):
+    return hashlib.md5(data).digest()
+
+
+def write(target, name, data):
+    path = CORPUS / target / name
+    path.parent.mkdir(parents=True, exist_ok=True)
+    path.write_bytes(data)
+
+
+def blte(chunks):
+    """BLTE blob with a chunk table, chunks are (mode, payload) pairs"""
+    encoded = []
+    for mode, payload in chunks:
+        body = zlib.compress(payload) if mode == b"Z" else payload
+        encoded.append((mode + body, payload))
+    header_size = 12 + 24 * len(encoded)
+    header = b"BLTE" + struct.pack(">I", header_size)
+    header += b"\x0f" + struct.pack(">I", len(encoded))[1:]
+    for chunk, payload in encoded:
+        header += struct.pack(">II", len(chunk), len(payload)) + md5(chunk)
+    return header + b"".join(chunk for chunk, _ in encoded)
+
+
+def encoding(files):
+    """Encoding file with one content and one ekey page, files are (ckey, ekey, size)"""
+    files = sorted(files)
+    page_size = 1024
+    cpage = b""
+    for ckey, ekey, size in files:
+        cpage += b"\x01" + struct.pack(">BI", size >> 32, size & 0xFFFFFFFF) + ckey + ekey
+    cpage = cpage.ljust(page_size, b"\0")
+    epage = b""
+    for _, ekey, size in sorted(files, key=lambda x: x[1]):
+        epage += ekey + struct.pack(">IBI", 0, size >> 32, size & 0xFFFFFFFF)
+    epage = epage.ljust(page_size, b"\0")
+    especs = b"n\0"
+    header = b"EN" + struct.pack(">BBBHHIIBI", 1, 16, 16, 1, 1, 1, 1, 0, len(especs))
+    first_ckey, first_ekey = files[0][0], min(f[1] for f in files)
+    return (
+        header
+        + especs
+        + first_ckey + md5(cpage) + cpage
+        + first_ekey + md5(epage) + epage
+        + b"n"
+    )
+
+
+def install(tags, files):
+    """Install manifest, tags are (name, type, [file index]) and files (name, ckey, size)"""
+    mask_len = (len(files) + 7) // 8
+    data = b"IN" + struct.pack(">BBHI", 1, 16, len(tags), len(files))
+    for name, ty, members in tags:
+        mask = bytearray(mask_len)
+        for i in members:
+            mask[i // 8] |= 1 << (i % 8)
+        data += name.encode() + b"\0" + struct.pack(">H", ty) + bytes(mask)
+    for name, ckey, size in files:
+        data += name.encode() + b"\0" + ckey + struct.pack(">I", size)
+    return data
+
+
+def archive_index(entries):
+    """Archive .index with a single block, entries are (ekey, size, offset)"""
+    entries = sorted(entries)
+    block = b"".join(k + struct.pack(">II", s, o) for k, s, o in entries).ljust(4096, b"\0")
+    toc = entries[-1][0] + md5(block)[:8]
+    footer_fields = struct.pack("<BBBBBBBBI", 1, 0, 0, 4, 4, 4, 16, 8, len(entries))
+    footer_hash = md5(footer_fields + b"\0" * 8)[:8]
+    return block + toc + md5(toc)[:8] + footer_fields + footer_hash
+
+
+def tvfs(files):
+    """TVFS manifest without ckeys, files are (path, [(ekey, content size)]) in path order.
+
+    Every path is either a bare name or one folder deep.
+    """
+    cft = b""
+    vfs = b""
+    leaves = []
+    for path, spans in files:
+        entry = bytes([len(spans)])
+        offset = 0
+        for ekey, size in spans:
+            entry += struct.pack(">II", offset, size) + bytes([len(cft)])
+            cft += ekey[:9] + struct.pack(">I", size + 16)
+            offset += size
+        leaves.append((path, len(vfs)))
+        vfs += entry
+    assert len(cft) < 256
+
+    def leaf(name, offset):
+        return bytes([len(name)]) + name.encode() + b"\xff" + struct.pack(">I", offset)
+
+    table = b""
+    folders = {}
+    for path, offset in leaves:
+        folder, _, name = path.rpartition("/")
+        folders.setdefault(folder, b"")
+        folders[folder] += leaf(name, offset)
+    for folder, children in folders.items():
+        if not folder:
+            table += children
+        else:
+            size = 0x80000000 | (4 + len(children))
+            table += bytes([len(folder)]) + folder.encode() + b"\0\xff" + struct.pack(">I", size) + children
+
+    header_size = 0x26
+    path_offset = header_size
+    vfs_offset = path_offset + len(table)
+    cft_offset = vfs_offset + len(vfs)
+    header = b"TVFS" + bytes([1, header_size, 9, 9]) + struct.pack(
+        ">IIIIIIIH", 0, path_offset, len(table), vfs_offset, len(vfs), cft_offset, len(cft), 2
+    )
+    return header + table + vfs + cft
+
+
+def local_idx(entries):
+    """Version 7 local .idx, entries are (ekey, archive, offset, size) with 30 offset bits"""
+    header = struct.pack("<HBBBBBBQ", 7, 0, 0, 4, 5, 9, 30, 1 << 30)
+    data = struct.pack("<II", len(header), 0) + header
+    data = data.ljust((len(data) + 15) // 16 * 16, b"\0")
+    body = b""
+    for ekey, archive, offset, size in sorted(entries):
+        packed = (archive << 30) | offset
+        body += ekey[:9] + packed.to_bytes(5, "big") + struct.pack("<I", size)
+    return data + struct.pack("<II", len(body), 0) + body
+
+
+def ribbit_v1(data):
+    """Ribbit v1 MIME response with a signature part and the checksum epilogue"""
+    message = (
+        b'MIME-Version: 1.0\r\nContent-Type: multipart/alternative; boundary="B"\r\n\r\n'
+        b"--B\r\nContent-Type: text/plain\r\nContent-Disposition: version\r\n\r\n"
+        + data
+        + b"\r\n--B\r\nContent-Type: application/cms\r\nContent-Disposition: cms.sgn\r\n\r\n"
+        b"AAAA\r\n--B--\r\n"
+    )
+    return message + b"Checksum: " + hashlib.sha256(message).hexdigest().encode() + b"\r\n"
+
+
+def root(blocks, version=None):
+    """WoW root, blocks are (locale, content flags, [(fdid, ckey, name hash)]).
+
+    Without a version this is the pre-8.2 layout, otherwise the 10.1.7 one.
+    """
+    if version is None:
+        data = b""
+    else:
+        total = sum(len(files) for _, _, files in blocks)
+        data = b"TSFM" + struct.pack("<IIIII", 24, version, total, total, 0)
+    for locale, content, files in blocks:
+        if version == 2:
+            data += struct.pack("<IIIIB", len(files), locale, content, 0, 0)
+        else:
+            data += struct.pack("<III", len(files), content, locale)
+        prev = -1
+        for fdid, _, _ in files:
+            data += struct.pack("<i", fdid - prev - 1)
+            prev = fdid
+        for _, ckey, name_hash in files:
+            data += ckey + (struct.pack("<Q", name_hash) if version is None else b"")
+        if version is not None:
+            data += b"".join(struct.pack("<Q", name_hash) for _, _, name_hash in files)
+    return data
+
+
+def key(n):
+    return md5(str(n).encode())
+
+
+def synthetic():
+    payload = b"hello world\n" * 64
+    write("blte", "n-chunk", blte([(b"N", payload)]))
+    write("blte", "z-chunks", blte([(b"Z", payload), (b"N", b"tail")]))
+    write("blte", "e-chunk", blte([(b"E", b"\x08" + bytes(range(8)) + b"\x04\0\0\0\0S")]))
+
+    files = [(key(i), key(100 + i), 1000 * i) for i in range(4)]
+    write("encoding", "small", encoding(files))
+
+    write(
+        "install",
+        "small",
+        install(
+            [("Windows", 1, [0, 1]), ("OSX", 1, [2]), ("x86_64", 2, [0, 1, 2])],
+            [("Wow.exe", key(0), 100), ("Data\\a.txt", key(1), 5), ("World of Warcraft.app", key(2), 7)],
+        ),
+    )
+
+    write(
+        "tvfs",
+        "small",
+        tvfs([("a.txt", [(key(200), 5)]), ("dir/b.bin", [(key(201), 100), (key(202), 7)])]),
+    )
+
+    write("archive_index", "small", archive_index([(key(i), 100 + i, 1000 * i) for i in range(8)]))
+
+    write("local_idx", "small", local_idx([(key(i), i % 2, 4096 * i, 30 + 10 * i) for i in range(8)]))
+
+    files = [(10 * i, key(300 + i), 0x1234567890ABCDEF + i) for i in range(4)]
+    write("root", "legacy", root([(2, 0, files), (0x1F3F6, 8, files[:2])]))
+    write("root", "mfst-v2", root([(2, 0, files), (0x1F3F6, 8, files[:2])], version=2))
+
+    write(
+        "config",
+        "build",
+        b"# Build Configuration\n\nroot = " + key(1).hex().encode()
+        + b"\ninstall = " + key(2).hex().encode() + b" " + key(3).hex().encode()
+        + b"\ninstall-size = 100 200\nbuild-name = 1.2.3.12345\n",
+    )
+    write("config", "cdn", b"# CDN Configuration\n\narchives = " + key(4).hex().encode() + b" " + key(5).hex().encode() + b"\n")
+
+    versions = (
+        b"Region!STRING:0|BuildConfig!HEX:16|BuildId!DEC:4\n## seqn = 1\n"
+        b"us|" + key(1).hex().encode() + b"|12345\n"
+    )
+    write("ribbit_v1", "versions", ribbit_v1(versions))
+
+    write(
+        "psv",
+        "versions",
+        b"Region!STRING:0|BuildConfig!HEX:16|CDNConfig!HEX:16|KeyRing!HEX:16|BuildId!DEC:4"
+        b"|VersionsName!String:0|ProductConfig!HEX:16\n## seqn = 1\n"
+        + b"us|" + key(1).hex().encode() + b"|" + key(2).hex().encode()
+        + b"||12345|1.2.3.12345|" + key(3).hex().encode() + b"\n",
+    )
+    write(
+        "psv",
+        "cdns",
+        b"Name!STRING:0|Path!STRING:0|Hosts!STRING:0|Servers!STRING:0|ConfigPath!STRING:0\n"
+        b"## seqn = 1\nus|tpr/wow|level3.blizzard.com us.cdn.blizzard.com|"
+        b"http://level3.blizzard.com/?maxhosts=4|tpr/configs/data\n",
+    )
+
+
+# Real files are cut down to their first bytes, enough for headers and a few pages
+PREFIX = 64 << 10
+
+
+def unblte(data):
+    """Decodes a BLTE blob of plain and zlib chunks, None for anything else"""
+    header_size, = struct.unpack(">I", data[4:8])
+    if header_size == 0:
+        chunks = [data[8:]]
+    else:
+        count = int.from_bytes(data[9:12], "big")
+        sizes = [struct.unpack(">I", data[12 + 24 * i:16 + 24 * i])[0] for i in range(count)]
+        offset, chunks = header_size, []
+        for size in sizes:
+            chunks.append(data[offset:offset + size])
+            offset += size
+    decoded = b""
+    for chunk in chunks:
+        mode, body = chunk[:1], chunk[1:]
+        if mode == b"N":
+            decoded += body
+        elif mode == b"Z":
+            decoded += zlib.decompress(body)
+        else:
+            return None
+    return decoded
+
+
+def from_cache(cache):
+    """Copies prefixes of real files out of a CacheByKey directory"""
+    for path in sorted((cache / "data").rglob("*")):
+        if not path.is_file() or path.name.endswith(".part"):
+            continue
+        if path.name.endswith(".index"):
+            # the footer is at the end, so indexes go in whole
+            write("archive_index", path.name, path.read_bytes())
+            continue
+        with path.open("rb") as f:
+            data = f.read(PREFIX)
+        if not data.startswith(b"BLTE"):
+            continue
+        write("blte", path.name, data)
+        decoded = unblte(path.read_bytes()) if path.stat().st_size <= 64 << 20 else None
+        if decoded and decoded.startswith(b"EN"):
+            write("encoding", path.name, decoded[:PREFIX])
+    for path in sorted((cache / "config").rglob("*")):
+        if path.is_file():
+            write("config", path.name, path.read_bytes())
+    for path in sorted((cache / "tact").rglob("*")):
+        if path.is_file():
+            write("psv", "-".join(path.relative_to(cache / "tact").parts), path.read_bytes())
+
+
+def from_install(data_dir):
+    """Copies prefixes of the `.idx` files of a local install"""
+    for path in sorted(data_dir.glob("*.idx")):
+        with path.open("rb") as f:
+            write("local_idx", path.name, f.read(PREFIX))
+
+
+if __name__ == "__main__":
+    synthetic()
+    if len(sys.argv) > 1:
+        from_cache(Path(sys.argv[1]))
+    if len(sys.argv) > 2:
+        from_install(Path(sys.argv[2]))
@@ -0,0 +1,182 @@
+//! Checking that a build's content can be found and decodes to what its keys say.
+//!
+//! Every ckey is resolved through encoding to an ekey, which must be in an
+//! archive index or available as a loose file. When fetching, the blob is
+//! read through the client's source, so a local install chained in front of
+//! the CDN is audited too, and blobs land in the cache like any other read.
+//! Its BLTE header must hash to the ekey and the decoded content to the ckey.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+
+use crate::{
+    CascClient, ContentKey, EncodingKey, Error, Index, blte, format_hex_key, md5hash,
+    source::CdnSource,
+};
+
+#[derive(Clone, Debug)]
+pub struct AuditOptions {
+    /// The ckeys to check, every ckey in encoding when `None`
+    pub ckeys: Option<Vec<ContentKey>>,
+    /// Fetch and hash each blob, otherwise only check it can be located
+    pub fetch: bool,
+    /// Blobs fetched and checked at once
+    pub concurrency: usize,
+}
+
+impl Default for AuditOptions {
+    fn default() -> Self {
+        Self {
+            ckeys: None,
+            fetch: true,
+            concurrency: 8,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct AuditFailure {
+    pub ckey: ContentKey,
+    /// `None` when encoding has no ekey for the ckey
+    pub ekey: Option<EncodingKey>,
+    pub error: String,
+}
+
+#[derive(Debug, Default)]
+pub struct AuditReport {
+    pub checked: usize,
+    /// Ekeys found in an archive index
+    pub archived: usize,
+    /// Ekeys not in any archive, expected as loose files
+    pub loose: usize,
+    /// Bytes of encoded blobs fetched
+    pub bytes: u64,
+    pub failures: Vec<AuditFailure>,
+}
+
+impl CascClient {
+    /// Loads the archive indexes up front, rather than on the first blob
+    /// that isn't available as a loose file.
+    ///
+    /// Replaces the blob source with the CDN, so call it before chaining in
+    /// other sources.
+    pub fn with_archive_index(mut self) -> Result<Self> {
+        let index = Arc::new(self.archive_index()?);
+        self.source =
+            Arc::new(CdnSource::new(self.cdn.clone(), self.cache.clone()).with_index(index));
+        Ok(self)
+    }
+
+    /// Checks the ckeys `options` selects, collecting every failure in the report
+    #[tracing::instrument(err, skip_all)]
+    pub fn audit(&self, options: &AuditOptions) -> Result<AuditReport> {
+        let index = self.archive_index()?;
+        let ckeys = match &options.ckeys {
+            Some(ckeys) => ckeys.clone(),
+            None => self.encoding.ckeys().map(|(ckey, _, _)| ckey).collect(),
+        };
+        tracing::info!(ckeys = ckeys.len(), fetch = options.fetch, "Auditing");
+        let queue = Mutex::new(ckeys.into_iter().collect::<VecDeque<_>>());
+        let report = Mutex::new(AuditReport::default());
+
+        std::thread::scope(|s| {
+            for _ in 0..options.concurrency.max(1) {
+                s.spawn(|| {
+                    loop {
+                        let Some(ckey) = queue.lock().unwrap().pop_front() else {
+                            break;
+                        };
+                        let ekey = self.encoding.c2e(ckey).ok();
+                        let result = match ekey {
+                            Some(ekey) => self.audit_one(ckey, ekey, &index, options.fetch),
+                            None => Err(Error::NotFound {
+                                key: ckey.to_string(),
+                            }
+                            .into()),
+                        };
+                        let mut report = report.lock().unwrap();
+                        report.checked += 1;
+                        match result {
+                            Ok((archived, bytes)) => {
+                                if archived {
+                                    report.archived += 1;
+                                } else {
+                                    report.loose += 1;
+                                }
+                                report.bytes += bytes;
+                            }
+                            Err(e) => {
+                                tracing::warn!("{ckey}: {e:#}");
+                                report.failures.push(AuditFailure {
+                                    ckey,
+                                    ekey,
+                                    error: format!("{e:#}"),
+                                });
+                            }
+                        }
+                    }
+                });
+            }
+        });
+
+        let mut report = report.into_inner().unwrap();
+        report.failures.sort_unstable_by_key(|x| x.ckey);
+        Ok(report)
+    }
+
+    /// Checks one ckey, returning whether it is archived and the bytes fetched
+    fn audit_one(
+        &self,
+        ckey: ContentKey,
+        ekey: EncodingKey,
+        index: &Index,
+        fetch: bool,
+    ) -> Result<(bool, u64)> {
+        let archived = index.map.contains_key(&ekey);
+        if !fetch {
+            if !archived && !self.has_loose(ekey)? {
+                return Err(Error::NotFound {
+                    key: ekey.to_string(),
+                }
+                .into());
+            }
+            return Ok((archived, 0));
+        }
+        let blob = self.source.get_ekey(ekey)?;
+        let header = blte::header_hash(&blob)?;
+        if header != ekey {
+            return Err(Error::ChecksumMismatch {
+                expected: ekey.0,
+                actual: header.0,
+                what: "BLTE header",
+            }
+            .into());
+        }
+        let actual = md5hash(&blte::parse(ekey, &blob)?);
+        if actual != ckey.0 {
+            return Err(Error::ChecksumMismatch {
+                expected: ckey.0,
+                actual,
+                what: "decoded content",
+            }
+            .into());
+        }
+        Ok((archived, blob.len() as u64))
+    }
+
+    /// Whether the loose file `ekey` is cached or on the CDN
+    fn has_loose(&self, ekey: EncodingKey) -> Result<bool> {
+        let key = ekey.to_string();
+        if self.cache.contains("data", &key) {
+            return Ok(true);
+        }
+        if self.cache.is_offline() {
+            return Ok(false);
+        }
+        self.cdn.exists(&format!("data/{}", format_hex_key(&key)))
+    }
+}
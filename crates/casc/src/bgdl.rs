@@ -0,0 +1,157 @@
+//! Background download (`bgdl`) builds, published ahead of a patch so
+//! clients can fetch the new content before it goes live.
+
+use std::{collections::HashSet, sync::Arc};
+
+use anyhow::Result;
+
+use crate::{
+    CascClient, EncodingKey, VersionEntry,
+    cache::CacheByKey,
+    casc_client_for_build,
+    cdn::{CdnPool, Mirror},
+    download::{self, DownloadOptions, DownloadReport},
+    product::ProductProfile,
+    tact_psv,
+    transport::Transport,
+    version_entries,
+};
+
+/// A region whose `bgdl` build differs from its live `versions` build
+#[derive(Debug)]
+pub struct PendingBuild {
+    pub live: VersionEntry,
+    pub bgdl: VersionEntry,
+}
+
+/// Compares the `bgdl` and `versions` builds of every region listed by `region`'s patch server
+#[tracing::instrument(err, skip(transport, cache))]
+pub fn pending_builds(
+    transport: &dyn Transport,
+    cache: &CacheByKey,
+    product: &str,
+    region: &str,
+) -> Result<Vec<PendingBuild>> {
+    let live = version_entries(&tact_psv(transport, cache, product, region, "versions")?)?;
+    let bgdl = version_entries(&tact_psv(transport, cache, product, region, "bgdl")?)?;
+
+    let pending = bgdl
+        .into_iter()
+        .filter_map(|bgdl| {
+            let live = live.iter().find(|x| x.region == bgdl.region)?;
+            (live.build_config != bgdl.build_config).then(|| PendingBuild {
+                live: live.clone(),
+                bgdl,
+            })
+        })
+        .collect::<Vec<_>>();
+    tracing::info!("{} region(s) with a pending bgdl build", pending.len());
+    Ok(pending)
+}
+
+/// Ekeys present in `next` but not in `current`, with their encoded sizes
+pub fn added_ekeys(current: &CascClient, next: &CascClient) -> Vec<(EncodingKey, u64)> {
+    let current = current
+        .encoding
+        .ekeys()
+        .map(|(k, _)| k)
+        .collect::<HashSet<_>>();
+    next.encoding
+        .ekeys()
+        .filter(|(k, _)| !current.contains(k))
+        .collect()
+}
+
+/// Warms the cache with every ekey the pending build adds over the live one
+#[tracing::instrument(err, skip(transport, cache, mirrors, pending, options), fields(region = pending.bgdl.region))]
+pub fn prefetch(
+    transport: Arc<dyn Transport>,
+    cache: CacheByKey,
+    product: &str,
+    region: &str,
+    mirrors: &[Mirror],
+    pending: &PendingBuild,
+    options: &DownloadOptions,
+) -> Result<DownloadReport> {
+    let cdns = tact_psv(&*transport, &cache, product, region, "cdns")?;
+    let live = casc_client_for_build(
+        ProductProfile::for_code(product),
+        CdnPool::from_cdns(transport.clone(), &cdns, region, mirrors)?,
+        cache.clone(),
+        &pending.live,
+    )?;
+    let next = casc_client_for_build(
+        ProductProfile::for_code(product),
+        CdnPool::from_cdns(transport, &cdns, region, mirrors)?,
+        cache,
+        &pending.bgdl,
+    )?;
+
+    let added = added_ekeys(&live, &next);
+    tracing::info!("{} ekeys added by bgdl build", added.len());
+    let index = next.archive_index()?;
+    download::download(
+        &next.cdn,
+        &next.cache,
+        &index,
+        added.into_iter().map(|(k, _)| k),
+        options,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{MockCdn, ScratchDir, SyntheticBuild},
+        transport::OfflineTransport,
+    };
+
+    #[test]
+    fn added_ekeys_are_new_in_the_next_build() -> Result<()> {
+        let live = SyntheticBuild::new("casctest")
+            .file("Game.exe", "live executable")
+            .loose_file("readme.txt", "unchanged");
+        let mut next = SyntheticBuild::new("casctest")
+            .file("Game.exe", "patched executable")
+            .loose_file("readme.txt", "unchanged")
+            .file("Data\\new.dat", "new content");
+        next.build_id = 2;
+        let (live, next) = (MockCdn::start(&live)?, MockCdn::start(&next)?);
+        let (live_cache, next_cache) = (ScratchDir::new("live")?, ScratchDir::new("next")?);
+
+        let mut added = added_ekeys(&live.client(&live_cache)?, &next.client(&next_cache)?)
+            .into_iter()
+            .map(|(k, _)| k)
+            .collect::<Vec<_>>();
+        added.sort();
+        let mut expected = vec![
+            next.build.file("Game.exe").unwrap().ekey,
+            next.build.file("Data\\new.dat").unwrap().ekey,
+            next.build.install.ekey,
+        ];
+        expected.sort();
+        assert_eq!(added, expected);
+        assert!(!added.contains(&live.build.file("readme.txt").unwrap().ekey));
+        Ok(())
+    }
+
+    #[test]
+    fn pending_builds_differ_from_live() -> Result<()> {
+        let dir = ScratchDir::new("bgdl")?;
+        let cache = CacheByKey::new(dir.path()).with_offline(true);
+        let header = "Region!STRING:0|BuildConfig!HEX:16|CDNConfig!HEX:16\n## seqn = 1\n";
+        let (a, b, cdn) = ("a".repeat(32), "b".repeat(32), "c".repeat(32));
+        let versions = format!("{header}us|{a}|{cdn}\neu|{a}|{cdn}\n");
+        let bgdl = format!("{header}us|{b}|{cdn}\neu|{a}|{cdn}\nkr|{b}|{cdn}\n");
+        cache.store_tact("casctest", "us", "versions", &versions)?;
+        cache.store_tact("casctest", "us", "bgdl", &bgdl)?;
+
+        let pending = pending_builds(&OfflineTransport, &cache, "casctest", "us")?;
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].live.region, "us");
+        assert_eq!(pending[0].live.build_config, a);
+        assert_eq!(pending[0].bgdl.build_config, b);
+        Ok(())
+    }
+}
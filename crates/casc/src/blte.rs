@@ -0,0 +1,133 @@
+use crate::{
+    EncodingKey, Md5Key,
+    cursor::Cursor,
+    error::{Error, Result, check},
+};
+
+const WHAT: &str = "BLTE";
+
+/// Upper bound on how much deflate can expand its input
+const MAX_DEFLATE_RATIO: usize = 1032;
+
+fn parse_blte_chunk(data: &[u8], output_buffer: &mut [u8]) -> Result<()> {
+    use miniz_oxide::inflate;
+    let (&mode, chunk_data) = data
+        .split_first()
+        .ok_or(Error::Truncated { what: "BLTE chunk" })?;
+    let size_mismatch = || Error::malformed("BLTE chunk", "decoded size mismatch");
+    match mode {
+        b'N' => {
+            check!(chunk_data.len() == output_buffer.len(), size_mismatch());
+            output_buffer.copy_from_slice(chunk_data);
+        }
+        b'Z' => {
+            let size = inflate::decompress_slice_iter_to_slice(
+                output_buffer,
+                std::iter::once(chunk_data),
+                true,
+                cfg!(not(debug_assertions)),
+            )
+            .map_err(|s| Error::malformed("BLTE chunk", format!("inflate error {s:?}")))?;
+            check!(size == output_buffer.len(), size_mismatch());
+        }
+        b'E' => {
+            let mut p = Cursor::new(chunk_data, "BLTE encryption header");
+            check!(
+                p.u8()? == 8,
+                Error::malformed("BLTE chunk", "bad encryption key name length")
+            );
+            return Err(Error::MissingDecryptionKey {
+                key_name: p.u64_le()?,
+            });
+        }
+        mode => {
+            return Err(Error::malformed(
+                "BLTE chunk",
+                format!("unknown encoding mode {:?}", mode as char),
+            ));
+        }
+    };
+    Ok(())
+}
+
+/// The hash a BLTE blob's ekey is derived from: its header, or the whole blob
+/// if it has no chunk table
+pub fn header_hash(data: &[u8]) -> Result<EncodingKey> {
+    let mut p = Cursor::new(data, WHAT);
+    check!(&p.array()? == b"BLTE", Error::malformed(WHAT, "bad magic"));
+    let header_size = p.u32()? as usize;
+    if header_size == 0 {
+        return Ok(EncodingKey::of(data));
+    }
+    let header = data.get(..header_size).ok_or(p.truncated())?;
+    Ok(EncodingKey::of(header))
+}
+
+pub fn parse(ekey: EncodingKey, data: &[u8]) -> Result<Vec<u8>> {
+    let mut p = Cursor::new(data, WHAT);
+    check!(&p.array()? == b"BLTE", Error::malformed(WHAT, "bad magic"));
+    let header_size = p.u32()? as usize;
+    if header_size == 0 {
+        return Err(Error::malformed(WHAT, "missing BLTE header not supported"));
+        // ensure!(crate::md5hash(data) == checksum);
+        // return Ok(parse_blte_chunk(p)?.to_vec());
+    }
+    let header = data.get(..header_size).ok_or(p.truncated())?;
+    let actual = crate::md5hash(header);
+    check!(
+        actual == ekey.0,
+        Error::ChecksumMismatch {
+            expected: ekey.0,
+            actual,
+            what: "BLTE header",
+        }
+    );
+    check!(p.u8()? == 0xf, Error::malformed(WHAT, "bad flag byte"));
+    let chunk_count = p.u24()? as usize;
+    check!(
+        header_size == chunk_count * 24 + 12,
+        Error::malformed(WHAT, "header size mismatch")
+    );
+    let mut chunkinfo = Vec::<(usize, usize, u128)>::with_capacity(chunk_count);
+    let mut overall_compressed_size = 0;
+    let mut overall_uncompressed_size = 0;
+    for _ in 0..chunk_count {
+        let compressed_size = p.u32()? as usize;
+        let uncompressed_size = p.u32()? as usize;
+        let checksum = p.u128()?;
+        check!(
+            uncompressed_size <= compressed_size.saturating_mul(MAX_DEFLATE_RATIO),
+            Error::malformed(WHAT, "implausible decoded chunk size")
+        );
+        chunkinfo.push((compressed_size, uncompressed_size, checksum));
+        overall_compressed_size += compressed_size;
+        overall_uncompressed_size += uncompressed_size;
+    }
+    // checked before allocating the output, so a bad header can't claim gigabytes
+    check!(overall_compressed_size <= p.remaining(), p.truncated());
+    let mut result = vec![0u8; overall_uncompressed_size];
+    let mut result_ptr = 0;
+    for (compressed_size, uncompressed_size, checksum) in chunkinfo {
+        let chunk = p.bytes(compressed_size)?;
+        #[cfg(debug_assertions)]
+        {
+            let actual = crate::md5hash(chunk);
+            check!(
+                checksum == actual,
+                Error::ChecksumMismatch {
+                    expected: checksum,
+                    actual,
+                    what: "BLTE chunk",
+                }
+            );
+        }
+        parse_blte_chunk(
+            chunk,
+            &mut result[result_ptr..result_ptr + uncompressed_size],
+        )?;
+        result_ptr += uncompressed_size;
+        //ensure!(data.len() == uncompressed_size, "invalid uncompressed size");
+    }
+    check!(p.is_empty(), Error::malformed(WHAT, "trailing data"));
+    Ok(result)
+}
@@ -0,0 +1,547 @@
+//! On-disk cache laid out like the CDN, `{kind}/xx/yy/{key}`
+//!
+//! With a quota set, the least recently used `data/` entries are evicted
+//! once the cache outgrows it. Recency is the file access time, which is set
+//! explicitly on every hit so it works on `noatime` mounts too. Configs and
+//! archive indexes are pinned and never evicted.
+//!
+//! TACT responses are kept under `tact/{product}/{region}/{endpoint}` so an
+//! offline cache can resolve the last known versions without the network.
+
+use std::{
+    collections::HashSet,
+    io::Read,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::SystemTime,
+};
+
+use anyhow::{Result, bail};
+
+use crate::{
+    ArchiveKey, ContentKey, EncodingKey, Error, blte, cdn::CdnPool, config::Config, format_hex_key,
+    md5hash,
+};
+
+#[derive(Clone, Debug)]
+pub struct CacheByKey {
+    path: PathBuf,
+    /// Check entries against their key on every read
+    verify_reads: bool,
+    quota: Option<Arc<Quota>>,
+    /// Never fetch, fail on any miss
+    offline: bool,
+    /// Archives share `data/` with blobs but aren't named by a BLTE header
+    archives: Arc<RwLock<HashSet<ArchiveKey>>>,
+}
+
+#[derive(Debug)]
+struct Quota {
+    limit: u64,
+    /// Bytes in evictable entries, counted on first use
+    used: Mutex<Option<u64>>,
+}
+
+#[derive(Debug, Default)]
+pub struct EvictionStats {
+    pub evicted: usize,
+    pub evicted_bytes: u64,
+    pub remaining_bytes: u64,
+}
+
+/// Fraction of the quota eviction frees down to, so it doesn't run on every write
+const EVICT_TO_PERCENT: u64 = 90;
+
+/// Whether an entry is exempt from eviction
+fn is_pinned(kind: &str, name: &str) -> bool {
+    kind != "data" || name.ends_with(".index")
+}
+
+/// In-progress downloads and writes, never counted or evicted
+fn is_temporary(name: &str) -> bool {
+    name.ends_with(".part") || name.contains(".tmp.")
+}
+
+/// Suffix for temporary files so concurrent writers of one key don't collide
+pub(crate) fn temp_suffix() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    format!(
+        ".tmp.{}.{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+pub(crate) fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.to_owned().into_os_string();
+    path.push(suffix);
+    path.into()
+}
+
+impl CacheByKey {
+    pub fn new(arg: impl AsRef<Path>) -> Self {
+        Self {
+            path: arg.as_ref().to_owned(),
+            verify_reads: false,
+            quota: None,
+            offline: false,
+            archives: Default::default(),
+        }
+    }
+
+    /// Serve only what is already cached
+    pub fn with_offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self
+    }
+
+    pub fn is_offline(&self) -> bool {
+        self.offline
+    }
+
+    /// Keep evictable `data/` entries under `limit` bytes
+    pub fn with_quota(mut self, limit: u64) -> Self {
+        self.quota = Some(Arc::new(Quota {
+            limit,
+            used: Mutex::new(None),
+        }));
+        self
+    }
+
+    /// Hash check cache hits before returning them, evicting and refetching bad entries
+    pub fn with_verified_reads(mut self, verify_reads: bool) -> Self {
+        self.verify_reads = verify_reads;
+        self
+    }
+
+    fn keyed_path(&self, kind: &str, key: &str) -> PathBuf {
+        let mut keyed_path = self.path.join(kind);
+        keyed_path.push(format_hex_key(key));
+        keyed_path
+    }
+
+    pub fn contains(&self, kind: &str, key: &str) -> bool {
+        self.keyed_path(kind, key).is_file()
+    }
+
+    /// Marks `archives` as such, so verifying doesn't mistake them for blobs
+    pub fn add_archives(&self, archives: impl IntoIterator<Item = ArchiveKey>) {
+        self.archives.write().unwrap().extend(archives);
+    }
+
+    /// Marks the archives of every cached CDN config, see [`Self::add_archives`]
+    pub fn add_cached_archives(&self) -> Result<()> {
+        for key in self.keys("config")? {
+            let Some(mut file) = self.open("config", &key)? else {
+                continue;
+            };
+            let mut text = String::new();
+            if file.read_to_string(&mut text).is_err() {
+                continue;
+            }
+            let Ok(config) = Config::parse(&text) else {
+                continue;
+            };
+            self.add_archives(config.words("archives").filter_map(|x| x.parse().ok()));
+        }
+        Ok(())
+    }
+
+    /// Checks `data` against the key it is stored under.
+    ///
+    /// Configs are named by the MD5 of their content and data entries named
+    /// by ekey are BLTE blobs whose header hashes to that ekey. Entries with
+    /// other names, such as archive indexes and the archives passed to
+    /// [`Self::add_archives`], are not checked.
+    pub fn verify(&self, kind: &str, key: &str, data: &[u8]) -> Result<()> {
+        let (expected, actual, what) = match kind {
+            "config" => match key.parse::<ContentKey>() {
+                Ok(expected) => (expected.0, md5hash(data), "config content"),
+                Err(_) => return Ok(()),
+            },
+            "data" => match key.parse::<EncodingKey>() {
+                Ok(expected)
+                    if !self
+                        .archives
+                        .read()
+                        .unwrap()
+                        .contains(&ArchiveKey(expected.0)) =>
+                {
+                    (expected.0, blte::header_hash(data)?.0, "BLTE header")
+                }
+                _ => return Ok(()),
+            },
+            _ => return Ok(()),
+        };
+        if actual != expected {
+            return Err(anyhow::Error::new(Error::ChecksumMismatch {
+                expected,
+                actual,
+                what,
+            })
+            .context(format!("verifying {kind}/{key}")));
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument(err, skip(self, cdn))]
+    pub fn get(&self, cdn: &CdnPool, kind: &str, key: &str) -> Result<Vec<u8>> {
+        self.get_range(cdn, kind, key, key, None)
+    }
+
+    /// Like [`Self::get`], but on a miss fetches `range` of the remote file
+    /// `remote_key` instead, used to pull a single entry out of an archive
+    #[tracing::instrument(err, skip(self, cdn))]
+    pub fn get_range(
+        &self,
+        cdn: &CdnPool,
+        kind: &str,
+        key: &str,
+        remote_key: &str,
+        range: Option<Range<usize>>,
+    ) -> Result<Vec<u8>> {
+        tracing::info!("Retrieving {kind}/{key}");
+        if let Some(file) = self.read(kind, key)? {
+            return Ok(file);
+        }
+        tracing::debug!("Cache miss");
+        let keyed_path = self.keyed_path(kind, key);
+        if self.offline {
+            bail!("{kind}/{key} is not cached and offline mode is enabled");
+        }
+        std::fs::create_dir_all(keyed_path.parent().unwrap())?;
+        let part = cdn.fetch_to_file(
+            &format!("{kind}/{}", format_hex_key(remote_key)),
+            range,
+            &with_suffix(&keyed_path, ".part"),
+        )?;
+        let data = std::fs::read(part.path())?;
+        if let Err(e) = self.verify(kind, key, &data) {
+            std::fs::remove_file(part.path())?;
+            return Err(e.context("downloaded data is corrupt"));
+        }
+        let replaced = file_size(&keyed_path);
+        part.persist(&keyed_path)?;
+        self.account(kind, key, replaced, data.len() as u64)?;
+        Ok(data)
+    }
+
+    /// Opens `kind/key` for streaming without verifying it, `None` on a miss
+    pub fn open(&self, kind: &str, key: &str) -> Result<Option<std::fs::File>> {
+        match std::fs::File::open(self.keyed_path(kind, key)) {
+            Ok(file) => Ok(Some(file)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Reads `kind/key` without fetching, `None` on a miss.
+    ///
+    /// With verified reads a corrupt entry is evicted and reported as a miss.
+    pub fn read(&self, kind: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        let keyed_path = self.keyed_path(kind, key);
+        let file = match std::fs::read(&keyed_path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if self.verify_reads
+            && let Err(e) = self.verify(kind, key, &file)
+        {
+            tracing::warn!("Evicting corrupt cache entry: {e:#}");
+            self.remove(kind, key)?;
+            return Ok(None);
+        }
+        tracing::debug!("Cache hit");
+        if self.quota.is_some() {
+            touch(&keyed_path);
+        }
+        Ok(Some(file))
+    }
+
+    /// Stores `data` under `kind/key`, replacing any existing entry.
+    ///
+    /// The data is written to a temporary file first and renamed into place,
+    /// so readers never see a partially written entry.
+    pub fn insert(&self, kind: &str, key: &str, data: &[u8]) -> Result<()> {
+        self.verify(kind, key, data)?;
+        let keyed_path = self.keyed_path(kind, key);
+        let replaced = file_size(&keyed_path);
+        write_atomic(&keyed_path, data)?;
+        self.account(kind, key, replaced, data.len() as u64)
+    }
+
+    fn tact_path(&self, product: &str, region: &str, endpoint: &str) -> PathBuf {
+        [
+            self.path.as_path(),
+            "tact".as_ref(),
+            product.as_ref(),
+            region.as_ref(),
+            endpoint.as_ref(),
+        ]
+        .iter()
+        .collect()
+    }
+
+    /// Remembers a TACT response such as `versions` for offline use
+    pub fn store_tact(
+        &self,
+        product: &str,
+        region: &str,
+        endpoint: &str,
+        text: &str,
+    ) -> Result<()> {
+        write_atomic(&self.tact_path(product, region, endpoint), text.as_bytes())?;
+        Ok(())
+    }
+
+    /// The last TACT response stored with [`Self::store_tact`]
+    pub fn load_tact(&self, product: &str, region: &str, endpoint: &str) -> Result<String> {
+        let path = self.tact_path(product, region, endpoint);
+        match std::fs::read_to_string(&path) {
+            Ok(text) => Ok(text),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                bail!("{product} {endpoint} for region {region} is not cached")
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Deletes `kind/key` if present
+    pub fn remove(&self, kind: &str, key: &str) -> Result<()> {
+        let keyed_path = self.keyed_path(kind, key);
+        let size = file_size(&keyed_path);
+        match std::fs::remove_file(&keyed_path) {
+            Ok(()) => self.account(kind, key, size, 0),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Walks every file under `kind/`, yielding its path, name and metadata
+    fn walk(&self, kind: &str) -> Result<Vec<(PathBuf, String, std::fs::Metadata)>> {
+        let mut entries = vec![];
+        let mut dirs = vec![self.path.join(kind)];
+        while let Some(dir) = dirs.pop() {
+            let read_dir = match std::fs::read_dir(&dir) {
+                Ok(read_dir) => read_dir,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            for entry in read_dir {
+                let entry = entry?;
+                let meta = entry.metadata()?;
+                if meta.is_dir() {
+                    dirs.push(entry.path());
+                } else {
+                    let name = entry.file_name().to_string_lossy().into_owned();
+                    entries.push((entry.path(), name, meta));
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    /// The keys of every complete entry under `kind/`
+    pub fn keys(&self, kind: &str) -> Result<Vec<String>> {
+        Ok(self
+            .walk(kind)?
+            .into_iter()
+            .map(|(_, name, _)| name)
+            .filter(|name| !is_temporary(name))
+            .collect())
+    }
+
+    /// Walks every evictable entry, yielding its path, size and last access time
+    fn evictable(&self) -> Result<Vec<(PathBuf, u64, SystemTime)>> {
+        let mut entries = vec![];
+        for (path, name, meta) in self.walk("data")? {
+            if !is_pinned("data", &name) && !is_temporary(&name) {
+                let accessed = meta.accessed().or_else(|_| meta.modified())?;
+                entries.push((path, meta.len(), accessed));
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Updates the quota for an entry that went from `old` to `new` bytes,
+    /// zero meaning absent, evicting if it is now exceeded
+    fn account(&self, kind: &str, key: &str, old: u64, new: u64) -> Result<()> {
+        let Some(quota) = &self.quota else {
+            return Ok(());
+        };
+        if is_pinned(kind, key) {
+            return Ok(());
+        }
+        let mut used = quota.used.lock().unwrap();
+        let total = match *used {
+            Some(used) => (used + new).saturating_sub(old),
+            None => self.evictable()?.iter().map(|(_, size, _)| size).sum(),
+        };
+        *used = Some(total);
+        if total > quota.limit {
+            drop(used);
+            self.evict_to(quota.limit * EVICT_TO_PERCENT / 100)?;
+        }
+        Ok(())
+    }
+
+    /// Deletes least recently used `data/` entries until at most `target` bytes remain
+    #[tracing::instrument(err, skip(self))]
+    pub fn evict_to(&self, target: u64) -> Result<EvictionStats> {
+        let mut entries = self.evictable()?;
+        entries.sort_unstable_by_key(|(_, _, accessed)| *accessed);
+        let mut stats = EvictionStats {
+            remaining_bytes: entries.iter().map(|(_, size, _)| size).sum(),
+            ..Default::default()
+        };
+        for (path, size, _) in entries {
+            if stats.remaining_bytes <= target {
+                break;
+            }
+            match std::fs::remove_file(&path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+            stats.evicted += 1;
+            stats.evicted_bytes += size;
+            stats.remaining_bytes -= size;
+        }
+        if let Some(quota) = &self.quota {
+            *quota.used.lock().unwrap() = Some(stats.remaining_bytes);
+        }
+        tracing::info!(
+            evicted = stats.evicted,
+            evicted_bytes = stats.evicted_bytes,
+            remaining_bytes = stats.remaining_bytes,
+            "Evicted cache entries"
+        );
+        Ok(stats)
+    }
+}
+
+/// Writes `data` to a temporary file and renames it over `path`, so readers
+/// never see a partially written file
+fn write_atomic(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    let temp = with_suffix(path, &temp_suffix());
+    let written = std::fs::File::create(&temp).and_then(|mut file| {
+        std::io::Write::write_all(&mut file, data)?;
+        file.sync_data()
+    });
+    let renamed = written.and_then(|_| std::fs::rename(&temp, path));
+    if renamed.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    renamed
+}
+
+/// Size of the file at `path`, zero if it doesn't exist
+fn file_size(path: &Path) -> u64 {
+    std::fs::metadata(path).map_or(0, |meta| meta.len())
+}
+
+/// Marks an entry as just used for LRU eviction
+fn touch(path: &Path) {
+    if let Err(e) = filetime::set_file_atime(path, filetime::FileTime::now()) {
+        tracing::debug!("Could not update access time of {}: {e}", path.display());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::testing::{ScratchDir, blte};
+
+    fn age(cache: &CacheByKey, key: &str, secs: u64) {
+        let accessed = SystemTime::now() - Duration::from_secs(secs);
+        let path = cache.keyed_path("data", key);
+        filetime::set_file_atime(path, accessed.into()).unwrap();
+    }
+
+    fn evictable_bytes(cache: &CacheByKey) -> u64 {
+        cache
+            .evictable()
+            .unwrap()
+            .iter()
+            .map(|(_, size, _)| size)
+            .sum()
+    }
+
+    #[test]
+    fn overwrites_are_not_counted_twice() -> Result<()> {
+        let dir = ScratchDir::new("cache")?;
+        let cache = CacheByKey::new(dir.path()).with_quota(1000);
+        for _ in 0..3 {
+            cache.insert("data", "entry-a", &[0; 400])?;
+        }
+        assert!(cache.contains("data", "entry-a"));
+        assert_eq!(
+            *cache.quota.as_ref().unwrap().used.lock().unwrap(),
+            Some(400)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn evicts_least_recently_used_first() -> Result<()> {
+        let dir = ScratchDir::new("cache")?;
+        let cache = CacheByKey::new(dir.path()).with_quota(300);
+        for (key, secs) in [("entry-a", 30), ("entry-b", 20), ("entry-c", 10)] {
+            cache.insert("data", key, &[0; 100])?;
+            age(&cache, key, secs);
+        }
+        cache.insert("config", "pinned", &[0; 1000])?;
+        // the oldest entry is read again, so it outlives the two after it
+        assert!(cache.read("data", "entry-a")?.is_some());
+
+        cache.insert("data", "entry-d", &[0; 100])?;
+        let mut keys = cache.keys("data")?;
+        keys.sort();
+        assert_eq!(keys, ["entry-a", "entry-d"]);
+        assert!(cache.contains("config", "pinned"));
+        assert!(evictable_bytes(&cache) <= 300 * EVICT_TO_PERCENT / 100);
+        Ok(())
+    }
+
+    #[test]
+    fn stays_under_the_quota() -> Result<()> {
+        let dir = ScratchDir::new("cache")?;
+        let cache = CacheByKey::new(dir.path()).with_quota(1000);
+        for i in 0..50 {
+            cache.insert("data", &format!("entry-{i}"), &[0; 70])?;
+            assert!(evictable_bytes(&cache) <= 1000);
+        }
+        assert!(cache.contains("data", "entry-49"));
+        Ok(())
+    }
+
+    #[test]
+    fn evicting_corrupt_entries_frees_their_quota() -> Result<()> {
+        let dir = ScratchDir::new("cache")?;
+        let (ekey, data) = blte(&[7; 64]);
+        let limit = 600 + data.len() as u64 + 300;
+        let cache = CacheByKey::new(dir.path())
+            .with_quota(limit)
+            .with_verified_reads(true);
+        cache.insert("data", "entry-a", &[0; 600])?;
+        cache.insert("data", &ekey.to_string(), &data)?;
+
+        let mut corrupt = data.clone();
+        corrupt[8] ^= 0xff;
+        std::fs::write(cache.keyed_path("data", &ekey.to_string()), corrupt)?;
+        assert_eq!(cache.read("data", &ekey.to_string())?, None);
+
+        // fits exactly in the space the corrupt entry gave back
+        cache.insert("data", "entry-b", &vec![0; data.len() + 300])?;
+        assert!(cache.contains("data", "entry-a"));
+        assert!(cache.contains("data", "entry-b"));
+        Ok(())
+    }
+}
@@ -0,0 +1,692 @@
+//! CDN host selection and failover
+
+use std::{
+    fs::{File, TryLockError},
+    io::Read,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Condvar, Mutex,
+        atomic::{AtomicU32, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result, anyhow, ensure};
+
+use crate::{
+    Error, PipeSeparatedVars,
+    cache::{temp_suffix, with_suffix},
+    transport::{Transport, TransportResponse},
+};
+
+/// How long a failed host is skipped before it is tried again
+const UNHEALTHY_COOLDOWN: Duration = Duration::from_secs(60);
+
+/// A user supplied CDN prefix such as `https://mirror.example/tpr/wow/`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Mirror {
+    pub url: String,
+    /// Preferred mirrors are tried before the official hosts, others after them
+    pub preferred: bool,
+}
+
+#[derive(Debug)]
+struct CdnHost {
+    /// Full prefix including the product path, always ending in `/`
+    prefix: String,
+    failures: AtomicU32,
+    unhealthy_until: Mutex<Option<Instant>>,
+    /// Requests currently in flight to this host
+    active: Mutex<usize>,
+    slot_freed: Condvar,
+    /// Wakes async requests waiting for a slot
+    #[cfg(feature = "tokio")]
+    slot_freed_async: tokio::sync::Notify,
+}
+
+/// An in-flight request slot on a host, released on drop
+pub(crate) struct HostSlot<'a>(&'a CdnHost);
+
+impl Drop for HostSlot<'_> {
+    fn drop(&mut self) {
+        *self.0.active.lock().unwrap() -= 1;
+        self.0.slot_freed.notify_one();
+        #[cfg(feature = "tokio")]
+        self.0.slot_freed_async.notify_one();
+    }
+}
+
+impl CdnHost {
+    fn new(prefix: String) -> Self {
+        let prefix = if prefix.ends_with('/') {
+            prefix
+        } else {
+            prefix + "/"
+        };
+        Self {
+            prefix,
+            failures: AtomicU32::new(0),
+            unhealthy_until: Mutex::new(None),
+            active: Mutex::new(0),
+            slot_freed: Condvar::new(),
+            #[cfg(feature = "tokio")]
+            slot_freed_async: tokio::sync::Notify::new(),
+        }
+    }
+
+    /// Waits until fewer than `limit` requests are in flight to this host
+    fn acquire(&self, limit: Option<usize>) -> HostSlot<'_> {
+        let mut active = self.active.lock().unwrap();
+        if let Some(limit) = limit {
+            while *active >= limit {
+                active = self.slot_freed.wait(active).unwrap();
+            }
+        }
+        *active += 1;
+        HostSlot(self)
+    }
+
+    /// [`Self::acquire`] without blocking the executor
+    #[cfg(feature = "tokio")]
+    async fn acquire_async(&self, limit: Option<usize>) -> HostSlot<'_> {
+        loop {
+            let freed = self.slot_freed_async.notified();
+            tokio::pin!(freed);
+            // registered before checking, so a slot freed in between still wakes us
+            freed.as_mut().enable();
+            {
+                let mut active = self.active.lock().unwrap();
+                if limit.is_none_or(|limit| *active < limit) {
+                    *active += 1;
+                    return HostSlot(self);
+                }
+            }
+            freed.await;
+        }
+    }
+
+    fn is_healthy(&self, now: Instant) -> bool {
+        self.unhealthy_until
+            .lock()
+            .unwrap()
+            .is_none_or(|until| until <= now)
+    }
+
+    fn mark_failed(&self) {
+        self.failures.fetch_add(1, Ordering::Relaxed);
+        *self.unhealthy_until.lock().unwrap() = Some(Instant::now() + UNHEALTHY_COOLDOWN);
+    }
+
+    fn mark_ok(&self) {
+        *self.unhealthy_until.lock().unwrap() = None;
+    }
+}
+
+/// The hosts of a pool with their health and request limit, shared by
+/// [`CdnPool`] and the async client's CDN
+#[derive(Debug)]
+pub(crate) struct Hosts {
+    hosts: Vec<CdnHost>,
+    /// Index of the host that last succeeded, requests start there
+    current: AtomicUsize,
+    /// Maximum concurrent requests per host, unlimited if zero
+    limit: AtomicUsize,
+}
+
+impl Hosts {
+    /// Hosts from explicit prefixes, first one preferred
+    pub(crate) fn new(prefixes: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut hosts: Vec<CdnHost> = vec![];
+        for prefix in prefixes {
+            let host = CdnHost::new(prefix);
+            if !hosts.iter().any(|x| x.prefix == host.prefix) {
+                hosts.push(host);
+            }
+        }
+        ensure!(!hosts.is_empty(), "no CDN hosts");
+        Ok(Self {
+            hosts,
+            current: AtomicUsize::new(0),
+            limit: AtomicUsize::new(0),
+        })
+    }
+
+    /// Swaps in a new per-host limit, returning the previous one
+    pub(crate) fn swap_limit(&self, limit: Option<usize>) -> Option<usize> {
+        let previous = self
+            .limit
+            .swap(limit.map_or(0, |x| x.max(1)), Ordering::Relaxed);
+        (previous != 0).then_some(previous)
+    }
+
+    fn limit(&self) -> Option<usize> {
+        let limit = self.limit.load(Ordering::Relaxed);
+        (limit != 0).then_some(limit)
+    }
+
+    pub(crate) fn primary(&self) -> &str {
+        &self.hosts[self.current.load(Ordering::Relaxed) % self.hosts.len()].prefix
+    }
+
+    pub(crate) fn health(&self) -> impl Iterator<Item = (&str, bool)> {
+        let now = Instant::now();
+        self.hosts
+            .iter()
+            .map(move |x| (x.prefix.as_str(), x.is_healthy(now)))
+    }
+
+    /// Starts a request for `path`, relative to the CDN prefix.
+    ///
+    /// Hosts are tried starting from the last one that worked. Unhealthy
+    /// hosts are only tried once every healthy one has failed.
+    pub(crate) fn failover<'a>(&'a self, path: &'a str) -> Failover<'a> {
+        let start = self.current.load(Ordering::Relaxed);
+        let now = Instant::now();
+        let order = (0..self.hosts.len()).map(|i| (start + i) % self.hosts.len());
+        let (healthy, unhealthy): (Vec<_>, Vec<_>) =
+            order.partition(|&i| self.hosts[i].is_healthy(now));
+        Failover {
+            hosts: self,
+            path,
+            order: healthy
+                .into_iter()
+                .chain(unhealthy)
+                .collect::<Vec<_>>()
+                .into_iter(),
+            last_error: None,
+            all_not_found: true,
+        }
+    }
+}
+
+/// One request's way through the hosts of a pool
+pub(crate) struct Failover<'a> {
+    hosts: &'a Hosts,
+    path: &'a str,
+    order: std::vec::IntoIter<usize>,
+    last_error: Option<anyhow::Error>,
+    all_not_found: bool,
+}
+
+impl<'a> Failover<'a> {
+    /// The next host to try and its URL for the path, `None` once all have failed
+    pub(crate) fn next_host(&mut self) -> Option<(usize, String)> {
+        let i = self.order.next()?;
+        Some((i, format!("{}{}", self.hosts.hosts[i].prefix, self.path)))
+    }
+
+    /// Whether the host from [`Self::next_host`] is the last one left.
+    ///
+    /// Only the last host retries transient failures, the others fail over
+    /// straight away rather than spending the backoff on a struggling host.
+    pub(crate) fn is_last(&self) -> bool {
+        self.order.len() == 0
+    }
+
+    /// Waits for a request slot on host `i`
+    pub(crate) fn acquire(&self, i: usize) -> HostSlot<'a> {
+        self.hosts.hosts[i].acquire(self.hosts.limit())
+    }
+
+    /// [`Self::acquire`] without blocking the executor
+    #[cfg(feature = "tokio")]
+    pub(crate) async fn acquire_async(&self, i: usize) -> HostSlot<'a> {
+        self.hosts.hosts[i].acquire_async(self.hosts.limit()).await
+    }
+
+    /// Host `i` answered, later requests start there
+    pub(crate) fn succeeded(self, i: usize) {
+        self.hosts.hosts[i].mark_ok();
+        self.hosts.current.store(i, Ordering::Relaxed);
+    }
+
+    /// Host `i` failed with `e`, which is worth trying the next host for.
+    ///
+    /// Hosts are marked unhealthy by failures other than a 404.
+    pub(crate) fn failed(&mut self, i: usize, e: anyhow::Error) {
+        let host = &self.hosts.hosts[i];
+        tracing::warn!(
+            host = host.prefix,
+            "CDN request failed, trying next host: {e:#}"
+        );
+        // a missing file says nothing about the host's health
+        let not_found = matches!(Error::find(&e), Some(Error::Http { status: 404, .. }));
+        if !not_found {
+            host.mark_failed();
+        }
+        self.all_not_found &= not_found;
+        self.last_error = Some(e);
+    }
+
+    /// The error once every host has failed, [`Error::NotFound`] if none had the file
+    pub(crate) fn into_error(self) -> anyhow::Error {
+        match self.last_error {
+            Some(_) if self.all_not_found => Error::NotFound {
+                key: self.path.to_owned(),
+            }
+            .into(),
+            Some(e) => e.context(format!("all CDN hosts failed for {}", self.path)),
+            None => anyhow!("no CDN hosts"),
+        }
+    }
+}
+
+/// Sorts out responses that should fail over.
+///
+/// Client and server errors move on to the next host, since another host
+/// may well have the file or let us read it. A 416 fails the request, as the
+/// range is just as unsatisfiable elsewhere.
+pub(crate) fn check_status<T>(url: &str, status: u16, response: T) -> Result<Attempt<T>> {
+    let error = || Error::Http {
+        url: url.to_owned(),
+        status,
+    };
+    if (400..600).contains(&status) && status != 416 {
+        return Ok(Attempt::Failover(error().into()));
+    }
+    ensure!((200..300).contains(&status), error());
+    Ok(Attempt::Done(response))
+}
+
+/// The requested part of a response body
+pub(crate) fn range_body(
+    url: &str,
+    status: u16,
+    data: bytes::Bytes,
+    range: &Option<Range<usize>>,
+) -> Result<bytes::Bytes> {
+    let Some(range) = range else {
+        return Ok(data);
+    };
+    // servers may ignore Range and send the whole file
+    let data = if status == 200 && data.len() >= range.end {
+        data.slice(range.clone())
+    } else {
+        data
+    };
+    ensure!(data.len() == range.len(), "short range response for {url}");
+    Ok(data)
+}
+
+/// Every CDN host known for a product, tried in order with failover
+#[derive(Debug)]
+pub struct CdnPool {
+    hosts: Hosts,
+    transport: Arc<dyn Transport>,
+}
+
+/// Outcome of a request against a single host
+pub(crate) enum Attempt<T> {
+    Done(T),
+    /// The host is unreachable or does not have the file, try the next one
+    Failover(anyhow::Error),
+}
+
+impl CdnPool {
+    /// Pool from explicit prefixes, first one preferred
+    pub fn new(
+        transport: Arc<dyn Transport>,
+        prefixes: impl IntoIterator<Item = String>,
+    ) -> Result<Self> {
+        Ok(Self {
+            hosts: Hosts::new(prefixes)?,
+            transport,
+        })
+    }
+
+    /// Builds a pool from a TACT `cdns` response, see [`prefixes_from_cdns`]
+    pub fn from_cdns(
+        transport: Arc<dyn Transport>,
+        cdns: &PipeSeparatedVars,
+        region: &str,
+        mirrors: &[Mirror],
+    ) -> Result<Self> {
+        Self::new(transport, prefixes_from_cdns(cdns, region, mirrors)?)
+    }
+
+    /// Limits how many requests may be in flight to any single host, `None` for no limit
+    pub fn set_host_limit(&self, limit: Option<usize>) {
+        self.hosts.swap_limit(limit);
+    }
+
+    /// Runs `f` under [`Self::set_host_limit`], restoring the previous limit afterwards
+    pub fn with_host_limit<T>(&self, limit: Option<usize>, f: impl FnOnce() -> T) -> T {
+        let previous = self.hosts.swap_limit(limit);
+        let result = f();
+        self.hosts.swap_limit(previous);
+        result
+    }
+
+    /// Prefix of the host requests currently start at
+    pub fn primary(&self) -> &str {
+        self.hosts.primary()
+    }
+
+    /// All host prefixes with whether they are currently considered healthy
+    pub fn hosts(&self) -> impl Iterator<Item = (&str, bool)> {
+        self.hosts.health()
+    }
+
+    /// Runs `attempt` against each host in turn until one succeeds, see
+    /// [`Hosts::failover`]. It is told whether the host is the last one left.
+    fn with_failover<T>(
+        &self,
+        path: &str,
+        mut attempt: impl FnMut(&str, bool) -> Result<Attempt<T>>,
+    ) -> Result<T> {
+        let mut failover = self.hosts.failover(path);
+        while let Some((i, url)) = failover.next_host() {
+            let slot = failover.acquire(i);
+            let result = attempt(&url, failover.is_last())?;
+            drop(slot);
+            match result {
+                Attempt::Done(data) => {
+                    failover.succeeded(i);
+                    return Ok(data);
+                }
+                Attempt::Failover(e) => failover.failed(i, e),
+            }
+        }
+        Err(failover.into_error())
+    }
+
+    /// Sends the request and sorts out responses that should fail over,
+    /// retrying transient failures only on the `last` host
+    fn send(
+        &self,
+        url: &str,
+        range: Option<Range<usize>>,
+        last: bool,
+    ) -> Result<Attempt<TransportResponse>> {
+        let response = if last {
+            self.transport.get(url, range)
+        } else {
+            self.transport.get_once(url, range)
+        };
+        match response {
+            Ok(response) => check_status(url, response.status, response),
+            Err(e) => Ok(Attempt::Failover(e)),
+        }
+    }
+
+    /// Fetches `path`, relative to the CDN prefix, from the first host that has it.
+    ///
+    /// Connection errors and error statuses other than 416 move on to the
+    /// next host. Transient failures are only retried on the last host.
+    #[tracing::instrument(err, skip(self))]
+    pub fn fetch(&self, path: &str, range: Option<Range<usize>>) -> Result<bytes::Bytes> {
+        self.with_failover(path, |url, last| {
+            let response = match self.send(url, range.clone(), last)? {
+                Attempt::Done(response) => response,
+                Attempt::Failover(e) => return Ok(Attempt::Failover(e)),
+            };
+            let status = response.status;
+            let data = match response.bytes() {
+                Ok(data) => data,
+                // body interrupted mid-transfer
+                Err(e) => return Ok(Attempt::Failover(e.into())),
+            };
+            range_body(url, status, data, &range).map(Attempt::Done)
+        })
+    }
+
+    /// Whether any host has `path`, probed with a one byte range request
+    pub fn exists(&self, path: &str) -> Result<bool> {
+        match self.fetch(path, Some(0..1)) {
+            Ok(_) => Ok(true),
+            Err(e) if matches!(Error::find(&e), Some(Error::NotFound { .. })) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Streams `path`, or `range` of it, into the file `part`.
+    ///
+    /// Whatever `part` already holds is assumed to be the start of the
+    /// requested data and only the remainder is requested. A transfer that
+    /// breaks off leaves `part` in place, so the next host or a later call
+    /// resumes from there.
+    ///
+    /// `part` is locked until the returned [`PartFile`] is dropped. If another
+    /// writer holds it, this one downloads from scratch into a file of its own
+    /// instead, removed again unless persisted.
+    #[tracing::instrument(err, skip(self))]
+    pub fn fetch_to_file(
+        &self,
+        path: &str,
+        range: Option<Range<usize>>,
+        part: &Path,
+    ) -> Result<PartFile> {
+        let start = range.as_ref().map_or(0, |x| x.start);
+        let end = range.as_ref().map(|x| x.end);
+        // length of the requested data, if known up front
+        let total = range.as_ref().map(ExactSizeIterator::len);
+        let mut part = PartFile::lock(part)?;
+        part.len = self.with_failover(path, |url, last| {
+            let mut file = &part.file;
+            let mut have = file.metadata()?.len() as usize;
+            if total.is_some_and(|total| have > total) {
+                tracing::warn!("{} is longer than {url}, restarting", part.path.display());
+                file.set_len(0)?;
+                have = 0;
+            }
+            if total == Some(have) {
+                return Ok(Attempt::Done(have as u64));
+            }
+            let resume =
+                (have > 0 || range.is_some()).then(|| start + have..end.unwrap_or(usize::MAX));
+            if have > 0 {
+                tracing::info!("Resuming {url} at {have} bytes");
+            }
+
+            let sent = match self.send(url, resume, last) {
+                // `part` already holds the whole file, or more than it
+                Err(e)
+                    if have > 0
+                        && matches!(Error::find(&e), Some(Error::Http { status: 416, .. })) =>
+                {
+                    tracing::info!("Can't resume {url} at {have} bytes, restarting");
+                    file.set_len(0)?;
+                    have = 0;
+                    self.send(url, range.clone(), last)?
+                }
+                sent => sent?,
+            };
+            let mut response = match sent {
+                Attempt::Done(response) => response,
+                Attempt::Failover(e) => return Ok(Attempt::Failover(e)),
+            };
+            let (skip, have) = if response.status == 200 {
+                // Range was ignored, the body is the whole file
+                file.set_len(0)?;
+                (start, 0)
+            } else {
+                (0, have)
+            };
+            // bytes still missing from `part`, if known
+            let short = || anyhow!("{url} is shorter than requested");
+            let want = match (total, response.content_length) {
+                (Some(total), _) => Some(total.checked_sub(have).ok_or_else(short)?),
+                (None, Some(len)) => Some((len as usize).checked_sub(skip).ok_or_else(short)?),
+                (None, None) => None,
+            };
+
+            let body = &mut response.body;
+            let copied =
+                std::io::copy(&mut body.take(skip as u64), &mut std::io::sink()).and_then(|_| {
+                    std::io::copy(&mut body.take(want.unwrap_or(usize::MAX) as u64), &mut file)
+                });
+            if let Err(e) = copied {
+                return Ok(Attempt::Failover(
+                    anyhow::Error::from(e).context("transfer interrupted"),
+                ));
+            }
+            file.sync_data()?;
+            let len = file.metadata()?.len();
+            if let Some(want) = want {
+                let expected = (have + want) as u64;
+                ensure!(
+                    len == expected,
+                    "size mismatch for {url}: expected {expected} got {len}"
+                );
+            }
+            Ok(Attempt::Done(len))
+        })?;
+        Ok(part)
+    }
+}
+
+/// A download written by [`CdnPool::fetch_to_file`], locked against other writers
+#[derive(Debug)]
+pub struct PartFile {
+    path: PathBuf,
+    len: u64,
+    file: File,
+    /// Written from scratch beside a busy `.part`, so never worth resuming
+    private: bool,
+}
+
+impl PartFile {
+    /// Opens and locks `part`, or a fresh file beside it if another writer has it
+    fn lock(part: &Path) -> Result<Self> {
+        let open = |path: &Path, new: bool| {
+            File::options()
+                .create(true)
+                .create_new(new)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("opening {}", path.display()))
+        };
+        let file = open(part, false)?;
+        let (path, file, private) = match file.try_lock() {
+            Ok(()) => (part.to_owned(), file, false),
+            Err(TryLockError::WouldBlock) => {
+                let path = with_suffix(part, &temp_suffix());
+                tracing::debug!("{} is busy, writing {}", part.display(), path.display());
+                let file = open(&path, true)?;
+                file.lock()?;
+                (path, file, true)
+            }
+            Err(TryLockError::Error(e)) => {
+                return Err(anyhow::Error::from(e).context(format!("locking {}", part.display())));
+            }
+        };
+        Ok(Self {
+            path,
+            len: 0,
+            file,
+            private,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Complete length of the downloaded data
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Moves the download to `path`, releasing the lock afterwards
+    pub fn persist(mut self, path: &Path) -> std::io::Result<()> {
+        std::fs::rename(&self.path, path)?;
+        self.private = false;
+        Ok(())
+    }
+}
+
+impl Drop for PartFile {
+    fn drop(&mut self) {
+        if self.private {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// The CDN prefixes a TACT `cdns` response lists, in the order to try them.
+///
+/// Hosts of `region` come first, https before http and Blizzard's own
+/// CDN before third parties, followed by the hosts of every other region.
+/// Mirrors are placed before or after those according to [`Mirror::preferred`].
+pub fn prefixes_from_cdns(
+    cdns: &PipeSeparatedVars,
+    region: &str,
+    mirrors: &[Mirror],
+) -> Result<Vec<String>> {
+    let name = cdns.column("Name").unwrap_or(0);
+    let path = cdns.column("Path").unwrap_or(1);
+    let hosts = cdns.column("Hosts").unwrap_or(2);
+    let servers = cdns.column("Servers");
+
+    let mut prefixes = mirrors
+        .iter()
+        .filter(|x| x.preferred)
+        .map(|x| x.url.clone())
+        .collect::<Vec<_>>();
+
+    let mut entries = cdns
+        .entries()
+        .map(|x| x.collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    // stable sort keeps the listed order within the configured and other regions
+    entries.sort_by_key(|x| x[name] != region);
+    ensure!(
+        entries.first().is_some_and(|x| x[name] == region),
+        "no CDN entry for region {region}"
+    );
+
+    for ele in entries {
+        let mut urls = servers
+            .map(|i| ele[i])
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(|x| {
+                x.split('?')
+                    .next()
+                    .unwrap_or(x)
+                    .trim_end_matches('/')
+                    .to_owned()
+            })
+            .collect::<Vec<_>>();
+        urls.extend(
+            ele[hosts]
+                .split_whitespace()
+                .map(|host| format!("http://{host}")),
+        );
+        urls.sort_by_key(|x| (!x.starts_with("https://"), !x.contains("cdn.blizzard.com")));
+        prefixes.extend(urls.into_iter().map(|url| format!("{url}/{}/", ele[path])));
+    }
+
+    prefixes.extend(
+        mirrors
+            .iter()
+            .filter(|x| !x.preferred)
+            .map(|x| x.url.clone()),
+    );
+    Ok(prefixes)
+}
+
+impl std::str::FromStr for Mirror {
+    type Err = anyhow::Error;
+
+    /// Parses a `--mirror` argument, a leading `+` marks a preferred mirror
+    fn from_str(s: &str) -> Result<Self> {
+        let (preferred, url) = match s.strip_prefix('+') {
+            Some(url) => (true, url),
+            None => (false, s),
+        };
+        ensure!(
+            url.starts_with("http://") || url.starts_with("https://"),
+            "invalid mirror {s}, must be an http(s) URL"
+        );
+        Ok(Self {
+            url: url.to_owned(),
+            preferred,
+        })
+    }
+}
@@ -0,0 +1,38 @@
+//! Build and CDN configs, `key = value` text files stored under `config/`
+
+use anyhow::{Context, Result};
+
+/// The values of a build or CDN config in file order
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    values: Vec<(String, String)>,
+}
+
+impl Config {
+    pub fn parse(text: &str) -> Result<Self> {
+        let i = ini::Ini::load_from_str(text)?;
+        let sec = i.section(Option::<&str>::None).context("Invalid INI")?;
+        Ok(Self {
+            values: sec
+                .iter()
+                .map(|(k, v)| (k.to_owned(), v.to_owned()))
+                .collect(),
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The whitespace separated words of `name`, empty if it is missing
+    pub fn words(&self, name: &str) -> impl Iterator<Item = &str> {
+        self.get(name).unwrap_or_default().split_whitespace()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.values.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+}
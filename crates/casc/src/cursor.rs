@@ -0,0 +1,103 @@
+//! Bounds checked reading of binary formats.
+//!
+//! Every read fails with [`Error::Truncated`] naming the format being parsed
+//! instead of panicking like [`bytes::Buf`] does on short input.
+
+use crate::error::{Error, Result};
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Cursor<'a> {
+    data: &'a [u8],
+    what: &'static str,
+}
+
+macro_rules! read_int {
+    ($($name:ident: $ty:ty = $from:ident;)*) => {
+        $(
+            pub(crate) fn $name(&mut self) -> Result<$ty> {
+                Ok(<$ty>::$from(self.array()?))
+            }
+        )*
+    };
+}
+
+impl<'a> Cursor<'a> {
+    pub(crate) fn new(data: &'a [u8], what: &'static str) -> Self {
+        Self { data, what }
+    }
+
+    pub(crate) fn truncated(&self) -> Error {
+        Error::Truncated { what: self.what }
+    }
+
+    pub(crate) fn remaining(&self) -> usize {
+        self.data.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Everything not read yet
+    pub(crate) fn rest(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// The next `n` bytes without consuming them
+    pub(crate) fn peek(&self, n: usize) -> Result<&'a [u8]> {
+        self.data.get(..n).ok_or_else(|| self.truncated())
+    }
+
+    pub(crate) fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        let bytes = self.peek(n)?;
+        self.data = &self.data[n..];
+        Ok(bytes)
+    }
+
+    pub(crate) fn skip(&mut self, n: usize) -> Result<()> {
+        self.bytes(n).map(|_| ())
+    }
+
+    /// Splits off the next `n` bytes as their own cursor
+    pub(crate) fn sub(&mut self, n: usize, what: &'static str) -> Result<Cursor<'a>> {
+        Ok(Cursor::new(self.bytes(n)?, what))
+    }
+
+    pub(crate) fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    /// Bytes up to the next NUL, which is consumed but not returned
+    pub(crate) fn cstr(&mut self) -> Result<&'a [u8]> {
+        let len = self
+            .data
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or_else(|| self.truncated())?;
+        let s = self.bytes(len)?;
+        self.skip(1)?;
+        Ok(s)
+    }
+
+    read_int! {
+        u8: u8 = from_be_bytes;
+        u16: u16 = from_be_bytes;
+        u16_le: u16 = from_le_bytes;
+        u32: u32 = from_be_bytes;
+        u32_le: u32 = from_le_bytes;
+        i32_le: i32 = from_le_bytes;
+        u64: u64 = from_be_bytes;
+        u64_le: u64 = from_le_bytes;
+        u128: u128 = from_be_bytes;
+    }
+
+    /// A big endian 40-bit integer, as used for file sizes
+    pub(crate) fn u40(&mut self) -> Result<u64> {
+        Ok((u64::from(self.u8()?) << 32) | u64::from(self.u32()?))
+    }
+
+    /// A big endian 24-bit integer
+    pub(crate) fn u24(&mut self) -> Result<u32> {
+        Ok((u32::from(self.u8()?) << 16) | u32::from(self.u16()?))
+    }
+}
@@ -0,0 +1,294 @@
+//! What changed between two builds of a product
+
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::Result;
+use derive_more::Display;
+
+use crate::{
+    CascClient, ContentKey, EncodingKey, FileDataID, VersionEntry,
+    product::RootFormat,
+    root::{LOCALE_ENUS, RootEntry},
+};
+
+#[derive(Clone, Copy, Debug, Display, Eq, PartialEq)]
+pub enum ChangeKind {
+    #[display("added")]
+    Added,
+    #[display("removed")]
+    Removed,
+    #[display("modified")]
+    Modified,
+}
+
+/// A file's content in one build
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FileVersion {
+    pub ckey: ContentKey,
+    /// Decoded size
+    pub size: u64,
+}
+
+/// A file present in either build whose content differs
+#[derive(Clone, Debug)]
+pub struct Change<K> {
+    pub id: K,
+    pub old: Option<FileVersion>,
+    pub new: Option<FileVersion>,
+}
+
+impl<K> Change<K> {
+    pub fn kind(&self) -> ChangeKind {
+        match (self.old, self.new) {
+            (None, _) => ChangeKind::Added,
+            (_, None) => ChangeKind::Removed,
+            _ => ChangeKind::Modified,
+        }
+    }
+}
+
+/// Encoding table differences, sizes are encoded sizes
+#[derive(Clone, Debug, Default)]
+pub struct EncodingDiff {
+    pub added: usize,
+    pub removed: usize,
+    pub added_bytes: u64,
+    pub removed_bytes: u64,
+}
+
+#[derive(Debug)]
+pub struct BuildDiff {
+    pub old: VersionEntry,
+    pub new: VersionEntry,
+    /// Install manifest changes by file name
+    pub install: Vec<Change<String>>,
+    /// Root manifest changes by FileDataID, `None` for products without a WoW root
+    pub root: Option<Vec<Change<FileDataID>>>,
+    pub encoding: EncodingDiff,
+}
+
+impl BuildDiff {
+    /// Bytes a client on the old build fetches to update, the new blobs' encoded sizes
+    pub fn download_bytes(&self) -> u64 {
+        self.encoding.added_bytes
+    }
+}
+
+/// Number of added, removed and modified entries in `changes`
+pub fn count<K>(changes: &[Change<K>]) -> (usize, usize, usize) {
+    changes
+        .iter()
+        .fold((0, 0, 0), |(a, r, m), x| match x.kind() {
+            ChangeKind::Added => (a + 1, r, m),
+            ChangeKind::Removed => (a, r + 1, m),
+            ChangeKind::Modified => (a, r, m + 1),
+        })
+}
+
+fn diff_maps<K: Clone + Ord>(
+    old: &BTreeMap<K, FileVersion>,
+    new: &BTreeMap<K, FileVersion>,
+) -> Vec<Change<K>> {
+    let mut changes = old
+        .iter()
+        .filter(|(id, version)| new.get(id).is_none_or(|x| x.ckey != version.ckey))
+        .map(|(id, version)| Change {
+            id: id.clone(),
+            old: Some(*version),
+            new: new.get(id).copied(),
+        })
+        .collect::<Vec<_>>();
+    changes.extend(
+        new.iter()
+            .filter(|(id, _)| !old.contains_key(id))
+            .map(|(id, version)| Change {
+                id: id.clone(),
+                old: None,
+                new: Some(*version),
+            }),
+    );
+    changes.sort_by(|a, b| a.id.cmp(&b.id));
+    changes
+}
+
+fn install_files(client: &CascClient) -> BTreeMap<String, FileVersion> {
+    client
+        .install()
+        .files
+        .iter()
+        .map(|x| {
+            let version = FileVersion {
+                ckey: x.key,
+                size: x.size.into(),
+            };
+            (x.name.clone(), version)
+        })
+        .collect()
+}
+
+/// One entry per FileDataID, the enUS one where locales differ
+fn pick_locales(entries: impl IntoIterator<Item = RootEntry>) -> HashMap<FileDataID, RootEntry> {
+    let mut picked = HashMap::<FileDataID, RootEntry>::new();
+    for entry in entries {
+        let current = picked.entry(entry.fdid).or_insert(entry);
+        if current.locale_flags & LOCALE_ENUS == 0 && entry.locale_flags & LOCALE_ENUS != 0 {
+            *current = entry;
+        }
+    }
+    picked
+}
+
+fn root_files(client: &CascClient) -> Result<BTreeMap<FileDataID, FileVersion>> {
+    Ok(pick_locales(client.root()?.entries)
+        .into_values()
+        .map(|x| {
+            let size = client.encoding().content_size(x.ckey).unwrap_or_default();
+            (x.fdid, FileVersion { ckey: x.ckey, size })
+        })
+        .collect())
+}
+
+/// Compares the install, root and encoding manifests of two builds
+#[tracing::instrument(err, skip_all)]
+pub fn diff(old: &CascClient, new: &CascClient) -> Result<BuildDiff> {
+    let install = diff_maps(&install_files(old), &install_files(new));
+    let has_root = |x: &CascClient| x.product().root_format == RootFormat::Wow;
+    let root = if has_root(old) && has_root(new) {
+        Some(diff_maps(&root_files(old)?, &root_files(new)?))
+    } else {
+        None
+    };
+
+    let encoding = diff_encoding(
+        &old.encoding().ekeys().collect(),
+        &new.encoding().ekeys().collect(),
+    );
+
+    Ok(BuildDiff {
+        old: old.version().clone(),
+        new: new.version().clone(),
+        install,
+        root,
+        encoding,
+    })
+}
+
+/// Counts the blobs only one of two encoding tables has, with their encoded sizes
+fn diff_encoding(old: &HashMap<EncodingKey, u64>, new: &HashMap<EncodingKey, u64>) -> EncodingDiff {
+    let mut encoding = EncodingDiff::default();
+    for (ekey, size) in new {
+        if !old.contains_key(ekey) {
+            encoding.added += 1;
+            encoding.added_bytes += size;
+        }
+    }
+    for (ekey, size) in old {
+        if !new.contains_key(ekey) {
+            encoding.removed += 1;
+            encoding.removed_bytes += size;
+        }
+    }
+    encoding
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(ckey: u128, size: u64) -> FileVersion {
+        FileVersion {
+            ckey: ContentKey(ckey),
+            size,
+        }
+    }
+
+    fn entry(fdid: u32, ckey: u128, locale_flags: u32) -> RootEntry {
+        RootEntry {
+            fdid: FileDataID(fdid),
+            ckey: ContentKey(ckey),
+            locale_flags,
+            content_flags: 0,
+            name_hash: None,
+        }
+    }
+
+    #[test]
+    fn diffs_added_removed_and_changed_files() {
+        let old = BTreeMap::from([
+            ("kept", version(1, 10)),
+            ("changed", version(2, 20)),
+            ("removed", version(3, 30)),
+        ]);
+        let new = BTreeMap::from([
+            ("kept", version(1, 10)),
+            ("changed", version(4, 25)),
+            ("added", version(5, 50)),
+        ]);
+        let changes = diff_maps(&old, &new);
+        let summary = changes
+            .iter()
+            .map(|x| (x.id, x.kind(), x.old, x.new))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                ("added", ChangeKind::Added, None, Some(version(5, 50))),
+                (
+                    "changed",
+                    ChangeKind::Modified,
+                    Some(version(2, 20)),
+                    Some(version(4, 25))
+                ),
+                ("removed", ChangeKind::Removed, Some(version(3, 30)), None),
+            ]
+        );
+        assert_eq!(count(&changes), (1, 1, 1));
+        assert!(diff_maps(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn root_files_prefer_enus() {
+        let picked = pick_locales([
+            entry(1, 0xde, 0x20),
+            entry(1, 0xe5, LOCALE_ENUS),
+            entry(1, 0xf5, 0x40),
+            entry(2, 0xaa, 0x20),
+        ]);
+        assert_eq!(picked.len(), 2);
+        assert_eq!(picked[&FileDataID(1)].ckey, ContentKey(0xe5));
+        assert_eq!(picked[&FileDataID(2)].ckey, ContentKey(0xaa));
+    }
+
+    #[test]
+    fn counts_encoding_blobs_and_download_bytes() {
+        let old = HashMap::from([(EncodingKey(1), 100), (EncodingKey(2), 200)]);
+        let new = HashMap::from([
+            (EncodingKey(2), 200),
+            (EncodingKey(3), 300),
+            (EncodingKey(4), 400),
+        ]);
+        let encoding = diff_encoding(&old, &new);
+        assert_eq!(
+            (encoding.added, encoding.added_bytes),
+            (2, 700),
+            "{encoding:?}"
+        );
+        assert_eq!((encoding.removed, encoding.removed_bytes), (1, 100));
+
+        let entry = VersionEntry {
+            region: "us".into(),
+            build_config: String::new(),
+            cdn_config: String::new(),
+            build_id: None,
+            versions_name: String::new(),
+        };
+        let diff = BuildDiff {
+            old: entry.clone(),
+            new: entry,
+            install: vec![],
+            root: None,
+            encoding,
+        };
+        assert_eq!(diff.download_bytes(), 700);
+    }
+}
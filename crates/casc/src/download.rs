@@ -0,0 +1,350 @@
+//! Concurrent download of many ekeys into the cache.
+//!
+//! Each ekey is resolved against the archive indexes to either a loose file
+//! or a byte range of an archive. Ranges that sit next to each other in the
+//! same archive are merged into one request and split again on arrival.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    ops::Range,
+    sync::Mutex,
+};
+
+use anyhow::{Context, Result};
+
+use crate::{ArchiveKey, CacheByKey, EncodingKey, Index, cdn::CdnPool};
+
+#[derive(Clone, Debug)]
+pub struct DownloadOptions {
+    /// Worker threads, and so the number of requests in flight overall
+    pub concurrency: usize,
+    /// Requests in flight to any single CDN host, applied to the pool
+    pub per_host: Option<usize>,
+    /// Largest request coalesced ranges may grow to
+    pub max_coalesced_bytes: usize,
+    /// Unrequested bytes allowed between two ranges that are still merged
+    pub max_gap: usize,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        Self {
+            concurrency: 8,
+            per_host: Some(4),
+            max_coalesced_bytes: 16 * 1024 * 1024,
+            max_gap: 0,
+        }
+    }
+}
+
+/// A single request to the CDN
+#[derive(Debug, Eq, PartialEq)]
+pub enum DownloadJob {
+    Loose(EncodingKey),
+    /// One range of an archive covering every listed `(ekey, range)` entry
+    Archive {
+        archive: ArchiveKey,
+        range: Range<usize>,
+        entries: Vec<(EncodingKey, Range<usize>)>,
+    },
+}
+
+impl DownloadJob {
+    fn ekeys(&self) -> Vec<EncodingKey> {
+        match self {
+            DownloadJob::Loose(ekey) => vec![*ekey],
+            DownloadJob::Archive { entries, .. } => entries.iter().map(|(k, _)| *k).collect(),
+        }
+    }
+}
+
+/// Resolves `ekeys` through `index` and merges neighbouring archive ranges
+pub fn plan(
+    ekeys: impl IntoIterator<Item = EncodingKey>,
+    index: &Index,
+    options: &DownloadOptions,
+) -> Vec<DownloadJob> {
+    let mut jobs = vec![];
+    let mut by_archive = HashMap::<ArchiveKey, Vec<(EncodingKey, Range<usize>)>>::new();
+    for ekey in ekeys {
+        match index.map.get(&ekey) {
+            Some(&(archive, size, offset)) => by_archive
+                .entry(archive)
+                .or_default()
+                .push((ekey, offset..offset + size)),
+            None => jobs.push(DownloadJob::Loose(ekey)),
+        }
+    }
+
+    for (archive, mut entries) in by_archive {
+        entries.sort_unstable_by_key(|(_, range)| range.start);
+        entries.dedup_by_key(|(ekey, _)| *ekey);
+        let mut current: Option<(Range<usize>, Vec<_>)> = None;
+        for (ekey, range) in entries {
+            if let Some((merged, merged_entries)) = &mut current {
+                let adjacent =
+                    range.start >= merged.end && range.start - merged.end <= options.max_gap;
+                if adjacent && range.end - merged.start <= options.max_coalesced_bytes {
+                    merged.end = range.end;
+                    merged_entries.push((ekey, range));
+                    continue;
+                }
+                let (range, entries) = current.take().unwrap();
+                jobs.push(DownloadJob::Archive {
+                    archive,
+                    range,
+                    entries,
+                });
+            }
+            current = Some((range.clone(), vec![(ekey, range)]));
+        }
+        if let Some((range, entries)) = current {
+            jobs.push(DownloadJob::Archive {
+                archive,
+                range,
+                entries,
+            });
+        }
+    }
+    jobs
+}
+
+#[derive(Debug, Default)]
+pub struct DownloadReport {
+    pub requested: usize,
+    pub already_cached: usize,
+    pub downloaded: usize,
+    pub requests: usize,
+    pub bytes: u64,
+    pub failed: Vec<(EncodingKey, String)>,
+}
+
+/// Downloads every ekey not already cached, writing each into `cache` as it arrives
+#[tracing::instrument(err, skip_all)]
+pub fn download(
+    cdn: &CdnPool,
+    cache: &CacheByKey,
+    index: &Index,
+    ekeys: impl IntoIterator<Item = EncodingKey>,
+    options: &DownloadOptions,
+) -> Result<DownloadReport> {
+    let mut report = DownloadReport::default();
+    let mut missing = vec![];
+    for ekey in ekeys {
+        report.requested += 1;
+        if cache.contains("data", &ekey.to_string()) {
+            report.already_cached += 1;
+        } else {
+            missing.push(ekey);
+        }
+    }
+
+    let queue = Mutex::new(
+        plan(missing, index, options)
+            .into_iter()
+            .collect::<VecDeque<_>>(),
+    );
+    report.requests = queue.lock().unwrap().len();
+    tracing::info!(
+        requests = report.requests,
+        already_cached = report.already_cached,
+        "Starting download"
+    );
+    let report = Mutex::new(report);
+
+    cdn.with_host_limit(options.per_host, || {
+        std::thread::scope(|s| {
+            for _ in 0..options.concurrency.max(1) {
+                s.spawn(|| {
+                    loop {
+                        let Some(job) = queue.lock().unwrap().pop_front() else {
+                            break;
+                        };
+                        let result = run_job(cdn, cache, &job);
+                        let mut report = report.lock().unwrap();
+                        match result {
+                            Ok((bytes, failed)) => {
+                                report.downloaded += job.ekeys().len() - failed.len();
+                                report.bytes += bytes;
+                                for (ekey, e) in failed {
+                                    tracing::warn!("Storing {ekey} failed: {e:#}");
+                                    report.failed.push((ekey, format!("{e:#}")));
+                                }
+                            }
+                            Err(e) => {
+                                tracing::warn!("Download failed: {e:#}");
+                                let e = format!("{e:#}");
+                                report
+                                    .failed
+                                    .extend(job.ekeys().into_iter().map(|k| (k, e.clone())));
+                            }
+                        }
+                    }
+                });
+            }
+        });
+    });
+
+    Ok(report.into_inner().unwrap())
+}
+
+/// Runs one request, returning the bytes fetched and any entries that could
+/// not be stored; an `Err` means nothing in the job arrived
+fn run_job(
+    cdn: &CdnPool,
+    cache: &CacheByKey,
+    job: &DownloadJob,
+) -> Result<(u64, Vec<(EncodingKey, anyhow::Error)>)> {
+    match job {
+        DownloadJob::Loose(ekey) => {
+            let key = ekey.to_string();
+            let data = cache
+                .get(cdn, "data", &key)
+                .with_context(|| format!("downloading loose {key}"))?;
+            Ok((data.len() as u64, vec![]))
+        }
+        DownloadJob::Archive {
+            archive,
+            range,
+            entries,
+        } => {
+            let data = cdn
+                .fetch(
+                    &format!("data/{}", crate::format_hex_key(&archive.to_string())),
+                    Some(range.clone()),
+                )
+                .with_context(|| format!("downloading {range:?} of archive {archive}"))?;
+            let failed = entries
+                .iter()
+                .filter_map(|(ekey, entry)| {
+                    let entry = entry.start - range.start..entry.end - range.start;
+                    cache
+                        .insert("data", &ekey.to_string(), &data[entry])
+                        .err()
+                        .map(|e| (*ekey, e))
+                })
+                .collect();
+            Ok((data.len() as u64, failed))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ekey(n: u8) -> EncodingKey {
+        EncodingKey(n.into())
+    }
+
+    fn archive(n: u8) -> ArchiveKey {
+        ArchiveKey(n.into())
+    }
+
+    fn index(entries: &[(u8, u8, usize, usize)]) -> Index {
+        Index {
+            map: entries
+                .iter()
+                .map(|&(e, a, offset, size)| (ekey(e), (archive(a), size, offset)))
+                .collect(),
+        }
+    }
+
+    fn sorted_plan(ekeys: &[u8], index: &Index, options: &DownloadOptions) -> Vec<DownloadJob> {
+        let mut jobs = plan(ekeys.iter().map(|&n| ekey(n)), index, options);
+        jobs.sort_by_key(|job| match job {
+            DownloadJob::Loose(ekey) => (None, 0, ekey.0),
+            DownloadJob::Archive { archive, range, .. } => (Some(archive.0), range.start, 0),
+        });
+        jobs
+    }
+
+    #[test]
+    fn adjacent_ranges_are_merged() {
+        let index = index(&[(1, 1, 0, 10), (2, 1, 10, 5), (3, 1, 15, 20)]);
+        let jobs = sorted_plan(&[3, 1, 2], &index, &DownloadOptions::default());
+        assert_eq!(
+            jobs,
+            [DownloadJob::Archive {
+                archive: archive(1),
+                range: 0..35,
+                entries: vec![(ekey(1), 0..10), (ekey(2), 10..15), (ekey(3), 15..35)],
+            }]
+        );
+    }
+
+    #[test]
+    fn gaps_split_unless_allowed() {
+        let index = index(&[(1, 1, 0, 10), (2, 1, 14, 6)]);
+        let split = sorted_plan(&[1, 2], &index, &DownloadOptions::default());
+        assert_eq!(
+            split,
+            [
+                DownloadJob::Archive {
+                    archive: archive(1),
+                    range: 0..10,
+                    entries: vec![(ekey(1), 0..10)],
+                },
+                DownloadJob::Archive {
+                    archive: archive(1),
+                    range: 14..20,
+                    entries: vec![(ekey(2), 14..20)],
+                },
+            ]
+        );
+
+        let options = DownloadOptions {
+            max_gap: 4,
+            ..Default::default()
+        };
+        let merged = sorted_plan(&[1, 2], &index, &options);
+        assert_eq!(
+            merged,
+            [DownloadJob::Archive {
+                archive: archive(1),
+                range: 0..20,
+                entries: vec![(ekey(1), 0..10), (ekey(2), 14..20)],
+            }]
+        );
+    }
+
+    #[test]
+    fn merged_ranges_stay_under_the_size_cap() {
+        let index = index(&[(1, 1, 0, 10), (2, 1, 10, 10), (3, 1, 20, 10)]);
+        let options = DownloadOptions {
+            max_coalesced_bytes: 20,
+            ..Default::default()
+        };
+        let ranges = sorted_plan(&[1, 2, 3], &index, &options)
+            .into_iter()
+            .map(|job| match job {
+                DownloadJob::Archive { range, .. } => range,
+                DownloadJob::Loose(_) => unreachable!(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(ranges, [0..20, 20..30]);
+    }
+
+    #[test]
+    fn duplicates_and_archives_are_kept_apart() {
+        // ekey 2 ends where ekey 3 starts, but in a different archive
+        let index = index(&[(1, 1, 0, 10), (2, 1, 10, 5), (3, 2, 15, 5)]);
+        let jobs = sorted_plan(&[2, 1, 4, 2, 3, 1], &index, &DownloadOptions::default());
+        assert_eq!(
+            jobs,
+            [
+                DownloadJob::Loose(ekey(4)),
+                DownloadJob::Archive {
+                    archive: archive(1),
+                    range: 0..15,
+                    entries: vec![(ekey(1), 0..10), (ekey(2), 10..15)],
+                },
+                DownloadJob::Archive {
+                    archive: archive(2),
+                    range: 15..20,
+                    entries: vec![(ekey(3), 15..20)],
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,275 @@
+use std::{collections, convert::TryInto, time::Instant};
+
+type HashMap<A, B> = collections::HashMap<A, B, ahash::RandomState>;
+type EncodingKeyVec = tinyvec::TinyVec<[EncodingKey; 1]>;
+
+use crate::{
+    cursor::Cursor,
+    error::{Error, Result, check},
+};
+
+use crate::{ContentKey, EncodingKey, TruncatedKey};
+
+pub struct Encoding {
+    _especs: Vec<String>,
+    c2e: Vec<(u128, u128, u64)>,
+    e2i: Vec<(u128, u32, u64)>,
+    //cmap: HashMap<ContentKey, (EncodingKey, u64)>,
+    cmap_extra: HashMap<ContentKey, EncodingKeyVec>,
+    _espec: String,
+}
+
+impl Encoding {
+    pub fn c2e(&self, c: ContentKey) -> Result<EncodingKey> {
+        let found = self.c2e.binary_search_by_key(&c.0, |&(a, _b, _c)| a);
+        if let Ok(found) = found {
+            Ok(EncodingKey(self.c2e[found].1))
+        } else {
+            Err(Error::NotFound { key: c.to_string() })
+        }
+    }
+
+    /// Decoded size of the file `c` names
+    pub fn content_size(&self, c: ContentKey) -> Option<u64> {
+        let found = self.c2e.binary_search_by_key(&c.0, |&(a, _b, _c)| a).ok()?;
+        Some(self.c2e[found].2)
+    }
+
+    /// Size of the BLTE blob `e` names
+    pub fn encoded_size(&self, e: EncodingKey) -> Option<u64> {
+        let found = self.e2i.binary_search_by_key(&e.0, |&(a, _b, _c)| a).ok()?;
+        Some(self.e2i[found].2)
+    }
+
+    /// The full ekey starting with `key`, as TVFS and local indexes store them
+    pub fn find_truncated(&self, key: TruncatedKey) -> Option<EncodingKey> {
+        let start = self.e2i.partition_point(|&(ekey, _, _)| ekey >> 56 < key.0);
+        self.e2i
+            .get(start)
+            .map(|&(ekey, _, _)| EncodingKey(ekey))
+            .filter(|&ekey| key.matches(ekey))
+    }
+
+    /// Number of content keys in the c2e table
+    pub fn ckey_count(&self) -> usize {
+        self.c2e.len()
+    }
+
+    /// Number of encoding keys in the e2i table
+    pub fn ekey_count(&self) -> usize {
+        self.e2i.len()
+    }
+
+    /// Every content key with its first encoding key and decoded size
+    pub fn ckeys(&self) -> impl Iterator<Item = (ContentKey, EncodingKey, u64)> + '_ {
+        self.c2e
+            .iter()
+            .map(|&(ckey, ekey, size)| (ContentKey(ckey), EncodingKey(ekey), size))
+    }
+
+    /// Every encoding key in the e2i table with its encoded size
+    pub fn ekeys(&self) -> impl Iterator<Item = (EncodingKey, u64)> + '_ {
+        self.e2i
+            .iter()
+            .map(|&(ekey, _espec, size)| (EncodingKey(ekey), size))
+    }
+}
+
+impl std::fmt::Display for Encoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Encoding")
+            .field("c2e_len", &self.c2e.len())
+            .finish()
+    }
+}
+
+impl std::fmt::Debug for Encoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(self, f)
+    }
+}
+
+const WHAT: &str = "encoding";
+
+// FIXME: encoding is sorted so we can mmap it in and use it as a data structure directly to avoid a time consuming parse step
+// binary search
+
+#[tracing::instrument(err, skip(data))]
+pub fn parse(data: &[u8]) -> Result<Encoding> {
+    let start = Instant::now();
+    tracing::debug!("Parsing encoding data");
+    let mut p = Cursor::new(data, WHAT);
+    check!(&p.array()? == b"EN", Error::malformed(WHAT, "bad magic"));
+    let version = p.u8()?;
+    check!(
+        version == 1,
+        Error::UnsupportedVersion {
+            what: WHAT,
+            version: version.into(),
+        }
+    );
+    check!(
+        p.u8()? == 16 && p.u8()? == 16,
+        Error::malformed(WHAT, "unsupported key size")
+    );
+    let cpagekb: usize = p.u16()?.into();
+    let epagekb: usize = p.u16()?.into();
+    let ccount = p.u32()? as usize;
+    let ecount = p.u32()? as usize;
+    check!(
+        p.u8()? == 0,
+        Error::malformed(WHAT, "unexpected nonzero byte in header")
+    );
+    let espec_size = p.u32()? as usize;
+    let especs = p
+        .bytes(espec_size)?
+        .split(|b| *b == 0)
+        .map(|s| String::from_utf8(s.to_vec()))
+        .collect::<Result<Vec<String>, _>>()
+        .map_err(|e| Error::malformed(WHAT, format!("espec: {e}")))?;
+    let pagesize = cpagekb * 1024;
+    // the index and its pages must all be there before sizing anything by them
+    check!(
+        ccount
+            .checked_mul(32 + pagesize)
+            .is_some_and(|x| x <= p.remaining()),
+        p.truncated()
+    );
+    let mut cpages = Vec::<(ContentKey, u128)>::with_capacity(ccount);
+    for _ in 0..ccount {
+        cpages.push((ContentKey(p.u128()?), p.u128()?));
+    }
+    let assumed_hash_count = (ccount * pagesize) / 32;
+    let mut c2e = Vec::with_capacity(assumed_hash_count);
+    let mut cmap_extra = HashMap::<ContentKey, EncodingKeyVec>::default();
+    for (first_key, hash) in cpages {
+        let mut page = p.sub(pagesize, "encoding content page")?;
+        #[cfg(debug_assertions)]
+        check_page(hash, page.rest(), "encoding content page")?;
+        let mut first = true;
+        // pages are zero padded, and no entry has a key count of zero
+        while page.remaining() >= 22 && page.peek(1)?[0] != 0 {
+            let key_count = page.u8()?.into();
+            let file_size = page.u40()?;
+            let ckey = ContentKey(page.u128()?);
+            #[cfg(debug_assertions)]
+            check!(
+                !first || first_key == ckey,
+                Error::malformed(WHAT, "first key mismatch in content page")
+            );
+            first = false;
+
+            if key_count > 0 {
+                c2e.push((ckey.0, page.u128()?, file_size));
+                //cmap.insert(ckey, (EncodingKey(page.get_u128()), file_size));
+                if key_count > 1 {
+                    let mut ekeys = EncodingKeyVec::with_capacity(key_count);
+                    for _ in 1..key_count {
+                        ekeys.push(EncodingKey(page.u128()?));
+                    }
+                    //tracing::error!("{ckey} Multiple ekeys {ekeys}");
+                    cmap_extra.insert(ckey, ekeys);
+                }
+            }
+        }
+    }
+    let pagesize = epagekb * 1024;
+    check!(
+        ecount
+            .checked_mul(32 + pagesize)
+            .is_some_and(|x| x <= p.remaining()),
+        p.truncated()
+    );
+    let mut epages = Vec::<(u128, u128)>::with_capacity(ecount);
+    for _ in 0..ecount {
+        epages.push((p.u128()?, p.u128()?));
+    }
+
+    let e2i = build_e2i(&epages, pagesize, &mut p)?;
+    let espec = String::from_utf8(p.rest().to_vec())
+        .map_err(|e| Error::malformed(WHAT, format!("espec: {e}")))?;
+    tracing::info!(
+        espec = espec,
+        especs_len = especs.len(),
+        e2i_len = e2i.len(),
+        c2e_len = c2e.len(),
+        duration = (Instant::now() - start).as_secs_f32(),
+    );
+    Ok(Encoding {
+        _especs: especs,
+        c2e,
+        //cmap,
+        cmap_extra,
+        e2i,
+        _espec: espec,
+    })
+}
+
+fn check_page(expected: u128, page: &[u8], what: &'static str) -> Result<()> {
+    let actual = crate::md5hash(page);
+    check!(
+        actual == expected,
+        Error::ChecksumMismatch {
+            expected,
+            actual,
+            what,
+        }
+    );
+    Ok(())
+}
+
+fn build_e2i(
+    epages: &[(u128, u128)],
+    pagesize: usize,
+    p: &mut Cursor,
+) -> Result<Vec<(u128, u32, u64)>> {
+    let mut e2i = Vec::with_capacity((epages.len() * pagesize) / 32);
+
+    for &(first_key, hash) in epages {
+        let mut page = p.sub(pagesize, "encoding ekey page")?;
+        #[cfg(debug_assertions)]
+        check_page(hash, page.rest(), "encoding ekey page")?;
+        let mut first = true;
+        // ekeys may start with a zero byte, so only an all-zero key is padding
+        while page.remaining() >= 25 && page.peek(16)? != [0; 16] {
+            let ekey = page.u128()?;
+            let index = page.u32()?;
+            let file_size = page.u40()?;
+            if first {
+                #[cfg(debug_assertions)]
+                check!(
+                    first_key == ekey,
+                    Error::malformed(WHAT, "first key mismatch in ekey page")
+                );
+                first = false;
+            }
+            e2i.push((ekey, index, file_size));
+            //emap.insert(ekey, (index, file_size));
+        }
+    }
+
+    Ok(e2i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_padding_ends_pages() -> Result<()> {
+        // the second ekey starts with a zero byte, which isn't padding yet
+        let entries = [(1, 0xaa << 120, 10, 20), (2, 0x00bb << 112, 30, 40)]
+            .map(|(c, e, size, esize)| (ContentKey(c), EncodingKey(e), size, esize));
+        let encoding = parse(&crate::testing::encoding_manifest(entries.to_vec()))?;
+
+        assert_eq!(encoding.ckey_count(), entries.len());
+        assert_eq!(encoding.ekey_count(), entries.len());
+        for (ckey, ekey, size, esize) in entries {
+            assert_eq!(encoding.c2e(ckey)?, ekey);
+            assert_eq!(encoding.content_size(ckey), Some(size));
+            assert_eq!(encoding.encoded_size(ekey), Some(esize));
+            assert_eq!(encoding.find_truncated(ekey.truncated()), Some(ekey));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,68 @@
+//! Failures callers may want to tell apart, e.g. to decide what to refetch.
+//!
+//! Parsers return [`Error`] directly. Operations that touch the network or
+//! disk return [`anyhow::Error`] with context, carrying an [`Error`] where
+//! one applies; [`Error::find`] digs it out.
+
+use derive_more::Display;
+
+#[derive(Debug, Display, derive_more::Error)]
+pub enum Error {
+    /// No source or CDN host has `key`
+    #[display("{key} not found")]
+    NotFound { key: String },
+    /// Data doesn't hash to what names or describes it
+    #[display("{what} checksum mismatch, expected {expected:032x} got {actual:032x}")]
+    ChecksumMismatch {
+        expected: u128,
+        actual: u128,
+        what: &'static str,
+    },
+    #[display("truncated {what}")]
+    Truncated { what: &'static str },
+    #[display("unsupported {what} version {version}")]
+    UnsupportedVersion { what: &'static str, version: u32 },
+    /// Well-formed so far but violating the format in some other way
+    #[display("invalid {what}: {reason}")]
+    Malformed { what: &'static str, reason: String },
+    /// A BLTE chunk is encrypted with a key we don't have
+    #[display("missing decryption key {key_name:016x}")]
+    MissingDecryptionKey { key_name: u64 },
+    /// A response with an unexpected status
+    #[display("HTTP {status} for {url}")]
+    Http { url: String, status: u16 },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    pub(crate) fn malformed(what: &'static str, reason: impl Into<String>) -> Self {
+        Self::Malformed {
+            what,
+            reason: reason.into(),
+        }
+    }
+
+    /// The [`Error`] somewhere in the chain of `e`, if any
+    pub fn find(e: &anyhow::Error) -> Option<&Self> {
+        e.chain().find_map(|x| x.downcast_ref())
+    }
+
+    /// Whether the data itself is bad, so fetching it again may help
+    pub fn is_corrupt(&self) -> bool {
+        matches!(
+            self,
+            Self::ChecksumMismatch { .. } | Self::Truncated { .. } | Self::Malformed { .. }
+        )
+    }
+}
+
+/// Returns `$err` from the enclosing function unless `$cond` holds
+macro_rules! check {
+    ($cond:expr, $err:expr $(,)?) => {
+        if !$cond {
+            return Err($err.into());
+        }
+    };
+}
+pub(crate) use check;
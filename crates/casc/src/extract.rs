@@ -0,0 +1,237 @@
+//! Writing install manifest entries out as a directory tree.
+//!
+//! Install names are backslash separated relative paths. They come from the
+//! CDN, so each one is checked to stay inside the output directory before
+//! anything is written.
+
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    CascClient, ContentKey,
+    error::{Error, check},
+    install::InstallFile,
+    md5hash,
+};
+
+#[derive(Clone, Debug)]
+pub struct ExtractOptions {
+    /// Globs a name must match one of, everything when empty
+    pub include: Vec<String>,
+    /// Globs excluding names that would otherwise be included
+    pub exclude: Vec<String>,
+    /// Directory the install paths are recreated under
+    pub output: PathBuf,
+}
+
+impl Default for ExtractOptions {
+    fn default() -> Self {
+        Self {
+            include: vec![],
+            exclude: vec![],
+            output: PathBuf::from("root"),
+        }
+    }
+}
+
+impl ExtractOptions {
+    pub fn selects(&self, name: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|x| glob_match(x, name)))
+            && !self.exclude.iter().any(|x| glob_match(x, name))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ExtractReport {
+    /// Files written, with their output paths
+    pub written: Vec<(String, PathBuf)>,
+    /// Files whose output already had the right content
+    pub up_to_date: Vec<(String, PathBuf)>,
+    pub written_bytes: u64,
+}
+
+/// Matches an install name against a glob, ignoring case.
+///
+/// `?` matches one character and `*` any run of characters within a path
+/// component, `**` also crosses separators. `/` and `\` are interchangeable.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    fn is_sep(b: u8) -> bool {
+        b == b'/' || b == b'\\'
+    }
+    let (p, n) = (pattern.as_bytes(), name.as_bytes());
+    let (mut pi, mut ni) = (0, 0);
+    // where to resume when the rest fails to match: the pattern after the
+    // last `*` and `**`, and the name position each of them has reached
+    let mut star: Option<(usize, usize)> = None;
+    let mut globstar: Option<(usize, usize)> = None;
+    while ni < n.len() {
+        match p.get(pi) {
+            Some(b'*') if p.get(pi + 1) == Some(&b'*') => {
+                globstar = Some((pi + 2, ni));
+                star = None;
+                pi += 2;
+                continue;
+            }
+            Some(b'*') => {
+                star = Some((pi + 1, ni));
+                pi += 1;
+                continue;
+            }
+            Some(b'?') if !is_sep(n[ni]) => {
+                pi += 1;
+                ni += 1;
+                continue;
+            }
+            Some(&c) if (is_sep(c) && is_sep(n[ni])) || c.eq_ignore_ascii_case(&n[ni]) => {
+                pi += 1;
+                ni += 1;
+                continue;
+            }
+            _ => {}
+        }
+        // a `*` can take one more character as long as it stays in its component,
+        // failing that a `**` can, which also resets any `*` after it
+        if let Some((sp, sn)) = star.filter(|&(_, sn)| !is_sep(n[sn])) {
+            star = Some((sp, sn + 1));
+            (pi, ni) = (sp, sn + 1);
+        } else if let Some((gp, gn)) = globstar {
+            globstar = Some((gp, gn + 1));
+            star = None;
+            (pi, ni) = (gp, gn + 1);
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|&c| c == b'*')
+}
+
+/// Where the install entry `name` goes under `output`, refusing names that
+/// are absolute or would climb out of it
+pub fn output_path(output: &Path, name: &str) -> Result<PathBuf> {
+    let mut path = output.to_owned();
+    for part in name.split(['\\', '/']) {
+        let mut components = Path::new(part).components();
+        let normal = matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        );
+        // `C:` style prefixes only parse as such on Windows, so reject them everywhere
+        check!(
+            normal && !part.contains(':'),
+            Error::malformed("install file name", format!("unsafe path {name:?}"))
+        );
+        path.push(part);
+    }
+    Ok(path)
+}
+
+/// Whether `path` exists with exactly the content `ckey` names
+fn is_up_to_date(path: &Path, ckey: ContentKey, size: u32) -> bool {
+    match std::fs::metadata(path) {
+        Ok(meta) if meta.is_file() && meta.len() == u64::from(size) => {}
+        _ => return false,
+    }
+    std::fs::read(path).is_ok_and(|data| md5hash(&data) == ckey.0)
+}
+
+impl CascClient {
+    /// Writes the install entries `options` selects under its output directory
+    #[tracing::instrument(err, skip(self))]
+    pub fn extract(&self, options: &ExtractOptions) -> Result<ExtractReport> {
+        let files = self
+            .install()
+            .files
+            .iter()
+            .filter(|x| options.selects(&x.name));
+        self.extract_files(files, &options.output)
+    }
+
+    /// Writes `files` under `output`, skipping those already there
+    pub fn extract_files<'a>(
+        &self,
+        files: impl IntoIterator<Item = &'a InstallFile>,
+        output: &Path,
+    ) -> Result<ExtractReport> {
+        let mut report = ExtractReport::default();
+        for file in files {
+            let path = output_path(output, &file.name)?;
+            if is_up_to_date(&path, file.key, file.size) {
+                tracing::debug!(path = %path.display(), "Up to date");
+                report.up_to_date.push((file.name.clone(), path));
+                continue;
+            }
+            let data = self
+                .get_by_ckey(file.key)
+                .with_context(|| format!("get_by_ckey failed for {}", file.name))?;
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            std::fs::write(&path, &data)?;
+            tracing::info!(path = %path.display(), name = file.name, "Extracted");
+            report.written_bytes += data.len() as u64;
+            report.written.push((file.name.clone(), path));
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn globs() {
+        let cases = [
+            ("*.exe", "Wow.exe", true),
+            ("*.EXE", "wow.exe", true),
+            ("*.exe", "Data\\Wow.exe", false),
+            ("**.exe", "Data\\Wow.exe", true),
+            ("Data/*", "Data\\config.txt", true),
+            ("Data/*", "Data\\sub\\config.txt", false),
+            ("Data/**", "Data\\sub\\config.txt", true),
+            ("**/config.txt", "Data\\sub\\config.txt", true),
+            ("**/*.txt", "Data\\sub\\config.bin", false),
+            ("W?w.exe", "Wow.exe", true),
+            ("Data?x", "Data\\x", false),
+            ("*a*b", "xaxbxb", true),
+            ("*", "", true),
+            ("?", "", false),
+            ("a", "ab", false),
+            ("ab", "a", false),
+        ];
+        for (pattern, name, expected) in cases {
+            assert_eq!(glob_match(pattern, name), expected, "{pattern} {name}");
+        }
+    }
+
+    #[test]
+    fn many_stars_stay_fast() {
+        let name = "a".repeat(100);
+        assert!(!glob_match(&format!("{}b", "*a".repeat(20)), &name));
+        assert!(!glob_match(&format!("{}b", "**a".repeat(20)), &name));
+        assert!(glob_match(&"*a".repeat(20), &name));
+    }
+
+    #[test]
+    fn output_paths_stay_inside() -> Result<()> {
+        let output = Path::new("out");
+        assert_eq!(
+            output_path(output, "Data\\sub/config.txt")?,
+            output.join("Data").join("sub").join("config.txt")
+        );
+        for name in [
+            "..",
+            "Data\\..\\..\\x",
+            "../x",
+            "/etc/passwd",
+            "\\Windows\\x",
+            "C:\\x",
+            "Data\\C:x",
+            "Data\\.\\x",
+            "Data\\\\x",
+            "",
+        ] {
+            assert!(output_path(output, name).is_err(), "{name:?}");
+        }
+        Ok(())
+    }
+}
@@ -1,20 +1,20 @@
-use std::io::{BufRead, Read};
-
 type EncodingKeyVec = tinyvec::TinyVec<[EncodingKey; 1]>;
 
-use anyhow::{Result, ensure};
-use bytes::Buf;
-
-use crate::EncodingKey;
+use crate::{
+    ContentKey, EncodingKey,
+    cursor::Cursor,
+    error::{Error, Result, check},
+};
 
 #[derive(Debug)]
 pub struct InstallFile {
     pub name: String,
-    pub key: crate::Key,
+    pub key: ContentKey,
+    pub size: u32,
 }
 
 #[derive(Debug)]
-pub(crate) struct Install {
+pub struct Install {
     pub root_names: Vec<String>,
     pub files: Vec<InstallFile>,
 }
@@ -35,55 +35,60 @@ struct Tag {
     mask: Vec<u8>,
 }
 
+const WHAT: &str = "install";
+
 #[tracing::instrument(err, skip(data))]
-pub(crate) fn parse(data: &[u8]) -> Result<Install> {
+pub fn parse(data: &[u8], needed_tags: &[&str]) -> Result<Install> {
     tracing::info!("Parsing install data");
-    let mut p = data;
-    ensure!(p.remaining() >= 16, "truncated encoding header");
-    ensure!(&p.get_u16().to_be_bytes() == b"IN", "not install format");
-    ensure!(p.get_u8() == 1, "unsupported install version");
-    let _unk = p.get_u8(); // unk
+    let mut p = Cursor::new(data, WHAT);
+    check!(&p.array()? == b"IN", Error::malformed(WHAT, "bad magic"));
+    let version = p.u8()?;
+    check!(
+        version == 1,
+        Error::UnsupportedVersion {
+            what: WHAT,
+            version: version.into(),
+        }
+    );
+    let _unk = p.u8()?; // unk
     //dbg!(unk);
-    let num_tags = p.get_u16();
-    let num_files = p.get_u32();
-    let num_mask_bytes = num_files.div_ceil(8);
+    let num_tags = p.u16()?;
+    let num_files = p.u32()?;
+    let num_mask_bytes = num_files.div_ceil(8) as usize;
+    // every tag has a mask and every file at least a key and size
+    check!(
+        (usize::from(num_tags) * (num_mask_bytes + 3) + num_files as usize * 21) <= p.remaining(),
+        p.truncated()
+    );
 
     let mut root_names = vec![];
     let mut files = vec![];
 
     let mut tags = vec![];
-    let mut mask_bytes_buf = vec![0u8; num_mask_bytes as usize];
     for _i in 0..num_tags {
-        let mut name_vec = vec![];
-        let _name_len = p.read_until(b'\0', &mut name_vec)?;
-        name_vec.pop();
-        let tag_name = String::from_utf8_lossy(&name_vec).into_owned();
-        let ty = p.get_u16();
-        p.read_exact(&mut mask_bytes_buf)?;
+        let tag_name = String::from_utf8_lossy(p.cstr()?).into_owned();
+        let ty = p.u16()?;
+        let mask = p.bytes(num_mask_bytes)?.to_vec();
 
         // dbg!(_tag_name, _ty);
         tags.push(Tag {
             name: tag_name,
             ty,
-            mask: mask_bytes_buf.clone(),
+            mask,
         })
     }
 
     let needed_tags = tags
         .iter()
-        .filter(|t| t.name == "Windows" || t.name == "x86_64" || t.name == "US")
+        .filter(|t| needed_tags.contains(&t.name.as_str()))
         .cloned()
         .collect::<Vec<_>>();
 
-    // FIXME: preserve tag info in Install and make tag selection generic
-    // instead of hardcoding it here
+    // FIXME: preserve tag info in Install
     for i in 0..num_files {
-        let mut name_vec = vec![];
-        let _name_len = p.read_until(b'\0', &mut name_vec)?;
-        name_vec.pop();
-        let file_name = String::from_utf8_lossy(&name_vec).into_owned();
-        let md5 = crate::Key(p.get_u128());
-        let size = p.get_u32();
+        let file_name = String::from_utf8_lossy(p.cstr()?).into_owned();
+        let md5 = ContentKey(p.u128()?);
+        let size = p.u32()?;
         if !file_name.contains('\\') {
             root_names.push(file_name.clone());
         }
@@ -111,6 +116,7 @@ pub(crate) fn parse(data: &[u8]) -> Result<Install> {
             files.push(InstallFile {
                 name: file_name,
                 key: md5,
+                size,
             });
         }
     }
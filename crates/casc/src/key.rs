@@ -0,0 +1,243 @@
+//! The MD5 sized keys naming content, encoded blobs and archives.
+//!
+//! Each kind of key is its own type so a ckey can't be passed where an ekey
+//! is expected. The few places where one hash legitimately names two kinds,
+//! such as unencoded files, convert with [`Md5Key::cast`].
+
+use std::{fmt, hash::Hash, str::FromStr};
+
+use derive_more::Display;
+
+/// Why a key couldn't be parsed from hex
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct KeyParseError {
+    input: String,
+    expected_len: usize,
+}
+
+impl fmt::Display for KeyParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid key {:?}, expected {} hex digits",
+            self.input, self.expected_len
+        )
+    }
+}
+
+impl std::error::Error for KeyParseError {}
+
+/// Parses exactly `N` bytes of hex
+fn parse_hex<const N: usize>(s: &str) -> Result<[u8; N], KeyParseError> {
+    let mut bytes = [0; N];
+    hex::decode_to_slice(s, &mut bytes).map_err(|_| KeyParseError {
+        input: s.to_owned(),
+        expected_len: N * 2,
+    })?;
+    Ok(bytes)
+}
+
+/// A 16 byte key, shown and parsed as 32 lowercase hex digits
+pub trait Md5Key: Copy + Eq + Hash + fmt::Display + FromStr<Err = KeyParseError> {
+    fn from_u128(value: u128) -> Self;
+
+    fn to_u128(self) -> u128;
+
+    fn from_bytes(bytes: [u8; 16]) -> Self {
+        Self::from_u128(u128::from_be_bytes(bytes))
+    }
+
+    fn to_bytes(self) -> [u8; 16] {
+        self.to_u128().to_be_bytes()
+    }
+
+    /// The key of `data`, its MD5
+    fn of(data: &[u8]) -> Self {
+        Self::from_u128(crate::md5hash(data))
+    }
+
+    /// Reinterprets the same hash as another kind of key
+    fn cast<K: Md5Key>(self) -> K {
+        K::from_u128(self.to_u128())
+    }
+}
+
+macro_rules! md5_key {
+    ($(#[$meta:meta])* $name:ident) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, Debug, Default, Display, Eq, Hash, Ord, PartialEq, PartialOrd)]
+        #[display("{:032x}", _0)]
+        #[repr(transparent)]
+        pub struct $name(pub u128);
+
+        impl Md5Key for $name {
+            fn from_u128(value: u128) -> Self {
+                Self(value)
+            }
+
+            fn to_u128(self) -> u128 {
+                self.0
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = KeyParseError;
+
+            fn from_str(s: &str) -> Result<Self, KeyParseError> {
+                parse_hex(s).map(Self::from_bytes)
+            }
+        }
+
+        #[cfg(feature = "serde")]
+        impl serde::Serialize for $name {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+
+        #[cfg(feature = "serde")]
+        impl<'de> serde::Deserialize<'de> for $name {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let s = <std::borrow::Cow<'de, str>>::deserialize(deserializer)?;
+                s.parse().map_err(serde::de::Error::custom)
+            }
+        }
+    };
+}
+
+md5_key!(
+    /// Names an archive on the CDN, and its `.index` file
+    ArchiveKey
+);
+md5_key!(
+    /// MD5 of a file's decoded content
+    ContentKey
+);
+md5_key!(
+    /// MD5 of a BLTE blob's header, naming the encoded file
+    EncodingKey
+);
+
+impl EncodingKey {
+    /// The first nine bytes, as local storage indexes it
+    pub fn truncated(self) -> TruncatedKey {
+        TruncatedKey(self.0 >> 56)
+    }
+}
+
+/// The first nine bytes of an ekey, as used by local `.idx` files
+#[derive(Clone, Copy, Debug, Default, Display, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[display("{:018x}", _0)]
+pub struct TruncatedKey(pub u128);
+
+impl TruncatedKey {
+    pub fn from_bytes(bytes: [u8; 9]) -> Self {
+        let mut full = [0; 16];
+        full[7..].copy_from_slice(&bytes);
+        Self(u128::from_be_bytes(full))
+    }
+
+    /// Whether `ekey` starts with these nine bytes
+    pub fn matches(self, ekey: EncodingKey) -> bool {
+        ekey.truncated() == self
+    }
+}
+
+impl FromStr for TruncatedKey {
+    type Err = KeyParseError;
+
+    fn from_str(s: &str) -> Result<Self, KeyParseError> {
+        parse_hex(s).map(Self::from_bytes)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for TruncatedKey {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for TruncatedKey {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = <std::borrow::Cow<'de, str>>::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Numeric file ID used by WoW's root manifest
+#[derive(Clone, Copy, Debug, Display, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+pub struct FileDataID(pub u32);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEX: &str = "00112233445566778899aabbccddeeff";
+
+    #[test]
+    fn parses_and_shows_hex() {
+        let key = HEX.parse::<EncodingKey>().unwrap();
+        assert_eq!(key, EncodingKey(0x00112233445566778899aabbccddeeff));
+        assert_eq!(key.to_string(), HEX);
+        assert_eq!(ContentKey(1).to_string(), format!("{:0>32}", 1));
+        assert_eq!(key.cast::<ArchiveKey>().to_u128(), key.0);
+    }
+
+    #[test]
+    fn rejects_bad_hex() {
+        for input in [&HEX[..30], &format!("{HEX}00"), "", &HEX.replace('f', "g")] {
+            let e = input.parse::<ContentKey>().unwrap_err();
+            assert_eq!(
+                e,
+                KeyParseError {
+                    input: input.to_owned(),
+                    expected_len: 32,
+                }
+            );
+        }
+        let e = "0011".parse::<TruncatedKey>().unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "invalid key \"0011\", expected 18 hex digits"
+        );
+    }
+
+    #[test]
+    fn truncates_to_nine_bytes() {
+        let ekey = HEX.parse::<EncodingKey>().unwrap();
+        let truncated = ekey.truncated();
+        assert_eq!(truncated.to_string(), &HEX[..18]);
+        assert_eq!(truncated, HEX[..18].parse().unwrap());
+        assert_eq!(
+            truncated,
+            TruncatedKey::from_bytes([0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88])
+        );
+        assert!(truncated.matches(ekey));
+        assert!(truncated.matches(EncodingKey(ekey.0 ^ 0xff)));
+        assert!(!truncated.matches(EncodingKey(ekey.0 ^ 1 << 56)));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trips() {
+        let ekey = HEX.parse::<EncodingKey>().unwrap();
+        let json = serde_json::to_string(&ekey).unwrap();
+        assert_eq!(json, format!("\"{HEX}\""));
+        assert_eq!(serde_json::from_str::<EncodingKey>(&json).unwrap(), ekey);
+
+        let truncated = ekey.truncated();
+        let json = serde_json::to_string(&truncated).unwrap();
+        assert_eq!(json, format!("\"{}\"", &HEX[..18]));
+        assert_eq!(
+            serde_json::from_str::<TruncatedKey>(&json).unwrap(),
+            truncated
+        );
+
+        assert_eq!(serde_json::to_string(&FileDataID(42)).unwrap(), "42");
+        assert!(serde_json::from_str::<ContentKey>("\"not hex\"").is_err());
+    }
+}
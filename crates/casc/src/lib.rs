@@ -0,0 +1,810 @@
+//! Client for Blizzard's TACT/CASC content distribution.
+//!
+//! [`CascClient`] resolves a product's current build through the TACT
+//! endpoints and its CDNs, and fetches files by content or encoding key.
+//! The manifest parsers live in their own modules and work on plain bytes.
+
+pub use error::Error;
+
+pub fn md5hash(p: &[u8]) -> u128 {
+    use md5::{Digest, Md5, digest::FixedOutput};
+    let mut hasher = Md5::new();
+    hasher.update(p);
+    u128::from_be_bytes(hasher.finalize_fixed().into())
+}
+
+pub use key::{
+    ArchiveKey, ContentKey, EncodingKey, FileDataID, KeyParseError, Md5Key, TruncatedKey,
+};
+
+pub mod audit;
+pub mod bgdl;
+pub mod blte;
+pub mod cache;
+pub mod cdn;
+pub mod config;
+mod cursor;
+pub mod diff;
+pub mod download;
+pub mod extract;
+pub mod install;
+pub mod key;
+pub mod local;
+pub mod mirror;
+#[cfg(feature = "tokio")]
+pub mod nonblocking;
+pub mod product;
+pub mod ribbit;
+pub mod root;
+pub mod serve;
+pub mod source;
+#[cfg(any(test, feature = "test-support"))]
+pub mod testing;
+pub mod transport;
+pub mod tvfs;
+
+pub static APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"),);
+
+/// A parsed pipe separated `versions`, `cdns` or `bgdl` response
+pub struct PipeSeparatedVars {
+    storage: String,
+    headings: Vec<Range<usize>>,
+    entries: Vec<Vec<Range<usize>>>,
+    meta: String,
+}
+
+impl PipeSeparatedVars {
+    pub fn headings(&self) -> impl Iterator<Item = &str> {
+        self.headings.iter().map(|x| &self.storage[x.to_owned()])
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = impl Iterator<Item = &str>> {
+        self.entries
+            .iter()
+            .map(|x| x.iter().map(|y| &self.storage[y.to_owned()]))
+    }
+
+    /// Index of the column named `name`, ignoring the `!TYPE:size` suffix
+    pub fn column(&self, name: &str) -> Option<usize> {
+        self.headings()
+            .position(|x| x.split('!').next() == Some(name))
+    }
+}
+
+impl Debug for PipeSeparatedVars {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PipeSeparatedVars")
+            //.field("storage", &self.storage)
+            .field("meta", &self.meta)
+            .field("headings", &self.headings().collect::<Vec<_>>())
+            .field(
+                "entries",
+                &self
+                    .entries()
+                    .map(|x| x.collect::<Vec<_>>().join(" | "))
+                    .collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+fn format_hex_key(hex: &str) -> String {
+    format!("{}/{}/{hex}", &hex[0..2], &hex[2..4])
+}
+
+fn trimmed_index(backing: &str, needle: &str) -> Range<usize> {
+    let needle = needle.trim();
+    let start = unsafe { needle.as_ptr().byte_offset_from(backing.as_ptr()) } as usize;
+    assert!(start + needle.len() <= backing.len());
+    start..start + needle.len()
+}
+
+pub fn load_pipe_separated_vars(backing: String) -> PipeSeparatedVars {
+    let mut lines = backing.lines();
+    let header = lines.next().unwrap_or(&backing[..0]);
+    let headings: Vec<_> = header
+        .split('|')
+        .map(|x| trimmed_index(&backing, x))
+        .collect();
+    let mut meta = "".to_owned();
+    let mut entries = vec![];
+
+    for line in lines {
+        let parts: Vec<_> = line
+            .split('|')
+            .map(|x| trimmed_index(&backing, x))
+            .collect();
+
+        if parts.len() != headings.len() || line.starts_with("##") {
+            meta += line;
+            meta += "\n";
+        } else {
+            entries.push(parts)
+        }
+    }
+
+    PipeSeparatedVars {
+        storage: backing,
+        meta,
+        headings,
+        entries,
+    }
+}
+
+/// A `ckey ekey` pair as found in build configs
+pub struct FileKeys {
+    pub ckey: ContentKey,
+    pub ekey: EncodingKey,
+}
+
+impl FromStr for FileKeys {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut split = s.split(' ');
+        let ckey = split.next().context("no ckey")?.parse()?;
+        let ekey = split.next().context("no ekey")?.parse()?;
+
+        Ok(Self { ckey, ekey })
+    }
+}
+
+impl Debug for FileKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{{c {} e {}}}", self.ckey, self.ekey)
+    }
+}
+
+pub struct CascClient {
+    product: product::ProductProfile,
+    cdn: Arc<CdnPool>,
+    /// Where blobs are read from, the CDN through `cache` unless replaced
+    source: Arc<dyn BlobSource>,
+    encoding: encoding::Encoding,
+    install: install::Install,
+    archives: Vec<ArchiveKey>,
+    cache: CacheByKey,
+    version: VersionEntry,
+    build_config: config::Config,
+    cdn_config: config::Config,
+}
+
+impl std::fmt::Debug for CascClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CascClient")
+            .field("product", &self.product.code)
+            .field("cdn", &self.cdn.primary())
+            .field("source", &self.source)
+            //.field("encoding", &self.encoding)
+            //.field("install", &self.install)
+            .field("cache", &self.cache)
+            .finish()
+    }
+}
+
+impl CascClient {
+    /// Starts building a client for the current build of `product`
+    pub fn builder(product: impl Into<String>) -> CascClientBuilder {
+        CascClientBuilder::new(product)
+    }
+
+    pub fn product(&self) -> &product::ProductProfile {
+        &self.product
+    }
+
+    pub fn encoding(&self) -> &encoding::Encoding {
+        &self.encoding
+    }
+
+    pub fn install(&self) -> &install::Install {
+        &self.install
+    }
+
+    /// The `versions` row this build was loaded from
+    pub fn version(&self) -> &VersionEntry {
+        &self.version
+    }
+
+    pub fn build_config(&self) -> &config::Config {
+        &self.build_config
+    }
+
+    pub fn cdn_config(&self) -> &config::Config {
+        &self.cdn_config
+    }
+
+    /// Fetches and parses the root manifest named in the build config
+    #[tracing::instrument(err)]
+    pub fn root(&self) -> Result<root::Root> {
+        ensure!(
+            self.product.root_format == product::RootFormat::Wow,
+            "{:?} root manifests are not supported",
+            self.product.root_format
+        );
+        let ckey = self
+            .build_config
+            .get("root")
+            .context("Missing root")?
+            .parse()?;
+        Ok(root::parse(&self.get_by_ckey(ckey)?)?)
+    }
+
+    pub fn cdn(&self) -> &CdnPool {
+        &self.cdn
+    }
+
+    pub fn cache(&self) -> &CacheByKey {
+        &self.cache
+    }
+
+    pub fn source(&self) -> Arc<dyn BlobSource> {
+        self.source.clone()
+    }
+
+    /// Reads the file `ckey` names, checking the decoded content hashes to it
+    pub fn get_by_ckey(&self, ckey: ContentKey) -> Result<Vec<u8>> {
+        let ekey = self.encoding.c2e(ckey)?;
+        self.get_verified(ckey, ekey)
+    }
+
+    /// [`Self::get_by_ckey`] without the content check
+    pub fn get_by_ckey_unverified(&self, ckey: ContentKey) -> Result<Vec<u8>> {
+        let ekey = self.encoding.c2e(ckey)?;
+        self.get_by_ekey(ekey)
+    }
+
+    pub fn get_by_keys(&self, k: FileKeys) -> Result<Vec<u8>> {
+        self.check_keys(&k)?;
+        self.get_verified(k.ckey, k.ekey)
+    }
+
+    /// [`Self::get_by_keys`] without the content check
+    pub fn get_by_keys_unverified(&self, k: FileKeys) -> Result<Vec<u8>> {
+        self.check_keys(&k)?;
+        self.get_by_ekey(k.ekey)
+    }
+
+    fn check_keys(&self, k: &FileKeys) -> Result<()> {
+        let ckey = k.ckey;
+        let ekey_verify = self.encoding.c2e(ckey).ok();
+        if let Some(ekey_verify) = ekey_verify {
+            ensure!(ekey_verify == k.ekey);
+        }
+        Ok(())
+    }
+
+    /// Decodes `ekey` and checks it against `ckey`, evicting and refetching
+    /// a blob that doesn't match
+    fn get_verified(&self, ckey: ContentKey, ekey: EncodingKey) -> Result<Vec<u8>> {
+        self.source.get_ekey_checked(ekey, &|blob| {
+            let data = blte::parse(ekey, blob)?;
+            let actual = md5hash(&data);
+            check!(
+                actual == ckey.0,
+                Error::ChecksumMismatch {
+                    expected: ckey.0,
+                    actual,
+                    what: "decoded content",
+                }
+            );
+            Ok(data)
+        })
+    }
+
+    /// Reads blobs from `source` instead, e.g. a local install or a fixture
+    pub fn with_source(mut self, source: Arc<dyn BlobSource>) -> Self {
+        self.source = source;
+        self
+    }
+
+    pub fn get_by_ekey(&self, ekey: EncodingKey) -> Result<Vec<u8>> {
+        let bytes = self.source.get_ekey(ekey)?;
+        let blted = blte::parse(ekey, &bytes)?;
+
+        Ok(blted)
+    }
+
+    /// Fetches and merges the `.index` files of every archive in the CDN config
+    #[tracing::instrument(err)]
+    pub fn archive_index(&self) -> Result<Index> {
+        load_archive_index(&self.cdn, &self.cache, &self.archives)
+    }
+
+    #[tracing::instrument(err)]
+    pub fn get_client_binaries(&self, options: &download::DownloadOptions) -> Result<()> {
+        let ekeys = self
+            .install
+            .files
+            .iter()
+            .filter(|x| self.product.is_client_binary(&x.name))
+            .filter_map(|x| self.encoding.c2e(x.key).ok())
+            .collect::<Vec<_>>();
+        let index = self.archive_index()?;
+        let report = download::download(&self.cdn, &self.cache, &index, ekeys, options)?;
+        tracing::debug!("{report:?}");
+
+        let binaries = self
+            .install
+            .files
+            .iter()
+            .filter(|x| self.product.is_client_binary(&x.name));
+        self.extract_files(binaries, Path::new("root"))?;
+        Ok(())
+    }
+}
+
+/// Fetches a TACT endpoint such as `versions`, `cdns` or `bgdl` for a product
+///
+/// Responses are stored in `cache`, and read back from it instead when offline.
+#[tracing::instrument(err, skip(transport, cache))]
+pub fn tact_psv(
+    transport: &dyn Transport,
+    cache: &CacheByKey,
+    product: &str,
+    region: &str,
+    endpoint: &str,
+) -> Result<PipeSeparatedVars> {
+    if cache.is_offline() {
+        return Ok(load_pipe_separated_vars(
+            cache.load_tact(product, region, endpoint)?,
+        ));
+    }
+    let url = tact_url(product, region, endpoint);
+    let response = transport.get(&url, None)?;
+    ensure!(response.is_success(), "HTTP {} for {url}", response.status);
+    let text = String::from_utf8(response.bytes()?.to_vec())
+        .with_context(|| format!("{endpoint} response is not utf-8"))?;
+    cache.store_tact(product, region, endpoint, &text)?;
+    Ok(load_pipe_separated_vars(text))
+}
+
+/// Fetches and merges the `.index` files of `archives`
+pub(crate) fn load_archive_index(
+    cdn: &CdnPool,
+    cache: &CacheByKey,
+    archives: &[ArchiveKey],
+) -> Result<Index> {
+    let mut map = HashMap::new();
+    for archive in archives {
+        let key = format!("{archive}.index");
+        let data = cache.get(cdn, "data", &key)?;
+        map.extend(parse_index(*archive, &data)?.map);
+    }
+    Ok(Index { map })
+}
+
+/// The URL of a TACT endpoint on Blizzard's patch servers
+pub(crate) fn tact_url(product: &str, region: &str, endpoint: &str) -> String {
+    format!("http://{region}.patch.battle.net:1119/{product}/{endpoint}")
+}
+
+/// A row of a `versions` or `bgdl` response
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VersionEntry {
+    pub region: String,
+    pub build_config: String,
+    pub cdn_config: String,
+    pub build_id: Option<u32>,
+    pub versions_name: String,
+}
+
+pub fn version_entries(versions: &PipeSeparatedVars) -> Result<Vec<VersionEntry>> {
+    let region = versions.column("Region").unwrap_or(0);
+    let build_config = versions.column("BuildConfig").unwrap_or(1);
+    let cdn_config = versions.column("CDNConfig").unwrap_or(2);
+    let build_id = versions.column("BuildId");
+    let versions_name = versions.column("VersionsName");
+    ensure!(
+        region.max(build_config).max(cdn_config) < versions.headings().count(),
+        "versions response is missing columns"
+    );
+
+    versions
+        .entries()
+        .map(|x| x.collect::<Vec<_>>())
+        .map(|x| {
+            Ok(VersionEntry {
+                region: x[region].to_owned(),
+                build_config: x[build_config].to_owned(),
+                cdn_config: x[cdn_config].to_owned(),
+                build_id: build_id
+                    .map(|i| x[i].parse())
+                    .transpose()
+                    .context("invalid BuildId")?,
+                versions_name: versions_name.map(|i| x[i].to_owned()).unwrap_or_default(),
+            })
+        })
+        .collect()
+}
+
+/// Picks `region`'s build named by `version`, the current one without it.
+///
+/// `version` is matched against `VersionsName`, `BuildId` and `BuildConfig`.
+/// Builds no longer listed are named by their config keys as
+/// `<build config>[:<cdn config>]`, using the current CDN config if omitted.
+pub fn find_version(
+    entries: Vec<VersionEntry>,
+    region: &str,
+    version: Option<&str>,
+) -> Result<VersionEntry> {
+    let mut entries = entries.into_iter().filter(|x| x.region == region);
+    let current = entries
+        .clone()
+        .next()
+        .with_context(|| format!("no build listed for region {region}"))?;
+    let Some(version) = version else {
+        return Ok(current);
+    };
+    if let Some(entry) = entries.find(|x| {
+        x.versions_name == version
+            || x.build_config == version
+            || x.build_id.is_some_and(|id| id.to_string() == version)
+    }) {
+        return Ok(entry);
+    }
+    let (build_config, cdn_config) = match version.split_once(':') {
+        Some((build, cdn)) => (build, cdn.parse::<ContentKey>()?.to_string()),
+        None => (version, current.cdn_config.clone()),
+    };
+    let build_config = build_config
+        .parse::<ContentKey>()
+        .with_context(|| format!("no build {version} listed for region {region}"))?
+        .to_string();
+    Ok(VersionEntry {
+        region: region.to_owned(),
+        build_config,
+        cdn_config,
+        build_id: None,
+        versions_name: version.to_owned(),
+    })
+}
+
+#[tracing::instrument(err, skip(transport, cache))]
+pub fn cdn_casc_client(
+    transport: Arc<dyn Transport>,
+    cache: CacheByKey,
+    product: &str,
+    region: &str,
+    version: Option<&str>,
+    mirrors: &[Mirror],
+) -> Result<CascClient> {
+    let product = product::ProductProfile::for_code(product);
+    let cdns = tact_psv(&*transport, &cache, &product.code, region, "cdns")?;
+    let versions = tact_psv(&*transport, &cache, &product.code, region, "versions")?;
+
+    let version_entry = find_version(version_entries(&versions)?, region, version)?;
+
+    tracing::debug!("{cdns:#?} {versions:#?}");
+
+    let cdn = CdnPool::from_cdns(transport, &cdns, region, mirrors)?;
+
+    tracing::info!(cdn = cdn.primary(), "Picked CDN");
+
+    casc_client_for_build(product, cdn, cache, &version_entry)
+}
+
+/// Loads the configs, encoding and install manifest of the build `version` names.
+///
+/// The client's archive indexes are loaded on the first blob that isn't
+/// available as a loose file, see [`CdnSource::with_archives`].
+#[tracing::instrument(err, skip(product, cache))]
+pub fn casc_client_for_build(
+    product: product::ProductProfile,
+    cdn: CdnPool,
+    cache: CacheByKey,
+    version: &VersionEntry,
+) -> Result<CascClient> {
+    let (build_cfg_key, cdn_cfg_key) = (&version.build_config, &version.cdn_config);
+
+    let cdn_cfg = cache.get(&cdn, "config", cdn_cfg_key)?;
+    let cdn_cfg = String::from_utf8(cdn_cfg).context("CDN config is not utf-8")?;
+    let build_cfg = cache.get(&cdn, "config", build_cfg_key)?;
+    let build_cfg = String::from_utf8(build_cfg).context("build config is not utf-8")?;
+
+    let build_cfg_mini = &build_cfg[0..build_cfg.find("vfs-").unwrap_or(build_cfg.len())];
+
+    tracing::debug!("{cdn_cfg} {build_cfg_mini}");
+
+    let cdn_config = config::Config::parse(&cdn_cfg)?;
+    let build_config = config::Config::parse(&build_cfg)?;
+    let keys = BuildKeys::new(&cdn_config, &build_config)?;
+    cache.add_archives(keys.archives.iter().copied());
+    tracing::info!("Encoding keys: {:?}", keys.encoding);
+
+    let cdn = Arc::new(cdn);
+    let source: Arc<dyn BlobSource> =
+        Arc::new(CdnSource::new(cdn.clone(), cache.clone()).with_archives(keys.archives.clone()));
+    let encoding_data = source.get_ekey(keys.encoding.ekey)?;
+
+    let encoding_decompressed = blte::parse(keys.encoding.ekey, &encoding_data)?;
+
+    let encoding_parsed: encoding::Encoding = encoding::parse(&encoding_decompressed)?;
+    tracing::info!("Parsed encoding. {}", encoding_parsed);
+
+    tracing::info!("Install keys: {:?}", keys.install);
+    let install_data = source.get_ekey(keys.install.ekey)?;
+    let install = keys.parse_install(&product, &version.region, &encoding_parsed, &install_data)?;
+    let archives = keys.archives;
+
+    Ok(CascClient {
+        product,
+        encoding: encoding_parsed,
+        install,
+        archives,
+        cache,
+        cdn,
+        source,
+        version: version.clone(),
+        build_config,
+        cdn_config,
+    })
+}
+
+/// What a build's configs name, shared by the blocking and async clients
+pub(crate) struct BuildKeys {
+    pub archives: Vec<ArchiveKey>,
+    pub encoding: FileKeys,
+    pub install: FileKeys,
+}
+
+impl BuildKeys {
+    pub(crate) fn new(cdn_config: &config::Config, build_config: &config::Config) -> Result<Self> {
+        let archives = cdn_config
+            .words("archives")
+            .map(ArchiveKey::from_str)
+            .collect::<Result<Vec<_>, _>>()?;
+        let keys = |name| -> Result<FileKeys> {
+            build_config
+                .get(name)
+                .with_context(|| format!("Missing {name}"))?
+                .parse()
+        };
+        Ok(Self {
+            archives,
+            encoding: keys("encoding")?,
+            install: keys("install")?,
+        })
+    }
+
+    /// Decodes the install manifest `blob`, keeping the entries `product` installs in `region`
+    pub(crate) fn parse_install(
+        &self,
+        product: &product::ProductProfile,
+        region: &str,
+        encoding: &encoding::Encoding,
+        blob: &[u8],
+    ) -> Result<install::Install> {
+        if let Ok(ekey) = encoding.c2e(self.install.ckey) {
+            tracing::info!("Verifying encoding and install ekey agree");
+            ensure!(
+                ekey == self.install.ekey,
+                "encoding and install ekeys disagree"
+            );
+        }
+        let data = blte::parse(self.install.ekey, blob)?;
+        let tags = product.install_tags(region);
+        let tags = tags.iter().map(String::as_str).collect::<Vec<_>>();
+        Ok(install::parse(&data, &tags)?)
+    }
+}
+
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    fmt::Debug,
+    io::Read,
+    ops::Range,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+use anyhow::{Context, Result, bail, ensure};
+use bytes::Buf;
+use cache::CacheByKey;
+use cdn::{CdnPool, Mirror};
+use error::check;
+use source::{BlobSource, CdnSource};
+use transport::Transport;
+
+/// Builds a [`CascClient`] for the current build of a product
+#[derive(Debug)]
+pub struct CascClientBuilder {
+    product: String,
+    region: String,
+    cache: CacheByKey,
+    transport: Option<Arc<dyn Transport>>,
+    mirrors: Vec<Mirror>,
+    source: Option<Arc<dyn BlobSource>>,
+    version: Option<String>,
+}
+
+impl CascClientBuilder {
+    pub fn new(product: impl Into<String>) -> Self {
+        Self {
+            product: product.into(),
+            region: "us".to_owned(),
+            cache: CacheByKey::new("cache"),
+            transport: None,
+            mirrors: vec![],
+            source: None,
+            version: None,
+        }
+    }
+
+    pub fn region(mut self, region: impl Into<String>) -> Self {
+        self.region = region.into();
+        self
+    }
+
+    pub fn cache(mut self, cache: CacheByKey) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Defaults to an [`transport::HttpTransport`], or no network at all when the cache is offline
+    pub fn transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.transport = Some(transport);
+        self
+    }
+
+    pub fn mirror(mut self, mirror: Mirror) -> Self {
+        self.mirrors.push(mirror);
+        self
+    }
+
+    /// Loads the build `version` names instead of the current one, see [`find_version`]
+    pub fn version(mut self, version: impl Into<String>) -> Self {
+        self.version = Some(version.into());
+        self
+    }
+
+    /// Reads blobs from `source` once the manifests are loaded
+    pub fn source(mut self, source: Arc<dyn BlobSource>) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    pub fn build(self) -> Result<CascClient> {
+        let transport = match self.transport {
+            Some(transport) => transport,
+            None if self.cache.is_offline() => Arc::new(transport::OfflineTransport),
+            None => Arc::new(transport::HttpTransport::new(Default::default())?),
+        };
+        let client = cdn_casc_client(
+            transport,
+            self.cache,
+            &self.product,
+            &self.region,
+            self.version.as_deref(),
+            &self.mirrors,
+        )?;
+        Ok(match self.source {
+            Some(source) => client.with_source(source),
+            None => client,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Index {
+    pub map: HashMap<EncodingKey, (ArchiveKey, usize, usize)>,
+}
+
+pub mod encoding;
+pub mod error;
+
+pub fn parse_index(name: ArchiveKey, data: &[u8]) -> error::Result<Index> {
+    const WHAT: &str = "archive index";
+    check!(data.len() >= 28, Error::Truncated { what: WHAT });
+    let non_footer_size = data.len() - 28;
+    let bytes_per_block = 4096 + 24;
+    let num_blocks = non_footer_size / bytes_per_block;
+    check!(
+        non_footer_size.is_multiple_of(bytes_per_block),
+        Error::malformed(WHAT, "size is not a whole number of blocks")
+    );
+    let mut footer = cursor::Cursor::new(&data[non_footer_size..], "archive index footer");
+    //ensure!(md5hash(footer) == name.0, "bad footer name");
+    let toc_size = num_blocks * 24;
+    let toc = &data[non_footer_size - toc_size..non_footer_size];
+    let toc_checksum = footer.u64()?;
+    check!(
+        (md5hash(toc) >> 64) as u64 == toc_checksum,
+        Error::ChecksumMismatch {
+            expected: toc_checksum.into(),
+            actual: md5hash(toc) >> 64,
+            what: "archive index toc",
+        }
+    );
+    let version = footer.u8()?;
+    check!(
+        version == 1,
+        Error::UnsupportedVersion {
+            what: WHAT,
+            version: version.into(),
+        }
+    );
+    check!(
+        footer.u8()? == 0 && footer.u8()? == 0,
+        Error::malformed(WHAT, "unexpected nonzero byte")
+    );
+    check!(
+        footer.u8()? == 4,
+        Error::malformed(WHAT, "unexpected block size")
+    );
+    check!(
+        footer.u8()? == 4,
+        Error::malformed(WHAT, "unexpected offset bytes")
+    );
+    check!(
+        footer.u8()? == 4,
+        Error::malformed(WHAT, "unexpected size bytes")
+    );
+    check!(
+        footer.u8()? == 16,
+        Error::malformed(WHAT, "unexpected key size")
+    );
+    check!(
+        footer.u8()? == 8,
+        Error::malformed(WHAT, "unexpected checksum size")
+    );
+    let num_elements = footer.u32_le()? as usize;
+    let footer_checksum = footer.u64()?;
+    check!(footer.is_empty(), Error::malformed(WHAT, "footer too long"));
+    {
+        let mut footer_to_check = data[non_footer_size + 8..non_footer_size + 20].to_vec();
+        footer_to_check.resize(20, 0);
+        let actual = md5hash(&footer_to_check) >> 64;
+        check!(
+            actual as u64 == footer_checksum,
+            Error::ChecksumMismatch {
+                expected: footer_checksum.into(),
+                actual,
+                what: "archive index footer",
+            }
+        );
+    };
+    let mut map = HashMap::<EncodingKey, (ArchiveKey, usize, usize)>::new();
+    let mut p = cursor::Cursor::new(&data[..non_footer_size - toc_size], WHAT);
+    let mut entries = cursor::Cursor::new(&toc[..(16 * num_blocks)], "archive index toc");
+    let mut blockhashes = cursor::Cursor::new(&toc[(16 * num_blocks)..], "archive index toc");
+    for _ in 0..num_blocks {
+        let mut block = p.sub(4096, "archive index block")?;
+        let block_checksum = blockhashes.u64()?;
+        let actual = md5hash(block.rest()) >> 64;
+        check!(
+            actual as u64 == block_checksum,
+            Error::ChecksumMismatch {
+                expected: block_checksum.into(),
+                actual,
+                what: "archive index block",
+            }
+        );
+        let last_ekey = EncodingKey(entries.u128()?);
+        let mut found = false;
+        while block.remaining() >= 24 {
+            let ekey = EncodingKey(block.u128()?);
+            let size = block.u32()? as usize;
+            let offset = block.u32()? as usize;
+            check!(
+                map.insert(ekey, (name, size, offset)).is_none(),
+                Error::malformed(WHAT, format!("duplicate key {ekey}"))
+            );
+            if ekey == last_ekey {
+                found = true;
+                break;
+            }
+        }
+        check!(found, Error::malformed(WHAT, "last ekey mismatch"));
+    }
+    check!(
+        p.is_empty() && entries.is_empty() && blockhashes.is_empty(),
+        Error::malformed(WHAT, "trailing data")
+    );
+    check!(
+        map.len() == num_elements,
+        Error::malformed(WHAT, "wrong element count")
+    );
+    Ok(Index { map })
+}
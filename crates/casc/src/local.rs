@@ -0,0 +1,156 @@
+//! Read-only access to the `Data/data` storage of a local game install.
+//!
+//! Blobs live in `data.NNN` archives, located through sixteen bucketed
+//! `.idx` files keyed by the first nine bytes of the ekey. Every blob is
+//! preceded by a 30 byte local header that is skipped here.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, ensure};
+
+use crate::{
+    EncodingKey, TruncatedKey,
+    cursor::Cursor,
+    error::{self, Error, check},
+    source::BlobSource,
+};
+
+const LOCAL_HEADER_SIZE: usize = 30;
+
+const WHAT: &str = "local idx";
+
+/// Where a blob lives in the `data.NNN` archives
+#[derive(Clone, Copy, Debug)]
+pub struct LocalEntry {
+    pub archive: u32,
+    /// Offset of the local header preceding the blob
+    pub offset: u64,
+    /// Size including the local header
+    pub size: u32,
+}
+
+#[derive(Debug)]
+pub struct LocalCascSource {
+    data_dir: PathBuf,
+    entries: HashMap<TruncatedKey, LocalEntry>,
+}
+
+/// Parses a version 7 `.idx` file
+pub fn parse_idx(data: &[u8]) -> error::Result<Vec<(TruncatedKey, LocalEntry)>> {
+    let mut p = Cursor::new(data, WHAT);
+    let header_size = p.u32_le()? as usize;
+    let _header_hash = p.u32_le()?;
+    check!(
+        header_size >= 16,
+        Error::malformed(WHAT, "header too small")
+    );
+    let mut header = p.sub(header_size, "local idx header")?;
+    let version = header.u16_le()?;
+    check!(
+        version == 7,
+        Error::UnsupportedVersion {
+            what: WHAT,
+            version: version.into(),
+        }
+    );
+    let _bucket = header.u8()?;
+    let _extra_bytes = header.u8()?;
+    let size_bytes = header.u8()?;
+    let offset_bytes = header.u8()?;
+    let key_bytes = header.u8()?;
+    let offset_bits = header.u8()?;
+    check!(
+        (size_bytes, offset_bytes, key_bytes) == (4, 5, 9),
+        Error::malformed(WHAT, "unsupported entry layout")
+    );
+    check!(
+        offset_bits < 40,
+        Error::malformed(WHAT, "invalid offset bits")
+    );
+
+    let mut p = Cursor::new(data, WHAT);
+    p.skip((8 + header_size).next_multiple_of(16))?;
+    let entries_size = p.u32_le()? as usize;
+    let _entries_hash = p.u32_le()?;
+    let mut p = p.sub(entries_size, "local idx entries")?;
+    let mut entries = Vec::with_capacity(entries_size / 18);
+    while p.remaining() >= 18 {
+        let key = TruncatedKey::from_bytes(p.array()?);
+        let packed = p.u40()?;
+        let size = p.u32_le()?;
+        entries.push((
+            key,
+            LocalEntry {
+                archive: (packed >> offset_bits) as u32,
+                offset: packed & ((1 << offset_bits) - 1),
+                size,
+            },
+        ));
+    }
+    Ok(entries)
+}
+
+impl LocalCascSource {
+    /// Opens an install's `Data/data` directory, reading the newest `.idx` of each bucket
+    #[tracing::instrument(err, skip_all, fields(data_dir = %data_dir.as_ref().display()))]
+    pub fn open(data_dir: impl AsRef<Path>) -> Result<Self> {
+        let data_dir = data_dir.as_ref().to_owned();
+        // file names are the bucket as two hex digits followed by a version
+        let mut newest = HashMap::<String, String>::new();
+        for entry in std::fs::read_dir(&data_dir)
+            .with_context(|| format!("reading {}", data_dir.display()))?
+        {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            let Some(stem) = name.strip_suffix(".idx") else {
+                continue;
+            };
+            if stem.len() != 10 || !stem.bytes().all(|b| b.is_ascii_hexdigit()) {
+                continue;
+            }
+            let current = newest.entry(stem[..2].to_owned()).or_default();
+            if *current < name {
+                *current = name;
+            }
+        }
+        ensure!(
+            !newest.is_empty(),
+            "no .idx files in {}",
+            data_dir.display()
+        );
+
+        let mut entries = HashMap::new();
+        for name in newest.values() {
+            let data = std::fs::read(data_dir.join(name))?;
+            entries.extend(parse_idx(&data).with_context(|| format!("parsing {name}"))?);
+        }
+        tracing::info!("Loaded {} local index entries", entries.len());
+        Ok(Self { data_dir, entries })
+    }
+}
+
+impl BlobSource for LocalCascSource {
+    fn get_ekey(&self, ekey: EncodingKey) -> Result<Vec<u8>> {
+        let entry = self
+            .entries
+            .get(&ekey.truncated())
+            .with_context(|| format!("{ekey} not in local storage"))?;
+        let size = entry.size as usize;
+        ensure!(size > LOCAL_HEADER_SIZE, "local entry for {ekey} too small");
+        let path = self.data_dir.join(format!("data.{:03}", entry.archive));
+        let mut file = File::open(&path).with_context(|| format!("opening {}", path.display()))?;
+        file.seek(SeekFrom::Start(entry.offset + LOCAL_HEADER_SIZE as u64))?;
+        let mut blob = vec![0; size - LOCAL_HEADER_SIZE];
+        file.read_exact(&mut blob)
+            .with_context(|| format!("reading {ekey} from {}", path.display()))?;
+        Ok(blob)
+    }
+
+    fn has(&self, ekey: EncodingKey) -> bool {
+        self.entries.contains_key(&ekey.truncated())
+    }
+}
@@ -0,0 +1,331 @@
+//! Copying a whole build into a directory laid out like the CDN.
+//!
+//! Files land at `<output>/<cdn path>/{config,data,patch}/xx/yy/<key>`, the
+//! TACT responses at `<output>/<product>/<endpoint>`, so any static file
+//! server can serve the tree and a [`crate::cdn::Mirror`] pointed at
+//! `<server>/<cdn path>/` reads it like the real CDN.
+//!
+//! Nothing lands in the tree unchecked: configs must hash to their key,
+//! loose blobs and every blob an archive index places in an archive must
+//! have a BLTE header hashing to their ekey, and an archive index's footer
+//! must hash to the archive's key.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Result, ensure};
+
+use crate::{
+    ArchiveKey, CascClient, EncodingKey, Error, FileKeys, Index, Md5Key, VersionEntry, blte,
+    cache::CacheByKey,
+    casc_client_for_build,
+    cdn::{CdnPool, Mirror},
+    download::DownloadOptions,
+    error::check,
+    find_version, format_hex_key,
+    product::ProductProfile,
+    tact_psv,
+    transport::Transport,
+    version_entries,
+};
+
+/// CDN config keys naming `.index` files, with the directory they live in
+const INDEXES: &[(&str, &str)] = &[
+    ("archive-group", "data"),
+    ("file-index", "data"),
+    ("archives", "data"),
+    ("patch-archive-group", "patch"),
+    ("patch-file-index", "patch"),
+    ("patch-archives", "patch"),
+];
+
+/// TACT endpoints stored alongside the build
+const TACT_ENDPOINTS: &[&str] = &["versions", "cdns"];
+
+#[derive(Debug, Default)]
+pub struct MirrorReport {
+    /// Build that was mirrored
+    pub version: Option<VersionEntry>,
+    /// Directory the CDN tree was written to, `<output>/<cdn path>`
+    pub root: PathBuf,
+    pub files: usize,
+    pub already_present: usize,
+    pub copied_from_cache: usize,
+    pub downloaded: usize,
+    pub bytes: u64,
+    /// Paths relative to `root` that could not be fetched, with the error
+    pub failed: Vec<(String, String)>,
+}
+
+/// Mirrors a build of `product` from `region`'s CDN under `output`.
+///
+/// Configs and indexes already in `cache` are copied from it, everything
+/// else streams straight into the tree. Files already present are skipped,
+/// so an interrupted mirror picks up where it stopped.
+#[tracing::instrument(err, skip(transport, cache, mirrors, options))]
+#[allow(clippy::too_many_arguments)]
+pub fn mirror(
+    transport: Arc<dyn Transport>,
+    cache: CacheByKey,
+    product: &str,
+    region: &str,
+    version: Option<&str>,
+    mirrors: &[Mirror],
+    output: &Path,
+    options: &DownloadOptions,
+) -> Result<MirrorReport> {
+    let cdns = tact_psv(&*transport, &cache, product, region, "cdns")?;
+    let versions = tact_psv(&*transport, &cache, product, region, "versions")?;
+    let entry = find_version(version_entries(&versions)?, region, version)?;
+
+    let name = cdns.column("Name").unwrap_or(0);
+    let path = cdns.column("Path").unwrap_or(1);
+    let cdn_path = cdns
+        .entries()
+        .map(|x| x.collect::<Vec<_>>())
+        .find(|x| x.get(name) == Some(&region))
+        .and_then(|x| x.get(path).map(|x| x.to_string()))
+        .with_context(|| format!("no CDN path for region {region}"))?;
+    ensure!(
+        cdn_path
+            .split('/')
+            .all(|x| !x.is_empty() && x != "." && x != ".."),
+        "unsafe CDN path {cdn_path}"
+    );
+
+    for endpoint in TACT_ENDPOINTS {
+        let text = cache.load_tact(product, region, endpoint)?;
+        let path = output.join(product).join(endpoint);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(path, text)?;
+    }
+
+    let cdn = CdnPool::from_cdns(transport, &cdns, region, mirrors)?;
+    let client = casc_client_for_build(ProductProfile::for_code(product), cdn, cache, &entry)?;
+    let root = output.join(cdn_path);
+    let index = client.archive_index()?;
+    let files = build_files(&client, &entry, &index)?;
+    tracing::info!(files = files.len(), root = %root.display(), "Mirroring build");
+
+    let mut archives = HashMap::<ArchiveKey, Vec<_>>::new();
+    for (&ekey, &(archive, size, offset)) in &index.map {
+        archives
+            .entry(archive)
+            .or_default()
+            .push((ekey, size, offset));
+    }
+    let mut report = copy_files(&client, &archives, &root, files, options);
+    report.version = Some(entry);
+    report.root = root;
+    Ok(report)
+}
+
+/// Every `(kind, key)` making up the build, indexes first so a partial mirror is usable
+fn build_files(
+    client: &CascClient,
+    entry: &VersionEntry,
+    index: &Index,
+) -> Result<Vec<(&'static str, String)>> {
+    let mut files = vec![
+        ("config", entry.build_config.clone()),
+        ("config", entry.cdn_config.clone()),
+    ];
+    let (build, cdn) = (client.build_config(), client.cdn_config());
+    files.extend(
+        build
+            .words("patch-config")
+            .map(|x| ("config", x.to_owned())),
+    );
+
+    for (name, kind) in INDEXES {
+        files.extend(cdn.words(name).map(|x| (*kind, format!("{x}.index"))));
+    }
+    for (name, kind) in [("archives", "data"), ("patch-archives", "patch")] {
+        files.extend(cdn.words(name).map(|x| (kind, x.to_owned())));
+    }
+    files.extend(build.words("patch").map(|x| ("patch", x.to_owned())));
+
+    // loose files are whatever encoding lists that no archive holds
+    let encoding = build
+        .get("encoding")
+        .context("Missing encoding")?
+        .parse::<FileKeys>()?;
+    let mut loose = HashSet::<EncodingKey>::new();
+    loose.insert(encoding.ekey);
+    loose.extend(
+        client
+            .encoding()
+            .ekeys()
+            .map(|(ekey, _)| ekey)
+            // an all-zero key is page padding, never a real file
+            .filter(|x| x.0 != 0 && !index.map.contains_key(x)),
+    );
+    let mut loose = loose.into_iter().collect::<Vec<_>>();
+    loose.sort_unstable();
+    files.extend(loose.into_iter().map(|x| ("data", x.to_string())));
+
+    let mut seen = HashSet::new();
+    files.retain(|x| seen.insert(x.clone()));
+    Ok(files)
+}
+
+enum Copied {
+    AlreadyPresent,
+    FromCache(u64),
+    Downloaded(u64),
+}
+
+/// Blobs of each archive as `(ekey, size, offset)`
+type ArchiveEntries = HashMap<ArchiveKey, Vec<(EncodingKey, usize, usize)>>;
+
+/// Copies `files` into `root` on `options.concurrency` threads
+fn copy_files(
+    client: &CascClient,
+    archives: &ArchiveEntries,
+    root: &Path,
+    files: Vec<(&'static str, String)>,
+    options: &DownloadOptions,
+) -> MirrorReport {
+    let report = Mutex::new(MirrorReport {
+        files: files.len(),
+        ..Default::default()
+    });
+    let queue = Mutex::new(files.into_iter().collect::<VecDeque<_>>());
+
+    client.cdn().with_host_limit(options.per_host, || {
+        std::thread::scope(|s| {
+            for _ in 0..options.concurrency.max(1) {
+                s.spawn(|| {
+                    loop {
+                        let Some((kind, key)) = queue.lock().unwrap().pop_front() else {
+                            break;
+                        };
+                        let result = copy_file(client, archives, root, kind, &key);
+                        let mut report = report.lock().unwrap();
+                        match result {
+                            Ok(Copied::AlreadyPresent) => report.already_present += 1,
+                            Ok(Copied::FromCache(bytes)) => {
+                                report.copied_from_cache += 1;
+                                report.bytes += bytes;
+                            }
+                            Ok(Copied::Downloaded(bytes)) => {
+                                report.downloaded += 1;
+                                report.bytes += bytes;
+                            }
+                            Err(e) => {
+                                tracing::warn!("Mirroring {kind}/{key} failed: {e:#}");
+                                report
+                                    .failed
+                                    .push((format!("{kind}/{key}"), format!("{e:#}")));
+                            }
+                        }
+                    }
+                });
+            }
+        })
+    });
+    report.into_inner().unwrap()
+}
+
+fn copy_file(
+    client: &CascClient,
+    archives: &ArchiveEntries,
+    root: &Path,
+    kind: &str,
+    key: &str,
+) -> Result<Copied> {
+    let relative = format!("{kind}/{}", format_hex_key(key));
+    let path = root.join(&relative);
+    if path.is_file() {
+        return Ok(Copied::AlreadyPresent);
+    }
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    let part = PathBuf::from(format!("{}.part", path.display()));
+    // raw bytes, since verifying could evict what we are about to copy
+    match client.cache().open(kind, key)? {
+        Some(mut file) => {
+            let bytes = std::io::copy(&mut file, &mut File::create(&part)?)?;
+            if let Err(e) = verify(client.cache(), archives, kind, key, &part) {
+                std::fs::remove_file(&part)?;
+                return Err(e.context("cached copy is corrupt"));
+            }
+            std::fs::rename(&part, &path)?;
+            Ok(Copied::FromCache(bytes))
+        }
+        None => {
+            let part = client.cdn().fetch_to_file(&relative, None, &part)?;
+            if let Err(e) = verify(client.cache(), archives, kind, key, part.path()) {
+                std::fs::remove_file(part.path())?;
+                return Err(e.context("downloaded data is corrupt"));
+            }
+            let bytes = part.len();
+            part.persist(&path)?;
+            Ok(Copied::Downloaded(bytes))
+        }
+    }
+}
+
+/// Checks the file at `path` against `kind/key` before it lands in the mirror
+fn verify(
+    cache: &CacheByKey,
+    archives: &ArchiveEntries,
+    kind: &str,
+    key: &str,
+    path: &Path,
+) -> Result<()> {
+    if let Some(archive) = key.strip_suffix(".index") {
+        let archive = archive.parse::<ArchiveKey>()?;
+        let data = std::fs::read(path)?;
+        let footer = data.len().checked_sub(28).map(|x| &data[x..]);
+        let actual = ArchiveKey::of(footer.ok_or(Error::Truncated {
+            what: "archive index",
+        })?);
+        check!(
+            actual == archive,
+            Error::ChecksumMismatch {
+                expected: archive.0,
+                actual: actual.0,
+                what: "archive index footer",
+            }
+        );
+        return Ok(());
+    }
+    if let Some(entries) = key.parse().ok().and_then(|x| archives.get(&x)) {
+        return verify_archive(path, entries);
+    }
+    cache.verify(kind, key, &std::fs::read(path)?)
+}
+
+/// Checks that each blob `entries` places in the archive at `path` has a
+/// BLTE header hashing to its ekey, reading only the headers
+fn verify_archive(path: &Path, entries: &[(EncodingKey, usize, usize)]) -> Result<()> {
+    let mut file = File::open(path)?;
+    for &(ekey, size, offset) in entries {
+        let mut start = [0; 8];
+        file.seek(SeekFrom::Start(offset as u64))?;
+        file.read_exact(&mut start)
+            .with_context(|| format!("reading {ekey} at {offset}"))?;
+        // a zero header size means the header hash covers the whole blob
+        let header_size = u32::from_be_bytes(start[4..].try_into().unwrap()) as usize;
+        let len = if header_size == 0 { size } else { header_size };
+        let mut header = vec![0; len.min(size)];
+        file.seek(SeekFrom::Start(offset as u64))?;
+        file.read_exact(&mut header)
+            .with_context(|| format!("reading {ekey} at {offset}"))?;
+        let actual = blte::header_hash(&header)?;
+        check!(
+            actual == ekey,
+            Error::ChecksumMismatch {
+                expected: ekey.0,
+                actual: actual.0,
+                what: "BLTE header",
+            }
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,471 @@
+//! An async client for tokio services, enabled by the `tokio` feature.
+//!
+//! Requests go through an async `reqwest` client, while cache access and
+//! the parsers, the same ones the blocking [`crate::CascClient`] uses, run
+//! on tokio's blocking pool so a large encoding manifest or archive index
+//! never stalls the executor.
+
+use std::{collections::HashMap, ops::Range, sync::Arc};
+
+use anyhow::{Context, Result, ensure};
+
+use crate::{
+    ArchiveKey, BuildKeys, ContentKey, EncodingKey, Error, Index, PipeSeparatedVars, VersionEntry,
+    blte,
+    cache::CacheByKey,
+    cdn::{Attempt, Hosts, Mirror, check_status, prefixes_from_cdns, range_body},
+    config, encoding, find_version, format_hex_key, install, load_pipe_separated_vars, md5hash,
+    parse_index, product, source, tact_url,
+    transport::{TransportOptions, range_header},
+    version_entries,
+};
+
+/// Runs `f` on tokio's blocking pool
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> Result<T> + Send + 'static) -> Result<T> {
+    tokio::task::spawn_blocking(f).await?
+}
+
+/// Issues GET requests, retrying transient failures like
+/// [`crate::transport::HttpTransport`] and optionally sending TACT requests
+/// elsewhere like [`crate::transport::TactRedirect`]
+#[derive(Clone, Debug, Default)]
+pub struct AsyncTransport {
+    client: reqwest::Client,
+    options: TransportOptions,
+    tact: Option<String>,
+}
+
+impl AsyncTransport {
+    pub fn new(client: reqwest::Client) -> Self {
+        Self {
+            client,
+            options: TransportOptions::default(),
+            tact: None,
+        }
+    }
+
+    /// Retries and backs off as `options` says, its timeouts apply to `client` only
+    pub fn with_options(mut self, options: TransportOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Sends TACT requests to `url`, e.g. a [`crate::serve::CdnServer`]
+    pub fn with_tact(mut self, url: &str) -> Self {
+        self.tact = Some(url.trim_end_matches('/').to_owned());
+        self
+    }
+
+    fn tact_url(&self, product: &str, region: &str, endpoint: &str) -> String {
+        match &self.tact {
+            Some(tact) => format!("{tact}/{product}/{endpoint}"),
+            None => tact_url(product, region, endpoint),
+        }
+    }
+
+    /// Like [`crate::transport::Transport::get`], any HTTP status is `Ok`
+    pub async fn get(&self, url: &str, range: Option<Range<usize>>) -> Result<reqwest::Response> {
+        self.get_with(url, range, &self.options).await
+    }
+
+    /// Like [`crate::transport::Transport::get_once`]
+    pub async fn get_once(
+        &self,
+        url: &str,
+        range: Option<Range<usize>>,
+    ) -> Result<reqwest::Response> {
+        self.get_with(url, range, &self.options.once()).await
+    }
+
+    async fn get_with(
+        &self,
+        url: &str,
+        range: Option<Range<usize>>,
+        options: &TransportOptions,
+    ) -> Result<reqwest::Response> {
+        if range.as_ref().is_some_and(|x| x.is_empty()) {
+            // nothing to fetch, and no `Range` header can ask for it
+            let empty = http::Response::builder().status(206).body(vec![])?;
+            return Ok(empty.into());
+        }
+        let mut attempt = 0;
+        loop {
+            let mut request = self.client.get(url);
+            if let Some(range) = &range {
+                request = request.header(reqwest::header::RANGE, range_header(range));
+            }
+            let delay = match request.send().await {
+                Ok(response) => {
+                    let status = response.status().as_u16();
+                    match options.retry_status(attempt, status, response.headers()) {
+                        Some(delay) => delay,
+                        None => return Ok(response),
+                    }
+                }
+                Err(e) => match options.retry_error(attempt, &e) {
+                    Some(delay) => delay,
+                    None => return Err(e).with_context(|| format!("GET {url}")),
+                },
+            };
+            attempt += 1;
+            tracing::info!("Retrying {url} in {delay:?}, attempt {attempt}");
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+/// The async counterpart of [`crate::tact_psv`]
+#[tracing::instrument(err, skip(transport, cache))]
+pub async fn tact_psv(
+    transport: &AsyncTransport,
+    cache: &CacheByKey,
+    product: &str,
+    region: &str,
+    endpoint: &str,
+) -> Result<PipeSeparatedVars> {
+    let (cache, product, region, endpoint) = (
+        cache.clone(),
+        product.to_owned(),
+        region.to_owned(),
+        endpoint.to_owned(),
+    );
+    if cache.is_offline() {
+        let text = blocking(move || cache.load_tact(&product, &region, &endpoint)).await?;
+        return Ok(load_pipe_separated_vars(text));
+    }
+    let url = transport.tact_url(&product, &region, &endpoint);
+    let response = transport.get(&url, None).await?;
+    ensure!(
+        response.status().is_success(),
+        "HTTP {} for {url}",
+        response.status()
+    );
+    let text = response.text().await?;
+    let text = blocking(move || {
+        cache.store_tact(&product, &region, &endpoint, &text)?;
+        Ok(text)
+    })
+    .await?;
+    Ok(load_pipe_separated_vars(text))
+}
+
+/// The async counterpart of [`crate::cdn::CdnPool`], with the same host
+/// health tracking, failover and per-host request limit
+#[derive(Debug)]
+pub struct AsyncCdn {
+    transport: AsyncTransport,
+    hosts: Hosts,
+}
+
+impl AsyncCdn {
+    pub fn new(transport: AsyncTransport, prefixes: Vec<String>) -> Result<Self> {
+        Ok(Self {
+            transport,
+            hosts: Hosts::new(prefixes)?,
+        })
+    }
+
+    /// Limits how many requests may be in flight to any single host, `None` for no limit
+    pub fn set_host_limit(&self, limit: Option<usize>) {
+        self.hosts.swap_limit(limit);
+    }
+
+    /// Prefix of the host requests currently start at
+    pub fn primary(&self) -> &str {
+        self.hosts.primary()
+    }
+
+    /// All host prefixes with whether they are currently considered healthy
+    pub fn hosts(&self) -> impl Iterator<Item = (&str, bool)> {
+        self.hosts.health()
+    }
+
+    /// Fetches `path`, or `range` of it, from the first host that has it.
+    ///
+    /// Connection errors and error statuses other than 416 move on to the
+    /// next host. Transient failures are only retried on the last host.
+    #[tracing::instrument(err, skip(self))]
+    pub async fn fetch(&self, path: &str, range: Option<Range<usize>>) -> Result<bytes::Bytes> {
+        let mut failover = self.hosts.failover(path);
+        while let Some((i, url)) = failover.next_host() {
+            let slot = failover.acquire_async(i).await;
+            let result = self.fetch_from(&url, &range, failover.is_last()).await?;
+            drop(slot);
+            match result {
+                Attempt::Done(data) => {
+                    failover.succeeded(i);
+                    return Ok(data);
+                }
+                Attempt::Failover(e) => failover.failed(i, e),
+            }
+        }
+        Err(failover.into_error())
+    }
+
+    /// One host's attempt at a fetch, retrying transient failures only if it is the `last`
+    async fn fetch_from(
+        &self,
+        url: &str,
+        range: &Option<Range<usize>>,
+        last: bool,
+    ) -> Result<Attempt<bytes::Bytes>> {
+        let response = if last {
+            self.transport.get(url, range.clone()).await
+        } else {
+            self.transport.get_once(url, range.clone()).await
+        };
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => return Ok(Attempt::Failover(e)),
+        };
+        let status = response.status().as_u16();
+        let response = match check_status(url, status, response)? {
+            Attempt::Done(response) => response,
+            Attempt::Failover(e) => return Ok(Attempt::Failover(e)),
+        };
+        let data = match response.bytes().await {
+            Ok(data) => data,
+            // body interrupted mid-transfer
+            Err(e) => return Ok(Attempt::Failover(e.into())),
+        };
+        range_body(url, status, data, range).map(Attempt::Done)
+    }
+}
+
+/// The async counterpart of [`crate::CascClient`].
+///
+/// Unlike the blocking client it never loads archive indexes on its own:
+/// archived blobs can only be read after [`Self::with_archive_index`].
+#[derive(Debug)]
+pub struct AsyncCascClient {
+    product: product::ProductProfile,
+    cdn: Arc<AsyncCdn>,
+    cache: CacheByKey,
+    encoding: encoding::Encoding,
+    install: install::Install,
+    archives: Vec<ArchiveKey>,
+    /// Archive indexes, without them every ekey is fetched as a loose file
+    index: Option<Arc<Index>>,
+    version: VersionEntry,
+    build_config: config::Config,
+    cdn_config: config::Config,
+}
+
+/// The async counterpart of [`crate::cdn_casc_client`]
+#[tracing::instrument(err, skip(transport, cache))]
+pub async fn cdn_casc_client(
+    transport: AsyncTransport,
+    cache: CacheByKey,
+    product: &str,
+    region: &str,
+    version: Option<&str>,
+    mirrors: &[Mirror],
+) -> Result<AsyncCascClient> {
+    let product = product::ProductProfile::for_code(product);
+    let cdns = tact_psv(&transport, &cache, &product.code, region, "cdns").await?;
+    let versions = tact_psv(&transport, &cache, &product.code, region, "versions").await?;
+    let version = find_version(version_entries(&versions)?, region, version)?;
+    let cdn = AsyncCdn::new(transport, prefixes_from_cdns(&cdns, region, mirrors)?)?;
+    tracing::info!(cdn = cdn.primary(), "Picked CDN");
+    casc_client_for_build(product, cdn, cache, &version).await
+}
+
+/// The async counterpart of [`crate::casc_client_for_build`]
+#[tracing::instrument(err, skip(product, cdn, cache))]
+pub async fn casc_client_for_build(
+    product: product::ProductProfile,
+    cdn: AsyncCdn,
+    cache: CacheByKey,
+    version: &VersionEntry,
+) -> Result<AsyncCascClient> {
+    let cdn = Arc::new(cdn);
+    let config_text = |key: String| {
+        let (cdn, cache) = (cdn.clone(), cache.clone());
+        async move {
+            let data = cached(&cdn, &cache, "config", &key, &key, None).await?;
+            String::from_utf8(data).with_context(|| format!("config {key} is not utf-8"))
+        }
+    };
+    let cdn_config = config::Config::parse(&config_text(version.cdn_config.clone()).await?)?;
+    let build_config = config::Config::parse(&config_text(version.build_config.clone()).await?)?;
+    let keys = BuildKeys::new(&cdn_config, &build_config)?;
+    cache.add_archives(keys.archives.iter().copied());
+
+    let ekey = keys.encoding.ekey;
+    let blob = cached(
+        &cdn,
+        &cache,
+        "data",
+        &ekey.to_string(),
+        &ekey.to_string(),
+        None,
+    )
+    .await?;
+    let encoding = blocking(move || Ok(encoding::parse(&blte::parse(ekey, &blob)?)?)).await?;
+    let ekey = keys.install.ekey;
+    let blob = cached(
+        &cdn,
+        &cache,
+        "data",
+        &ekey.to_string(),
+        &ekey.to_string(),
+        None,
+    )
+    .await?;
+    let (profile, region) = (product.clone(), version.region.clone());
+    let (encoding, install, keys) = blocking(move || {
+        let install = keys.parse_install(&profile, &region, &encoding, &blob)?;
+        Ok((encoding, install, keys))
+    })
+    .await?;
+    let archives = keys.archives;
+
+    Ok(AsyncCascClient {
+        product,
+        cdn,
+        cache,
+        encoding,
+        install,
+        archives,
+        index: None,
+        version: version.clone(),
+        build_config,
+        cdn_config,
+    })
+}
+
+/// Reads `kind/key` from the cache, on a miss fetching `range` of the remote
+/// file `remote_key` and caching it, like [`CacheByKey::get_range`]
+async fn cached(
+    cdn: &AsyncCdn,
+    cache: &CacheByKey,
+    kind: &'static str,
+    key: &str,
+    remote_key: &str,
+    range: Option<Range<usize>>,
+) -> Result<Vec<u8>> {
+    let (reader, read_key) = (cache.clone(), key.to_owned());
+    if let Some(data) = blocking(move || reader.read(kind, &read_key)).await? {
+        return Ok(data);
+    }
+    ensure!(
+        !cache.is_offline(),
+        "{kind}/{key} is not cached and offline mode is enabled"
+    );
+    let path = format!("{kind}/{}", format_hex_key(remote_key));
+    let data = cdn.fetch(&path, range).await?.to_vec();
+    let (writer, key) = (cache.clone(), key.to_owned());
+    blocking(move || {
+        writer
+            .insert(kind, &key, &data)
+            .context("downloaded data is corrupt")?;
+        Ok(data)
+    })
+    .await
+}
+
+impl AsyncCascClient {
+    pub fn product(&self) -> &product::ProductProfile {
+        &self.product
+    }
+
+    pub fn cdn(&self) -> &AsyncCdn {
+        &self.cdn
+    }
+
+    pub fn cache(&self) -> &CacheByKey {
+        &self.cache
+    }
+
+    pub fn encoding(&self) -> &encoding::Encoding {
+        &self.encoding
+    }
+
+    pub fn install(&self) -> &install::Install {
+        &self.install
+    }
+
+    pub fn version(&self) -> &VersionEntry {
+        &self.version
+    }
+
+    pub fn build_config(&self) -> &config::Config {
+        &self.build_config
+    }
+
+    pub fn cdn_config(&self) -> &config::Config {
+        &self.cdn_config
+    }
+
+    /// Fetches and merges the `.index` files of every archive in the CDN config
+    #[tracing::instrument(err, skip(self))]
+    pub async fn archive_index(&self) -> Result<Index> {
+        let mut map = HashMap::new();
+        for &archive in &self.archives {
+            let key = format!("{archive}.index");
+            let data = cached(&self.cdn, &self.cache, "data", &key, &key, None).await?;
+            map.extend(
+                blocking(move || Ok(parse_index(archive, &data)?))
+                    .await?
+                    .map,
+            );
+        }
+        Ok(Index { map })
+    }
+
+    /// Reads archived blobs as ranges of their archive instead of as loose files
+    pub async fn with_archive_index(mut self) -> Result<Self> {
+        self.index = Some(Arc::new(self.archive_index().await?));
+        Ok(self)
+    }
+
+    /// The encoded blob stored under `ekey`, from the cache or the CDN
+    pub async fn get_blob(&self, ekey: EncodingKey) -> Result<Vec<u8>> {
+        let key = ekey.to_string();
+        match self.index.as_ref().and_then(|x| x.map.get(&ekey)) {
+            Some(&(archive, size, offset)) => {
+                let archive = archive.to_string();
+                let range = Some(offset..offset + size);
+                cached(&self.cdn, &self.cache, "data", &key, &archive, range).await
+            }
+            None => cached(&self.cdn, &self.cache, "data", &key, &key, None).await,
+        }
+    }
+
+    #[tracing::instrument(err, skip(self))]
+    pub async fn get_by_ekey(&self, ekey: EncodingKey) -> Result<Vec<u8>> {
+        let blob = self.get_blob(ekey).await?;
+        blocking(move || Ok(blte::parse(ekey, &blob)?)).await
+    }
+
+    /// Reads the file `ckey` names, checking the decoded content hashes to it.
+    ///
+    /// A cached blob that decodes to the wrong content is evicted and fetched again.
+    #[tracing::instrument(err, skip(self))]
+    pub async fn get_by_ckey(&self, ckey: ContentKey) -> Result<Vec<u8>> {
+        let ekey = self.encoding.c2e(ckey)?;
+        let e = match self.get_by_ekey(ekey).await.and_then(|x| check(ckey, x)) {
+            Ok(data) => return Ok(data),
+            Err(e) => e,
+        };
+        let cache = self.cache.clone();
+        blocking(move || {
+            source::evict_for_refetch(ekey, e, |ekey| source::evict_cached(&cache, ekey))
+        })
+        .await?;
+        check(ckey, self.get_by_ekey(ekey).await?)
+    }
+}
+
+fn check(ckey: ContentKey, data: Vec<u8>) -> Result<Vec<u8>> {
+    let actual = md5hash(&data);
+    ensure!(
+        actual == ckey.0,
+        Error::ChecksumMismatch {
+            expected: ckey.0,
+            actual,
+            what: "decoded content",
+        }
+    );
+    Ok(data)
+}
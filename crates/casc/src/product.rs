@@ -0,0 +1,192 @@
+//! Product codes and the per-product behaviour that depends on them
+
+use anyhow::{Context, Result};
+
+use crate::ribbit::RibbitClient;
+
+/// Format of the build config's `root` file
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RootFormat {
+    /// WoW `MFST` root keyed by FileDataID
+    Wow,
+    /// Diablo III directory based root
+    Diablo3,
+    /// TVFS manifest referenced from the `vfs-root` build config key
+    Tvfs,
+    Unknown,
+}
+
+#[derive(Clone, Debug)]
+pub struct ProductProfile {
+    pub code: String,
+    /// Install manifest names of the main client executables, any top level
+    /// `.exe` if empty
+    pub client_binaries: &'static [&'static str],
+    /// Platform install tags a file must carry, none selects every platform
+    pub platform_tags: &'static [&'static str],
+    pub root_format: RootFormat,
+}
+
+const WINDOWS_TAGS: &[&str] = &["Windows", "x86_64"];
+
+impl ProductProfile {
+    /// Profile for a known product code, or a generic one for anything else
+    pub fn for_code(code: &str) -> Self {
+        let (client_binaries, root_format): (&'static [&'static str], _) = match code {
+            "wow" => (&["Wow.exe"], RootFormat::Wow),
+            "wowt" | "wowxptr" => (&["WowT.exe"], RootFormat::Wow),
+            "wow_beta" => (&["WowB.exe"], RootFormat::Wow),
+            "wow_classic" | "wow_classic_era" => (&["WowClassic.exe"], RootFormat::Wow),
+            "wow_classic_ptr" | "wow_classic_era_ptr" => (&["WowClassicT.exe"], RootFormat::Wow),
+            "wow_classic_beta" => (&["WowClassicB.exe"], RootFormat::Wow),
+            "d3" | "d3t" => (&["Diablo III64.exe"], RootFormat::Diablo3),
+            "pro" | "prot" => (&["Overwatch.exe"], RootFormat::Tvfs),
+            "hero" => (&["HeroesOfTheStorm_x64.exe"], RootFormat::Tvfs),
+            "fenris" => (&["Diablo IV.exe"], RootFormat::Tvfs),
+            _ => {
+                tracing::warn!(
+                    "Unknown product {code}, selecting install files for every platform"
+                );
+                return Self {
+                    code: code.to_owned(),
+                    client_binaries: &[],
+                    platform_tags: &[],
+                    root_format: RootFormat::Unknown,
+                };
+            }
+        };
+        Self {
+            code: code.to_owned(),
+            client_binaries,
+            platform_tags: WINDOWS_TAGS,
+            root_format,
+        }
+    }
+
+    /// Install tags for a build of `region`, whose upper-cased code is also
+    /// the tag of its region-specific files
+    pub fn install_tags(&self, region: &str) -> Vec<String> {
+        let mut tags = self
+            .platform_tags
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>();
+        tags.push(region.to_ascii_uppercase());
+        tags
+    }
+
+    pub fn is_client_binary(&self, install_name: &str) -> bool {
+        if self.client_binaries.is_empty() {
+            return !install_name.contains('\\') && install_name.ends_with(".exe");
+        }
+        self.client_binaries
+            .iter()
+            .any(|exe| install_name.ends_with(exe))
+    }
+}
+
+/// One row of the Ribbit `summary` listing
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SummaryEntry {
+    pub product: String,
+    pub seqn: u64,
+    /// Empty for `versions`, otherwise `cdn` or `bgdl`
+    pub flags: String,
+}
+
+#[derive(Debug)]
+pub struct ProductSummary {
+    pub entries: Vec<SummaryEntry>,
+}
+
+impl ProductSummary {
+    /// Fetches the product listing from a Ribbit server
+    pub fn fetch(ribbit: &RibbitClient) -> Result<Self> {
+        let psv = ribbit.summary()?.into_psv();
+        let product = psv
+            .column("Product")
+            .context("summary has no Product column")?;
+        let seqn = psv.column("Seqn").context("summary has no Seqn column")?;
+        let flags = psv.column("Flags");
+
+        let entries = psv
+            .entries()
+            .map(|x| x.collect::<Vec<_>>())
+            .map(|x| {
+                Ok(SummaryEntry {
+                    product: x[product].to_owned(),
+                    seqn: x[seqn].parse().context("invalid summary seqn")?,
+                    flags: flags.map(|i| x[i].to_owned()).unwrap_or_default(),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { entries })
+    }
+
+    /// Distinct product codes, sorted
+    pub fn products(&self) -> Vec<&str> {
+        let mut products = self
+            .entries
+            .iter()
+            .map(|x| x.product.as_str())
+            .collect::<Vec<_>>();
+        products.sort_unstable();
+        products.dedup();
+        products
+    }
+
+    /// Sequence number of a product's endpoint, `flags` as in [`SummaryEntry::flags`]
+    pub fn seqn(&self, product: &str, flags: &str) -> Option<u64> {
+        self.entries
+            .iter()
+            .find(|x| x.product == product && x.flags == flags)
+            .map(|x| x.seqn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_product_codes() {
+        let wow = ProductProfile::for_code("wow_classic_era_ptr");
+        assert_eq!(wow.code, "wow_classic_era_ptr");
+        assert_eq!(wow.client_binaries, ["WowClassicT.exe"]);
+        assert_eq!(wow.platform_tags, WINDOWS_TAGS);
+        assert_eq!(wow.root_format, RootFormat::Wow);
+        assert!(wow.is_client_binary("WowClassicT.exe"));
+        assert!(!wow.is_client_binary("Wow.exe"));
+
+        assert_eq!(
+            ProductProfile::for_code("d3t").root_format,
+            RootFormat::Diablo3
+        );
+        assert_eq!(
+            ProductProfile::for_code("fenris").root_format,
+            RootFormat::Tvfs
+        );
+    }
+
+    #[test]
+    fn unknown_products_select_everything() {
+        let profile = ProductProfile::for_code("casctest");
+        assert_eq!(profile.root_format, RootFormat::Unknown);
+        assert!(profile.platform_tags.is_empty());
+        assert!(profile.is_client_binary("Game.exe"));
+        assert!(!profile.is_client_binary("Tools\\Helper.exe"));
+        assert!(!profile.is_client_binary("Game.dll"));
+    }
+
+    #[test]
+    fn region_is_an_install_tag() {
+        assert_eq!(
+            ProductProfile::for_code("wow").install_tags("eu"),
+            ["Windows", "x86_64", "EU"]
+        );
+        assert_eq!(
+            ProductProfile::for_code("casctest").install_tags("us"),
+            ["US"]
+        );
+    }
+}
@@ -0,0 +1,218 @@
+//! Client for Blizzard's Ribbit protocol on TCP port 1119.
+//!
+//! A request is a single command line such as `v1/products/wow/versions`,
+//! after which the server writes its response and closes the connection.
+//! `v1/` responses are MIME multipart messages whose epilogue carries a
+//! SHA-256 `Checksum:` over everything before it, and may have a CMS
+//! signature attached. `v2/` responses are the bare pipe separated text.
+
+use std::{
+    io::{Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+use anyhow::{Context, Result, bail, ensure};
+
+use crate::{PipeSeparatedVars, load_pipe_separated_vars};
+
+pub const DEFAULT_PORT: u16 = 1119;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Version {
+    V1,
+    V2,
+}
+
+impl Version {
+    fn prefix(self) -> &'static str {
+        match self {
+            Version::V1 => "v1",
+            Version::V2 => "v2",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RibbitClient {
+    addr: String,
+    version: Version,
+    timeout: Duration,
+}
+
+/// A decoded Ribbit response
+#[derive(Debug)]
+pub struct RibbitResponse {
+    /// Payload text, normally pipe separated vars
+    pub data: String,
+    /// Base64 CMS signature attachment, v1 only and not always present
+    pub signature: Option<String>,
+    /// Verified SHA-256 from the MIME epilogue, v1 only
+    pub checksum: Option<[u8; 32]>,
+}
+
+impl RibbitResponse {
+    pub fn into_psv(self) -> PipeSeparatedVars {
+        load_pipe_separated_vars(self.data)
+    }
+}
+
+impl RibbitClient {
+    /// Client for `addr`, a `host:port` pair
+    pub fn new(addr: impl Into<String>, version: Version) -> Self {
+        Self {
+            addr: addr.into(),
+            version,
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// Client for the public `{region}.version.battle.net` server
+    pub fn for_region(region: &str, version: Version) -> Self {
+        Self::new(
+            format!("{region}.version.battle.net:{DEFAULT_PORT}"),
+            version,
+        )
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn summary(&self) -> Result<RibbitResponse> {
+        self.request("summary")
+    }
+
+    pub fn versions(&self, product: &str) -> Result<RibbitResponse> {
+        self.request(&format!("products/{product}/versions"))
+    }
+
+    pub fn cdns(&self, product: &str) -> Result<RibbitResponse> {
+        self.request(&format!("products/{product}/cdns"))
+    }
+
+    pub fn bgdl(&self, product: &str) -> Result<RibbitResponse> {
+        self.request(&format!("products/{product}/bgdl"))
+    }
+
+    /// Sends `command` prefixed with the protocol version and decodes the reply
+    #[tracing::instrument(err, skip(self), fields(addr = self.addr))]
+    pub fn request(&self, command: &str) -> Result<RibbitResponse> {
+        let command = format!("{}/{command}", self.version.prefix());
+        let raw = self.raw_request(&command)?;
+        match self.version {
+            Version::V1 => parse_v1(&raw),
+            Version::V2 => Ok(RibbitResponse {
+                data: String::from_utf8(raw).context("ribbit v2 response is not utf-8")?,
+                signature: None,
+                checksum: None,
+            }),
+        }
+    }
+
+    fn raw_request(&self, command: &str) -> Result<Vec<u8>> {
+        tracing::debug!("Sending ribbit command {command}");
+        let addr = self
+            .addr
+            .to_socket_addrs()?
+            .next()
+            .with_context(|| format!("could not resolve {}", self.addr))?;
+        let mut stream = TcpStream::connect_timeout(&addr, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        stream.write_all(command.as_bytes())?;
+        stream.write_all(b"\r\n")?;
+        let mut raw = vec![];
+        stream.read_to_end(&mut raw)?;
+        ensure!(!raw.is_empty(), "empty ribbit response for {command}");
+        Ok(raw)
+    }
+}
+
+fn sha256(p: &[u8]) -> [u8; 32] {
+    use sha2::{Digest, Sha256};
+    Sha256::digest(p).into()
+}
+
+/// Splits `headers\r\n\r\nbody` and returns the header lines and body
+fn split_headers(part: &str) -> (Vec<(&str, &str)>, &str) {
+    let (head, body) = part
+        .split_once("\r\n\r\n")
+        .or_else(|| part.split_once("\n\n"))
+        .unwrap_or((part, ""));
+    let headers = head
+        .lines()
+        .filter_map(|l| l.split_once(':'))
+        .map(|(k, v)| (k.trim(), v.trim()))
+        .collect();
+    (headers, body)
+}
+
+fn header<'a>(headers: &[(&str, &'a str)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| *v)
+}
+
+fn boundary(content_type: &str) -> Option<&str> {
+    content_type
+        .split(';')
+        .filter_map(|p| p.trim().split_once('='))
+        .find(|(k, _)| k.eq_ignore_ascii_case("boundary"))
+        .map(|(_, v)| v.trim_matches('"'))
+}
+
+/// Checks the epilogue checksum of a v1 MIME response and extracts its parts
+pub fn parse_v1(raw: &[u8]) -> Result<RibbitResponse> {
+    let text = std::str::from_utf8(raw).context("ribbit v1 response is not utf-8")?;
+
+    let checksum_at = text
+        .rfind("Checksum: ")
+        .filter(|&i| i == 0 || text.as_bytes()[i - 1] == b'\n')
+        .context("ribbit v1 response has no checksum epilogue")?;
+    let expected: [u8; 32] = hex::decode(text[checksum_at + "Checksum: ".len()..].trim())?
+        .try_into()
+        .ok()
+        .context("ribbit checksum has wrong length")?;
+    let actual = sha256(&raw[..checksum_at]);
+    ensure!(
+        actual == expected,
+        "ribbit checksum mismatch: expected {} got {}",
+        hex::encode(expected),
+        hex::encode(actual)
+    );
+
+    let (headers, body) = split_headers(&text[..checksum_at]);
+    let content_type = header(&headers, "Content-Type").context("missing Content-Type")?;
+    let boundary = boundary(content_type).context("missing MIME boundary")?;
+    let delimiter = format!("--{boundary}");
+
+    let mut data = None;
+    let mut signature = None;
+    // the first split is the preamble, the part after the close delimiter is the epilogue
+    for part in body.split(&delimiter).skip(1) {
+        if part.starts_with("--") {
+            break;
+        }
+        let (headers, body) = split_headers(part.trim_start_matches(['\r', '\n']));
+        let body = body.strip_suffix("\r\n").unwrap_or(body);
+        let disposition = header(&headers, "Content-Disposition").unwrap_or("");
+        let part_type = header(&headers, "Content-Type").unwrap_or("text/plain");
+        if part_type.starts_with("application/cms") || disposition.contains("cms.sgn") {
+            signature = Some(body.split_whitespace().collect());
+        } else if data.is_none() {
+            data = Some(body.to_owned());
+        }
+    }
+
+    let Some(data) = data else {
+        bail!("ribbit v1 response has no data part");
+    };
+    Ok(RibbitResponse {
+        data,
+        signature,
+        checksum: Some(expected),
+    })
+}
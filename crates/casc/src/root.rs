@@ -0,0 +1,197 @@
+//! WoW root manifest, mapping FileDataIDs and path hashes to ckeys.
+//!
+//! Files are grouped in blocks sharing locale and content flags. Since 8.2
+//! the file starts with an `MFST` header and a block stores its ckeys and
+//! name hashes as separate arrays; before that they were interleaved.
+
+use crate::{
+    ContentKey, FileDataID,
+    cursor::Cursor,
+    error::{Error, Result, check},
+};
+
+const WHAT: &str = "root";
+
+/// Content flag of blocks that carry no name hashes
+pub const NO_NAME_HASH: u32 = 0x1000_0000;
+/// Locale flag for enUS
+pub const LOCALE_ENUS: u32 = 0x2;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RootEntry {
+    pub fdid: FileDataID,
+    pub ckey: ContentKey,
+    pub locale_flags: u32,
+    pub content_flags: u32,
+    /// Jenkins hash of the path, see [`name_hash`]
+    pub name_hash: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+pub struct Root {
+    pub entries: Vec<RootEntry>,
+}
+
+impl Root {
+    /// The entry for `fdid`, preferring the enUS variant where there are several
+    pub fn by_fdid(&self, fdid: FileDataID) -> Option<&RootEntry> {
+        pick(self.entries.iter().filter(|x| x.fdid == fdid))
+    }
+
+    /// The entry whose path is `path`, preferring the enUS variant
+    pub fn by_path(&self, path: &str) -> Option<&RootEntry> {
+        let hash = name_hash(path);
+        pick(self.entries.iter().filter(|x| x.name_hash == Some(hash)))
+    }
+}
+
+fn pick<'a>(mut entries: impl Iterator<Item = &'a RootEntry> + Clone) -> Option<&'a RootEntry> {
+    entries
+        .clone()
+        .find(|x| x.locale_flags & LOCALE_ENUS != 0)
+        .or_else(|| entries.next())
+}
+
+#[tracing::instrument(err, skip(data))]
+pub fn parse(data: &[u8]) -> Result<Root> {
+    let mut p = Cursor::new(data, WHAT);
+    let mut version = None;
+    if p.peek(4).is_ok_and(|x| x == b"TSFM") {
+        let mut header = p;
+        header.skip(4)?;
+        let (header_size, header_version) = (header.u32_le()?, header.u32_le()?);
+        // 10.1.7 added a sized, versioned header, 8.2 only had the two counts
+        let header_size = if header_size == 24 && matches!(header_version, 1 | 2) {
+            version = Some(header_version);
+            header_size as usize
+        } else {
+            version = Some(0);
+            12
+        };
+        p.skip(header_size)?;
+    }
+
+    let mut entries = vec![];
+    while !p.is_empty() {
+        let num_records = p.u32_le()? as usize;
+        let (locale_flags, content_flags) = if version == Some(2) {
+            let locale = p.u32_le()?;
+            let (unk1, unk2, unk3) = (p.u32_le()?, p.u32_le()?, p.u8()?);
+            (locale, unk1 | unk2 | (u32::from(unk3) << 17))
+        } else {
+            let content = p.u32_le()?;
+            (p.u32_le()?, content)
+        };
+        let has_names = version.is_none() || content_flags & NO_NAME_HASH == 0;
+        let record_size = 4 + 16 + if has_names { 8 } else { 0 };
+        check!(
+            num_records
+                .checked_mul(record_size)
+                .is_some_and(|x| x <= p.remaining()),
+            p.truncated()
+        );
+
+        let mut fdids = Vec::with_capacity(num_records);
+        let mut next = 0i64;
+        for _ in 0..num_records {
+            let fdid = next + i64::from(p.i32_le()?);
+            check!(
+                (0..=i64::from(u32::MAX)).contains(&fdid),
+                Error::malformed(WHAT, "FileDataID out of range")
+            );
+            fdids.push(FileDataID(fdid as u32));
+            next = fdid + 1;
+        }
+        let start = entries.len();
+        for &fdid in &fdids {
+            let ckey = ContentKey(p.u128()?);
+            // before 8.2 each ckey is directly followed by its name hash
+            let name_hash = if version.is_none() {
+                Some(p.u64_le()?)
+            } else {
+                None
+            };
+            entries.push(RootEntry {
+                fdid,
+                ckey,
+                locale_flags,
+                content_flags,
+                name_hash,
+            });
+        }
+        if version.is_some() && has_names {
+            for entry in &mut entries[start..] {
+                entry.name_hash = Some(p.u64_le()?);
+            }
+        }
+    }
+    Ok(Root { entries })
+}
+
+/// The root manifest's hash of a path: Jenkins lookup3 over the upper-cased,
+/// backslash separated path
+pub fn name_hash(path: &str) -> u64 {
+    let normalized = path.to_ascii_uppercase().replace('/', "\\");
+    let (c, b) = hashlittle2(normalized.as_bytes(), 0, 0);
+    (u64::from(c) << 32) | u64::from(b)
+}
+
+/// Bob Jenkins' lookup3 `hashlittle2`, returning `(pc, pb)`
+fn hashlittle2(key: &[u8], pc: u32, pb: u32) -> (u32, u32) {
+    let init = 0xdeadbeef_u32
+        .wrapping_add(key.len() as u32)
+        .wrapping_add(pc);
+    let (mut a, mut b, mut c) = (init, init, init.wrapping_add(pb));
+    if key.is_empty() {
+        return (c, b);
+    }
+
+    let word = |x: &[u8]| {
+        x.iter()
+            .enumerate()
+            .fold(0u32, |acc, (i, &v)| acc | u32::from(v) << (8 * i))
+    };
+    let mut k = key;
+    while k.len() > 12 {
+        a = a.wrapping_add(word(&k[0..4]));
+        b = b.wrapping_add(word(&k[4..8]));
+        c = c.wrapping_add(word(&k[8..12]));
+        // mix
+        a = a.wrapping_sub(c) ^ c.rotate_left(4);
+        c = c.wrapping_add(b);
+        b = b.wrapping_sub(a) ^ a.rotate_left(6);
+        a = a.wrapping_add(c);
+        c = c.wrapping_sub(b) ^ b.rotate_left(8);
+        b = b.wrapping_add(a);
+        a = a.wrapping_sub(c) ^ c.rotate_left(16);
+        c = c.wrapping_add(b);
+        b = b.wrapping_sub(a) ^ a.rotate_left(19);
+        a = a.wrapping_add(c);
+        c = c.wrapping_sub(b) ^ b.rotate_left(4);
+        b = b.wrapping_add(a);
+        k = &k[12..];
+    }
+    a = a.wrapping_add(word(&k[..k.len().min(4)]));
+    if k.len() > 4 {
+        b = b.wrapping_add(word(&k[4..k.len().min(8)]));
+    }
+    if k.len() > 8 {
+        c = c.wrapping_add(word(&k[8..]));
+    }
+    // final
+    c ^= b;
+    c = c.wrapping_sub(b.rotate_left(14));
+    a ^= c;
+    a = a.wrapping_sub(c.rotate_left(11));
+    b ^= a;
+    b = b.wrapping_sub(a.rotate_left(25));
+    c ^= b;
+    c = c.wrapping_sub(b.rotate_left(16));
+    a ^= c;
+    a = a.wrapping_sub(c.rotate_left(4));
+    b ^= a;
+    b = b.wrapping_sub(a.rotate_left(14));
+    c ^= b;
+    c = c.wrapping_sub(b.rotate_left(24));
+    (c, b)
+}
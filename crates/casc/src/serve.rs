@@ -0,0 +1,376 @@
+//! Serving a cache directory to other clients as if it were the CDN.
+//!
+//! `/<cdn path>/{config,data,patch}/xx/yy/<key>` is read straight from the
+//! cache, honouring `Range` so archive entries can be fetched on their own.
+//! `/<product>/versions` returns the cached response and `/<product>/cdns`
+//! one listing this server as the only host, so a client whose TACT
+//! requests go here (see [`crate::transport::TactRedirect`]) downloads
+//! everything from it too.
+
+use std::{
+    io::{BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    ops::Range,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+
+use crate::{cache::CacheByKey, format_hex_key, load_pipe_separated_vars};
+
+/// Cache kinds served as CDN directories
+const KINDS: &[&str] = &["config", "data", "patch"];
+/// Longest request or header line accepted, in bytes
+const MAX_LINE: u64 = 8192;
+/// Most headers accepted in one request
+const MAX_HEADERS: usize = 100;
+
+#[derive(Debug)]
+pub struct CdnServer {
+    listener: TcpListener,
+    cache: CacheByKey,
+    /// Region whose cached TACT responses are served
+    region: String,
+    /// Connections served at once, later ones are answered with a 503
+    max_connections: usize,
+    /// How long a connection may sit idle, reading or writing, before it is dropped
+    timeout: Duration,
+    active: AtomicUsize,
+}
+
+/// Counts a connection as active until dropped
+struct ConnectionSlot<'a>(&'a AtomicUsize);
+
+impl Drop for ConnectionSlot<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: Body,
+}
+
+enum Body {
+    Empty,
+    Text(String),
+    File(std::fs::File, u64),
+}
+
+impl Response {
+    fn status(status: u16) -> Self {
+        Self {
+            status,
+            headers: vec![],
+            body: Body::Empty,
+        }
+    }
+
+    fn text(text: String) -> Self {
+        Self {
+            status: 200,
+            headers: vec![("Content-Type", "text/plain".to_owned())],
+            body: Body::Text(text),
+        }
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        206 => "Partial Content",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        416 => "Range Not Satisfiable",
+        431 => "Request Header Fields Too Large",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Parses a single `bytes=` range against a file of `len` bytes, `None` if unsatisfiable
+fn parse_range(value: &str, len: u64) -> Option<Range<u64>> {
+    let (start, end) = value.trim().strip_prefix("bytes=")?.split_once('-')?;
+    let range = match (start.trim(), end.trim()) {
+        ("", suffix) => len.saturating_sub(suffix.parse().ok()?)..len,
+        (start, "") => start.parse().ok()?..len,
+        (start, end) => start.parse().ok()?..end.parse::<u64>().ok()?.saturating_add(1).min(len),
+    };
+    (range.start < range.end).then_some(range)
+}
+
+/// The `kind` and key a CDN path such as `tpr/wow/data/ab/cd/abcd..` names
+fn cdn_file(path: &str) -> Option<(&str, &str)> {
+    let parts = path.rsplitn(5, '/').collect::<Vec<_>>();
+    let [key, _, _, kind, ..] = parts.as_slice() else {
+        return None;
+    };
+    let hex = key.strip_suffix(".index").unwrap_or(key);
+    let valid = KINDS.contains(kind)
+        && hex.len() == 32
+        && hex.bytes().all(|b| b.is_ascii_hexdigit())
+        && path.ends_with(&format!("{kind}/{}", format_hex_key(key)));
+    valid.then_some((kind, key))
+}
+
+/// Whether `product` is a plain product code, safe to use as a path component
+fn is_product(product: &str) -> bool {
+    !product.is_empty()
+        && product
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+}
+
+/// Whether `host` is a plain `host[:port]`, safe to write into a `cdns` response
+fn is_host(host: &str) -> bool {
+    !host.is_empty()
+        && host
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b".-_:[]".contains(&b))
+}
+
+/// Reads one line of at most [`MAX_LINE`] bytes, `None` if it is longer
+fn read_line(reader: &mut impl BufRead) -> Result<Option<String>> {
+    let mut line = String::new();
+    reader.take(MAX_LINE).read_line(&mut line)?;
+    if line.len() as u64 == MAX_LINE && !line.ends_with('\n') {
+        return Ok(None);
+    }
+    Ok(Some(line))
+}
+
+impl CdnServer {
+    pub fn bind(addr: impl ToSocketAddrs, cache: CacheByKey, region: &str) -> Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(addr)?,
+            cache,
+            region: region.to_owned(),
+            max_connections: 256,
+            timeout: Duration::from_secs(60),
+            active: AtomicUsize::new(0),
+        })
+    }
+
+    /// Serves at most `max` connections at once, 256 by default
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.max_connections = max.max(1);
+        self
+    }
+
+    /// Drops connections idle for `timeout`, a minute by default
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Serves connections until the listener fails, one thread each
+    #[tracing::instrument(err, skip(self))]
+    pub fn run(&self) -> Result<()> {
+        tracing::info!(addr = %self.local_addr()?, "Serving cache");
+        std::thread::scope(|s| {
+            for stream in self.listener.incoming() {
+                let mut stream = stream?;
+                stream.set_read_timeout(Some(self.timeout))?;
+                stream.set_write_timeout(Some(self.timeout))?;
+                if self.active.fetch_add(1, Ordering::Relaxed) >= self.max_connections {
+                    self.active.fetch_sub(1, Ordering::Relaxed);
+                    tracing::warn!("Too many connections, refusing one");
+                    let _ = write_response(&mut stream, Response::status(503), false);
+                    continue;
+                }
+                let slot = ConnectionSlot(&self.active);
+                s.spawn(move || {
+                    if let Err(e) = self.handle(stream) {
+                        tracing::debug!("Connection failed: {e:#}");
+                    }
+                    drop(slot);
+                });
+            }
+            Ok(())
+        })
+    }
+
+    /// Answers requests on `stream` until the client closes it
+    fn handle(&self, stream: TcpStream) -> Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+        loop {
+            let Some(line) = read_line(&mut reader)? else {
+                return write_response(&mut writer, Response::status(431), false);
+            };
+            if line.is_empty() {
+                return Ok(());
+            }
+            let mut parts = line.split_whitespace();
+            let (method, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+            let mut host = None;
+            let mut range = None;
+            let mut close = false;
+            for count in 0.. {
+                let Some(header) = read_line(&mut reader)? else {
+                    return write_response(&mut writer, Response::status(431), false);
+                };
+                if header.trim().is_empty() {
+                    break;
+                }
+                if count == MAX_HEADERS {
+                    return write_response(&mut writer, Response::status(431), false);
+                }
+                let Some((name, value)) = header.split_once(':') else {
+                    continue;
+                };
+                let value = value.trim().to_owned();
+                match name.trim().to_ascii_lowercase().as_str() {
+                    "host" => host = Some(value),
+                    "range" => range = Some(value),
+                    "connection" => close = value.eq_ignore_ascii_case("close"),
+                    _ => {}
+                }
+            }
+            let host = match host.filter(|x| is_host(x)) {
+                Some(host) => host,
+                None => self.local_addr()?.to_string(),
+            };
+
+            let response = match method {
+                "GET" | "HEAD" => self
+                    .respond(target, &host, range.as_deref())
+                    .unwrap_or_else(|e| {
+                        tracing::warn!("{method} {target} failed: {e:#}");
+                        Response::status(500)
+                    }),
+                _ => Response::status(405),
+            };
+            tracing::debug!(status = response.status, "{method} {target}");
+            write_response(&mut writer, response, method == "HEAD")?;
+            if close {
+                return Ok(());
+            }
+        }
+    }
+
+    fn respond(&self, target: &str, host: &str, range: Option<&str>) -> Result<Response> {
+        let path = target.split('?').next().unwrap_or(target).trim_matches('/');
+        if let Some((kind, key)) = cdn_file(path) {
+            return self.file(kind, key, range);
+        }
+        let Some((product, endpoint)) = path.split_once('/').filter(|(x, _)| is_product(x)) else {
+            return Ok(Response::status(404));
+        };
+        let cached = |endpoint| self.cache.load_tact(product, &self.region, endpoint);
+        Ok(match endpoint {
+            "versions" => match cached("versions") {
+                Ok(text) => Response::text(text),
+                Err(_) => Response::status(404),
+            },
+            "cdns" => match cached("versions") {
+                Ok(versions) => {
+                    Response::text(self.cdns(product, host, versions, cached("cdns").ok()))
+                }
+                Err(_) => Response::status(404),
+            },
+            _ => Response::status(404),
+        })
+    }
+
+    /// A `cdns` response with one entry per region in `versions`, all pointing here
+    fn cdns(&self, product: &str, host: &str, versions: String, cdns: Option<String>) -> String {
+        let versions = load_pipe_separated_vars(versions);
+        let mut regions = versions
+            .column("Region")
+            .map(|i| {
+                versions
+                    .entries()
+                    .filter_map(|mut x| x.nth(i).map(str::to_owned))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        if !regions.contains(&self.region) {
+            regions.insert(0, self.region.clone());
+        }
+        // keep the real CDN path so a mirrored tree and this server look alike
+        let path = cdns
+            .map(load_pipe_separated_vars)
+            .and_then(|cdns| {
+                let name = cdns.column("Name")?;
+                let path = cdns.column("Path")?;
+                cdns.entries()
+                    .map(|x| x.collect::<Vec<_>>())
+                    .find(|x| x.get(name) == Some(&self.region.as_str()))
+                    .and_then(|x| x.get(path).map(|x| x.to_string()))
+            })
+            .unwrap_or_else(|| format!("tpr/{product}"));
+
+        let mut text =
+            "Name!STRING:0|Path!STRING:0|Hosts!STRING:0|ConfigPath!STRING:0\n## seqn = 1\n"
+                .to_owned();
+        for region in regions {
+            text.push_str(&format!("{region}|{path}|{host}|{path}\n"));
+        }
+        text
+    }
+
+    fn file(&self, kind: &str, key: &str, range: Option<&str>) -> Result<Response> {
+        let Some(mut file) = self.cache.open(kind, key)? else {
+            return Ok(Response::status(404));
+        };
+        let len = file.metadata()?.len();
+        let mut response = Response::status(200);
+        let mut body = 0..len;
+        if let Some(range) = range {
+            let Some(range) = parse_range(range, len) else {
+                response.status = 416;
+                response
+                    .headers
+                    .push(("Content-Range", format!("bytes */{len}")));
+                return Ok(response);
+            };
+            response.status = 206;
+            response.headers.push((
+                "Content-Range",
+                format!("bytes {}-{}/{len}", range.start, range.end - 1),
+            ));
+            file.seek(SeekFrom::Start(range.start))?;
+            body = range;
+        }
+        response.headers.push(("Accept-Ranges", "bytes".to_owned()));
+        response.body = Body::File(file, body.end - body.start);
+        Ok(response)
+    }
+}
+
+fn write_response(writer: &mut TcpStream, response: Response, head: bool) -> Result<()> {
+    let length = match &response.body {
+        Body::Empty => 0,
+        Body::Text(text) => text.len() as u64,
+        Body::File(_, len) => *len,
+    };
+    let mut header = format!(
+        "HTTP/1.1 {} {}\r\nContent-Length: {length}\r\n",
+        response.status,
+        reason(response.status)
+    );
+    for (name, value) in &response.headers {
+        header.push_str(&format!("{name}: {value}\r\n"));
+    }
+    header.push_str("\r\n");
+    writer.write_all(header.as_bytes())?;
+    if !head {
+        match response.body {
+            Body::Empty => {}
+            Body::Text(text) => writer.write_all(text.as_bytes())?,
+            Body::File(file, len) => {
+                let copied = std::io::copy(&mut file.take(len), writer)?;
+                anyhow::ensure!(copied == len, "file shrank while serving it");
+            }
+        }
+    }
+    writer.flush().context("writing response")
+}
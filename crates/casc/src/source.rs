@@ -0,0 +1,306 @@
+//! Where encoded blobs come from.
+//!
+//! A [`BlobSource`] hands out the raw BLTE blob stored under an ekey.
+//! Decoding and key lookups happen in [`crate::CascClient`] on top of it, so
+//! the same code works over the CDN, a cache directory, a local install or
+//! an in-memory fixture.
+
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    fmt::Debug,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Result, bail};
+
+use crate::{
+    ArchiveKey, EncodingKey, Error, Index, cache::CacheByKey, cdn::CdnPool, load_archive_index,
+};
+
+pub trait BlobSource: Debug + Send + Sync {
+    /// The encoded blob stored under `ekey`
+    fn get_ekey(&self, ekey: EncodingKey) -> Result<Vec<u8>>;
+
+    /// Whether `ekey` is likely to be found, without fetching it
+    fn has(&self, ekey: EncodingKey) -> bool;
+
+    /// Drops any copy of `ekey` the source keeps, returning whether there was one
+    fn evict(&self, _ekey: EncodingKey) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Fetches `ekey` and runs `decode` on it.
+    ///
+    /// When `decode` finds the blob corrupt it is evicted and fetched once
+    /// more, as long as there was a copy to evict. Other failures, such as a
+    /// missing decryption key, would only repeat and are returned as is.
+    fn get_ekey_checked(
+        &self,
+        ekey: EncodingKey,
+        decode: &dyn Fn(&[u8]) -> Result<Vec<u8>>,
+    ) -> Result<Vec<u8>> {
+        let e = match decode(&self.get_ekey(ekey)?) {
+            Ok(data) => return Ok(data),
+            Err(e) => e,
+        };
+        evict_for_refetch(ekey, e, |ekey| self.evict(ekey))?;
+        decode(&self.get_ekey(ekey)?)
+    }
+}
+
+/// Whether `e` says the data itself is bad, see [`Error::is_corrupt`]
+pub(crate) fn is_corrupt(e: &anyhow::Error) -> bool {
+    Error::find(e).is_some_and(Error::is_corrupt)
+}
+
+/// Decides what to do after the blob `ekey` failed to decode with `e`.
+///
+/// A corrupt blob is dropped with `evict` and `Ok` says to fetch it again.
+/// Otherwise, or if there was no copy to evict, a refetch would fail the
+/// same way and `e` is handed back.
+pub(crate) fn evict_for_refetch(
+    ekey: EncodingKey,
+    e: anyhow::Error,
+    evict: impl FnOnce(EncodingKey) -> Result<bool>,
+) -> Result<()> {
+    if !is_corrupt(&e) || !evict(ekey)? {
+        return Err(e);
+    }
+    tracing::warn!("Evicted {ekey} and refetching: {e:#}");
+    Ok(())
+}
+
+/// The CDN, reading through and filling a cache directory.
+///
+/// Archived blobs are only found through the archive indexes. Given them up
+/// front with [`Self::with_index`], or the archives to load them from with
+/// [`Self::with_archives`], which happens on the first blob that can't be
+/// fetched as a loose file.
+#[derive(Debug)]
+pub struct CdnSource {
+    cdn: Arc<CdnPool>,
+    cache: CacheByKey,
+    /// Archive indexes, without them every ekey is fetched as a loose file
+    index: Mutex<Option<Arc<Index>>>,
+    /// Archives whose indexes are loaded once a loose fetch fails
+    archives: Vec<ArchiveKey>,
+}
+
+impl CdnSource {
+    pub fn new(cdn: Arc<CdnPool>, cache: CacheByKey) -> Self {
+        Self {
+            cdn,
+            cache,
+            index: Mutex::new(None),
+            archives: vec![],
+        }
+    }
+
+    pub fn with_index(self, index: Arc<Index>) -> Self {
+        *self.index.lock().unwrap() = Some(index);
+        self
+    }
+
+    /// Loads the indexes of `archives` when a blob isn't available loose
+    pub fn with_archives(mut self, archives: Vec<ArchiveKey>) -> Self {
+        self.archives = archives;
+        self
+    }
+
+    /// The archive indexes, loading them if there are archives to load them from
+    fn index(&self) -> Result<Option<Arc<Index>>> {
+        let mut index = self.index.lock().unwrap();
+        if index.is_none() && !self.archives.is_empty() {
+            tracing::info!("Loading {} archive indexes", self.archives.len());
+            *index = Some(Arc::new(load_archive_index(
+                &self.cdn,
+                &self.cache,
+                &self.archives,
+            )?));
+        }
+        Ok(index.clone())
+    }
+
+    fn get_archived(
+        &self,
+        ekey: EncodingKey,
+        archive: ArchiveKey,
+        size: usize,
+        offset: usize,
+    ) -> Result<Vec<u8>> {
+        self.cache.get_range(
+            &self.cdn,
+            "data",
+            &ekey.to_string(),
+            &archive.to_string(),
+            Some(offset..offset + size),
+        )
+    }
+}
+
+impl BlobSource for CdnSource {
+    fn get_ekey(&self, ekey: EncodingKey) -> Result<Vec<u8>> {
+        let loaded = self.index.lock().unwrap().clone();
+        if let Some(index) = loaded {
+            return match index.map.get(&ekey) {
+                Some(&(archive, size, offset)) => self.get_archived(ekey, archive, size, offset),
+                None => self.cache.get(&self.cdn, "data", &ekey.to_string()),
+            };
+        }
+        let e = match self.cache.get(&self.cdn, "data", &ekey.to_string()) {
+            Ok(blob) => return Ok(blob),
+            Err(e) => e,
+        };
+        let entry = self
+            .index()
+            .context("loading archive indexes")?
+            .and_then(|x| x.map.get(&ekey).copied());
+        match entry {
+            Some((archive, size, offset)) => self.get_archived(ekey, archive, size, offset),
+            None => Err(e),
+        }
+    }
+
+    /// Always true unless offline, loose files can't be checked for without fetching them
+    fn has(&self, ekey: EncodingKey) -> bool {
+        !self.cache.is_offline() || self.cache.contains("data", &ekey.to_string())
+    }
+
+    fn evict(&self, ekey: EncodingKey) -> Result<bool> {
+        evict_cached(&self.cache, ekey)
+    }
+}
+
+/// A cache directory on its own, never fetching anything
+#[derive(Debug)]
+pub struct CacheSource {
+    cache: CacheByKey,
+}
+
+impl CacheSource {
+    pub fn new(cache: CacheByKey) -> Self {
+        Self {
+            cache: cache.with_offline(true),
+        }
+    }
+}
+
+impl BlobSource for CacheSource {
+    fn get_ekey(&self, ekey: EncodingKey) -> Result<Vec<u8>> {
+        let key = ekey.to_string();
+        self.cache
+            .read("data", &key)?
+            .with_context(|| format!("data/{key} is not cached"))
+    }
+
+    fn has(&self, ekey: EncodingKey) -> bool {
+        self.cache.contains("data", &ekey.to_string())
+    }
+
+    fn evict(&self, ekey: EncodingKey) -> Result<bool> {
+        evict_cached(&self.cache, ekey)
+    }
+}
+
+/// Removes `ekey` from `cache`, returning whether it was there
+pub(crate) fn evict_cached(cache: &CacheByKey, ekey: EncodingKey) -> Result<bool> {
+    let key = ekey.to_string();
+    let cached = cache.contains("data", &key);
+    cache.remove("data", &key)?;
+    Ok(cached)
+}
+
+/// Blobs held in memory, mostly for tests
+#[derive(Debug, Default)]
+pub struct MemorySource {
+    blobs: HashMap<EncodingKey, Vec<u8>>,
+}
+
+impl MemorySource {
+    pub fn insert(&mut self, ekey: EncodingKey, blob: Vec<u8>) {
+        self.blobs.insert(ekey, blob);
+    }
+}
+
+impl BlobSource for MemorySource {
+    fn get_ekey(&self, ekey: EncodingKey) -> Result<Vec<u8>> {
+        self.blobs
+            .get(&ekey)
+            .cloned()
+            .with_context(|| format!("{ekey} not in memory source"))
+    }
+
+    fn has(&self, ekey: EncodingKey) -> bool {
+        self.blobs.contains_key(&ekey)
+    }
+}
+
+/// Tries each source in order, falling back to the next when one fails
+#[derive(Debug)]
+pub struct ChainedSource {
+    sources: Vec<Arc<dyn BlobSource>>,
+}
+
+impl ChainedSource {
+    pub fn new(sources: Vec<Arc<dyn BlobSource>>) -> Self {
+        Self { sources }
+    }
+}
+
+impl BlobSource for ChainedSource {
+    fn get_ekey(&self, ekey: EncodingKey) -> Result<Vec<u8>> {
+        let mut last_error = None;
+        for source in self.sources.iter().filter(|x| x.has(ekey)) {
+            match source.get_ekey(ekey) {
+                Ok(blob) => return Ok(blob),
+                Err(e) => {
+                    tracing::debug!("{source:?} failed for {ekey}: {e:#}");
+                    last_error = Some(e);
+                }
+            }
+        }
+        match last_error {
+            Some(e) => Err(e.context(format!("no source could provide {ekey}"))),
+            None => bail!("no source has {ekey}"),
+        }
+    }
+
+    fn has(&self, ekey: EncodingKey) -> bool {
+        self.sources.iter().any(|x| x.has(ekey))
+    }
+
+    fn evict(&self, ekey: EncodingKey) -> Result<bool> {
+        let mut evicted = false;
+        for source in &self.sources {
+            evicted |= source.evict(ekey)?;
+        }
+        Ok(evicted)
+    }
+
+    /// Moves on to the next source when one's blob still fails `decode` as
+    /// corrupt, any other `decode` failure would be the same everywhere
+    fn get_ekey_checked(
+        &self,
+        ekey: EncodingKey,
+        decode: &dyn Fn(&[u8]) -> Result<Vec<u8>>,
+    ) -> Result<Vec<u8>> {
+        let unfixable = Cell::new(false);
+        let decode = |blob: &[u8]| decode(blob).inspect_err(|e| unfixable.set(!is_corrupt(e)));
+        let mut last_error = None;
+        for source in self.sources.iter().filter(|x| x.has(ekey)) {
+            match source.get_ekey_checked(ekey, &decode) {
+                Ok(data) => return Ok(data),
+                Err(e) if unfixable.get() => return Err(e),
+                Err(e) => {
+                    tracing::debug!("{source:?} failed for {ekey}: {e:#}");
+                    last_error = Some(e);
+                }
+            }
+        }
+        match last_error {
+            Some(e) => Err(e.context(format!("no source could provide {ekey}"))),
+            None => bail!("no source has {ekey}"),
+        }
+    }
+}
@@ -0,0 +1,513 @@
+//! Synthetic builds and a mock CDN, so tests run without the network.
+//!
+//! [`SyntheticBuild`] encodes a handful of files into everything a real
+//! build has on the CDN: BLTE blobs, encoding and install manifests, an
+//! archive with its `.index`, build and CDN configs and a `versions`
+//! response. [`MockCdn`] writes one into a scratch directory and serves it
+//! with a [`CdnServer`] on a loopback port.
+
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use anyhow::Result;
+
+#[cfg(feature = "tokio")]
+use crate::nonblocking::{self, AsyncCascClient, AsyncTransport};
+use crate::{
+    ArchiveKey, CascClient, ContentKey, EncodingKey, FileKeys, Md5Key, VersionEntry,
+    cache::CacheByKey,
+    cdn_casc_client, format_hex_key, md5hash,
+    serve::CdnServer,
+    transport::{HttpTransport, TactRedirect, Transport},
+};
+
+/// Encoding manifest page size in KiB
+const PAGE_KB: u16 = 4;
+/// Archive index block size
+const INDEX_BLOCK: usize = 4096;
+
+/// A file of a synthetic build and the keys it was stored under
+#[derive(Clone, Debug)]
+pub struct SyntheticFile {
+    pub name: String,
+    pub data: Vec<u8>,
+    pub ckey: ContentKey,
+    pub ekey: EncodingKey,
+    /// Stored in the archive rather than as a loose file
+    pub archived: bool,
+}
+
+/// A build assembled from in-memory files
+#[derive(Debug)]
+pub struct SyntheticBuild {
+    pub product: String,
+    pub region: String,
+    pub versions_name: String,
+    pub build_id: u32,
+    files: Vec<(String, Vec<u8>, bool)>,
+    /// Loose blobs the build config names, with their build config keys
+    config_files: Vec<(String, Vec<u8>)>,
+}
+
+/// What [`SyntheticBuild::write`] produced
+#[derive(Debug)]
+pub struct WrittenBuild {
+    pub version: VersionEntry,
+    pub files: Vec<SyntheticFile>,
+    pub encoding: FileKeys,
+    pub install: FileKeys,
+    pub archive: ArchiveKey,
+}
+
+impl WrittenBuild {
+    pub fn file(&self, name: &str) -> Option<&SyntheticFile> {
+        self.files.iter().find(|x| x.name == name)
+    }
+}
+
+impl SyntheticBuild {
+    pub fn new(product: &str) -> Self {
+        Self {
+            product: product.to_owned(),
+            region: "us".to_owned(),
+            versions_name: "1.0.0.1".to_owned(),
+            build_id: 1,
+            files: vec![],
+            config_files: vec![],
+        }
+    }
+
+    /// The build most tests use: two archived files and a loose one
+    pub fn sample() -> Self {
+        Self::new("casctest")
+            .file("Game.exe", b"MZ pretend executable".repeat(100))
+            .file("Data\\config.txt", "setting = 1\n")
+            .loose_file("Data\\readme.txt", "hello from the mock CDN\n")
+    }
+
+    /// Adds an install entry stored in the archive
+    pub fn file(mut self, name: &str, data: impl Into<Vec<u8>>) -> Self {
+        self.files.push((name.to_owned(), data.into(), true));
+        self
+    }
+
+    /// Adds an install entry stored as a loose file
+    pub fn loose_file(mut self, name: &str, data: impl Into<Vec<u8>>) -> Self {
+        self.files.push((name.to_owned(), data.into(), false));
+        self
+    }
+
+    /// Adds a loose blob the build config names as `key`, e.g. a TVFS manifest as `vfs-root`
+    pub fn config_file(mut self, key: &str, data: impl Into<Vec<u8>>) -> Self {
+        self.config_files.push((key.to_owned(), data.into()));
+        self
+    }
+
+    /// Writes the build under `dir` in the CDN layout, with the `versions`
+    /// response where [`CacheByKey`] keeps TACT responses
+    pub fn write(&self, dir: &Path) -> Result<WrittenBuild> {
+        let mut files = vec![];
+        let mut archive = vec![];
+        let mut archived = vec![];
+        // (ckey, ekey, content size, encoded size) of everything encoding lists
+        let mut entries = vec![];
+        for (name, data, in_archive) in &self.files {
+            let (ekey, blob) = blte(data);
+            entries.push((
+                ContentKey::of(data),
+                ekey,
+                data.len() as u64,
+                blob.len() as u64,
+            ));
+            if *in_archive {
+                archived.push((ekey, blob.len() as u32, archive.len() as u32));
+                archive.extend(&blob);
+            } else {
+                put(dir, "data", &ekey.to_string(), &blob)?;
+            }
+            files.push(SyntheticFile {
+                name: name.clone(),
+                data: data.clone(),
+                ckey: ContentKey::of(data),
+                ekey,
+                archived: *in_archive,
+            });
+        }
+        let index = archive_index(archived);
+        let archive_key = ArchiveKey::of(&index[index.len() - 28..]);
+        put(dir, "data", &archive_key.to_string(), &archive)?;
+        put(dir, "data", &format!("{archive_key}.index"), &index)?;
+
+        let mut config_files = String::new();
+        for (key, data) in &self.config_files {
+            let (keys, entry) = self.put_blob(dir, data)?;
+            entries.push(entry);
+            config_files.push_str(&format!("{key} = {} {}\n", keys.ckey, keys.ekey));
+        }
+        let install = self.put_blob(dir, &install_manifest(&files))?;
+        entries.push(install.1);
+        let encoding = self.put_blob(dir, &encoding_manifest(entries))?;
+
+        let build_config = format!(
+            "# Build Configuration\n\nbuild-name = {}\nencoding = {} {}\ninstall = {} {}\n{config_files}",
+            self.versions_name, encoding.0.ckey, encoding.0.ekey, install.0.ckey, install.0.ekey,
+        );
+        let cdn_config = format!("# CDN Configuration\n\narchives = {archive_key}\n");
+        let (build_config, cdn_config) = (md5_hex(&build_config), md5_hex(&cdn_config));
+        put(dir, "config", &build_config.0, build_config.1.as_bytes())?;
+        put(dir, "config", &cdn_config.0, cdn_config.1.as_bytes())?;
+
+        let version = VersionEntry {
+            region: self.region.clone(),
+            build_config: build_config.0,
+            cdn_config: cdn_config.0,
+            build_id: Some(self.build_id),
+            versions_name: self.versions_name.clone(),
+        };
+        let versions = format!(
+            "Region!STRING:0|BuildConfig!HEX:16|CDNConfig!HEX:16|BuildId!DEC:4|VersionsName!String:0\n\
+             ## seqn = 1\n\
+             {}|{}|{}|{}|{}\n",
+            version.region,
+            version.build_config,
+            version.cdn_config,
+            self.build_id,
+            version.versions_name,
+        );
+        CacheByKey::new(dir).store_tact(&self.product, &self.region, "versions", &versions)?;
+
+        Ok(WrittenBuild {
+            version,
+            files,
+            encoding: encoding.0,
+            install: install.0,
+            archive: archive_key,
+        })
+    }
+
+    /// Stores `data` as a loose blob, returning its keys and encoding entry
+    fn put_blob(
+        &self,
+        dir: &Path,
+        data: &[u8],
+    ) -> Result<(FileKeys, (ContentKey, EncodingKey, u64, u64))> {
+        let ckey = ContentKey::of(data);
+        let (ekey, blob) = blte(data);
+        put(dir, "data", &ekey.to_string(), &blob)?;
+        let entry = (ckey, ekey, data.len() as u64, blob.len() as u64);
+        Ok((FileKeys { ckey, ekey }, entry))
+    }
+}
+
+fn md5_hex(text: &str) -> (String, String) {
+    (
+        format!("{:032x}", md5hash(text.as_bytes())),
+        text.to_owned(),
+    )
+}
+
+/// Writes `data` at `kind/xx/yy/key` under `dir`
+fn put(dir: &Path, kind: &str, key: &str, data: &[u8]) -> Result<()> {
+    let path = dir.join(kind).join(format_hex_key(key));
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    std::fs::write(path, data)?;
+    Ok(())
+}
+
+/// Encodes `data` as a single zlib chunk, returning the ekey and blob
+pub fn blte(data: &[u8]) -> (EncodingKey, Vec<u8>) {
+    let mut chunk = vec![b'Z'];
+    chunk.extend(miniz_oxide::deflate::compress_to_vec_zlib(data, 6));
+    let mut blob = b"BLTE".to_vec();
+    blob.extend(36u32.to_be_bytes());
+    blob.extend([0xf, 0, 0, 1]);
+    blob.extend((chunk.len() as u32).to_be_bytes());
+    blob.extend((data.len() as u32).to_be_bytes());
+    blob.extend(md5hash(&chunk).to_be_bytes());
+    let ekey = EncodingKey::of(&blob);
+    blob.extend(chunk);
+    (ekey, blob)
+}
+
+fn install_manifest(files: &[SyntheticFile]) -> Vec<u8> {
+    let mut data = b"IN".to_vec();
+    data.extend([1, 16]);
+    data.extend(0u16.to_be_bytes());
+    data.extend((files.len() as u32).to_be_bytes());
+    for file in files {
+        data.extend(file.name.as_bytes());
+        data.push(0);
+        data.extend(file.ckey.0.to_be_bytes());
+        data.extend((file.data.len() as u32).to_be_bytes());
+    }
+    data
+}
+
+fn u40(value: u64) -> [u8; 5] {
+    let bytes = value.to_be_bytes();
+    [bytes[3], bytes[4], bytes[5], bytes[6], bytes[7]]
+}
+
+/// Splits `entries` into zero padded pages, returning the page index and pages
+fn pages(entries: Vec<(u128, Vec<u8>)>) -> (Vec<u8>, Vec<u8>) {
+    let page_size = usize::from(PAGE_KB) * 1024;
+    let (mut index, mut pages) = (vec![], vec![]);
+    let mut page = Vec::<u8>::new();
+    let mut first = None;
+    let mut flush = |page: &mut Vec<u8>, first: u128| {
+        page.resize(page_size, 0);
+        index.extend(first.to_be_bytes());
+        index.extend(md5hash(page).to_be_bytes());
+        pages.append(page);
+    };
+    for (key, entry) in entries {
+        if page.len() + entry.len() > page_size {
+            flush(&mut page, first.take().unwrap());
+        }
+        first.get_or_insert(key);
+        page.extend(entry);
+    }
+    if let Some(first) = first {
+        flush(&mut page, first);
+    }
+    (index, pages)
+}
+
+/// An encoding manifest of `(ckey, ekey, content size, encoded size)` entries
+pub(crate) fn encoding_manifest(mut entries: Vec<(ContentKey, EncodingKey, u64, u64)>) -> Vec<u8> {
+    entries.sort_unstable_by_key(|x| x.0);
+    let c2e = entries
+        .iter()
+        .map(|&(ckey, ekey, size, _)| {
+            let mut entry = vec![1];
+            entry.extend(u40(size));
+            entry.extend(ckey.0.to_be_bytes());
+            entry.extend(ekey.0.to_be_bytes());
+            (ckey.0, entry)
+        })
+        .collect();
+    entries.sort_unstable_by_key(|x| x.1);
+    let e2i = entries
+        .iter()
+        .map(|&(_, ekey, _, size)| {
+            let mut entry = ekey.0.to_be_bytes().to_vec();
+            entry.extend(0u32.to_be_bytes());
+            entry.extend(u40(size));
+            (ekey.0, entry)
+        })
+        .collect();
+    let (c_index, c_pages) = pages(c2e);
+    let (e_index, e_pages) = pages(e2i);
+    let especs = b"z\0";
+
+    let mut data = b"EN".to_vec();
+    data.extend([1, 16, 16]);
+    data.extend(PAGE_KB.to_be_bytes());
+    data.extend(PAGE_KB.to_be_bytes());
+    data.extend(((c_index.len() / 32) as u32).to_be_bytes());
+    data.extend(((e_index.len() / 32) as u32).to_be_bytes());
+    data.push(0);
+    data.extend((especs.len() as u32).to_be_bytes());
+    data.extend(especs);
+    data.extend(c_index);
+    data.extend(c_pages);
+    data.extend(e_index);
+    data.extend(e_pages);
+    data.extend(b"z");
+    data
+}
+
+/// A TVFS span as `(ekey, content size, encoded size)`
+pub(crate) type TvfsSpan = (EncodingKey, u32, u32);
+
+/// A TVFS manifest without ckeys for `(path, spans)` in path order.
+///
+/// Every path is either a bare name or one folder deep.
+pub(crate) fn tvfs_manifest(files: &[(&str, Vec<TvfsSpan>)]) -> Vec<u8> {
+    let (mut vfs, mut cft) = (vec![], vec![]);
+    // path table nodes by folder, in order of appearance
+    let mut folders: Vec<(&str, Vec<u8>)> = vec![];
+    for (path, spans) in files {
+        let (folder, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let mut leaf = vec![name.len() as u8];
+        leaf.extend(name.as_bytes());
+        leaf.push(0xff);
+        leaf.extend((vfs.len() as u32).to_be_bytes());
+        match folders.iter_mut().find(|x| x.0 == folder) {
+            Some((_, nodes)) => nodes.extend(leaf),
+            None => folders.push((folder, leaf)),
+        }
+
+        vfs.push(spans.len() as u8);
+        let mut offset = 0u32;
+        for &(ekey, size, encoded_size) in spans {
+            vfs.extend(offset.to_be_bytes());
+            vfs.extend(size.to_be_bytes());
+            vfs.push(cft.len() as u8);
+            cft.extend(&ekey.0.to_be_bytes()[..9]);
+            cft.extend(encoded_size.to_be_bytes());
+            offset += size;
+        }
+    }
+    assert!(cft.len() < 0x100, "CFT offsets are written as one byte");
+
+    let mut table = vec![];
+    for (folder, nodes) in folders {
+        if folder.is_empty() {
+            table.extend(nodes);
+            continue;
+        }
+        table.push(folder.len() as u8);
+        table.extend(folder.as_bytes());
+        table.extend([0, 0xff]);
+        table.extend((0x8000_0000 | (4 + nodes.len() as u32)).to_be_bytes());
+        table.extend(nodes);
+    }
+
+    let header_size = 0x26u32;
+    let mut data = b"TVFS".to_vec();
+    data.extend([1, header_size as u8, 9, 9]);
+    data.extend(0u32.to_be_bytes());
+    let mut offset = header_size;
+    for table in [&table, &vfs, &cft] {
+        data.extend(offset.to_be_bytes());
+        data.extend((table.len() as u32).to_be_bytes());
+        offset += table.len() as u32;
+    }
+    data.extend(2u16.to_be_bytes());
+    data.extend(table);
+    data.extend(vfs);
+    data.extend(cft);
+    data
+}
+
+/// An archive `.index` for `(ekey, size, offset)` entries
+fn archive_index(mut entries: Vec<(EncodingKey, u32, u32)>) -> Vec<u8> {
+    entries.sort_unstable_by_key(|x| x.0);
+    let per_block = INDEX_BLOCK / 24;
+    let (mut blocks, mut lasts, mut hashes) = (vec![], vec![], vec![]);
+    for chunk in entries.chunks(per_block) {
+        let mut block = vec![];
+        for &(ekey, size, offset) in chunk {
+            block.extend(ekey.0.to_be_bytes());
+            block.extend(size.to_be_bytes());
+            block.extend(offset.to_be_bytes());
+        }
+        block.resize(INDEX_BLOCK, 0);
+        lasts.extend(chunk.last().unwrap().0.0.to_be_bytes());
+        hashes.extend(((md5hash(&block) >> 64) as u64).to_be_bytes());
+        blocks.extend(block);
+    }
+    let mut toc = lasts;
+    toc.extend(hashes);
+
+    let mut footer = ((md5hash(&toc) >> 64) as u64).to_be_bytes().to_vec();
+    footer.extend([1, 0, 0, 4, 4, 4, 16, 8]);
+    footer.extend((entries.len() as u32).to_le_bytes());
+    let mut checked = footer[8..].to_vec();
+    checked.resize(20, 0);
+    footer.extend(((md5hash(&checked) >> 64) as u64).to_be_bytes());
+
+    let mut data = blocks;
+    data.extend(toc);
+    data.extend(footer);
+    data
+}
+
+/// A directory under the system temp dir, removed on drop
+#[derive(Debug)]
+pub struct ScratchDir(PathBuf);
+
+impl ScratchDir {
+    pub fn new(name: &str) -> Result<Self> {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "casc-{name}-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&path)?;
+        Ok(Self(path))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A synthetic build served over HTTP from a background thread
+#[derive(Debug)]
+pub struct MockCdn {
+    pub build: WrittenBuild,
+    pub addr: SocketAddr,
+    product: String,
+    dir: ScratchDir,
+}
+
+impl MockCdn {
+    pub fn start(build: &SyntheticBuild) -> Result<Self> {
+        let dir = ScratchDir::new("cdn")?;
+        let written = build.write(dir.path())?;
+        let server = CdnServer::bind("127.0.0.1:0", CacheByKey::new(dir.path()), &build.region)?;
+        let addr = server.local_addr()?;
+        std::thread::spawn(move || server.run());
+        Ok(Self {
+            build: written,
+            addr,
+            product: build.product.clone(),
+            dir,
+        })
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Where the build was written, laid out like the CDN
+    pub fn dir(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// An HTTP transport whose TACT requests go to this server
+    pub fn transport(&self) -> Result<Arc<dyn Transport>> {
+        let http = Arc::new(HttpTransport::new(Default::default())?);
+        Ok(Arc::new(TactRedirect::new(http, &self.url())))
+    }
+    /// A client for the served build, caching in `cache`
+    pub fn client(&self, cache: &ScratchDir) -> Result<CascClient> {
+        cdn_casc_client(
+            self.transport()?,
+            CacheByKey::new(cache.path()),
+            &self.product,
+            &self.build.version.region,
+            None,
+            &[],
+        )
+    }
+
+    /// The async counterpart of [`Self::client`]
+    #[cfg(feature = "tokio")]
+    pub async fn async_client(&self, cache: &ScratchDir) -> Result<AsyncCascClient> {
+        nonblocking::cdn_casc_client(
+            AsyncTransport::default().with_tact(&self.url()),
+            CacheByKey::new(cache.path()),
+            &self.product,
+            &self.build.version.region,
+            None,
+            &[],
+        )
+        .await
+    }
+}
@@ -0,0 +1,327 @@
+//! HTTP transport shared by every CDN and TACT request
+
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    io::{Cursor, Read},
+    ops::Range,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+
+use crate::APP_USER_AGENT;
+
+/// A response whose status has been received but whose body is still streaming
+pub struct TransportResponse {
+    pub status: u16,
+    pub content_length: Option<u64>,
+    pub body: Box<dyn Read + Send>,
+}
+
+impl TransportResponse {
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /// Reads the whole body into memory
+    pub fn bytes(mut self) -> std::io::Result<bytes::Bytes> {
+        let mut data = Vec::with_capacity(self.content_length.unwrap_or(0).min(1 << 26) as usize);
+        self.body.read_to_end(&mut data)?;
+        Ok(data.into())
+    }
+}
+
+impl Debug for TransportResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TransportResponse")
+            .field("status", &self.status)
+            .field("content_length", &self.content_length)
+            .finish()
+    }
+}
+
+/// Issues GET requests.
+///
+/// A `range` ending at `usize::MAX` requests everything from its start.
+///
+/// `Err` means no response was received at all, e.g. a connection failure.
+/// Any HTTP status, including errors, is returned as `Ok`.
+pub trait Transport: Debug + Send + Sync {
+    fn get(&self, url: &str, range: Option<Range<usize>>) -> Result<TransportResponse>;
+
+    /// Like [`Self::get`] without retrying transient failures, for callers
+    /// with another host to turn to
+    fn get_once(&self, url: &str, range: Option<Range<usize>>) -> Result<TransportResponse> {
+        self.get(url, range)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TransportOptions {
+    pub connect_timeout: Duration,
+    /// Whole request timeout, generous since archives are hundreds of MB
+    pub timeout: Duration,
+    /// Retries after the first attempt for transient failures
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for TransportOptions {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(10),
+            timeout: Duration::from_secs(30 * 60),
+            max_retries: 4,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl TransportOptions {
+    /// These options with retries turned off
+    pub(crate) fn once(&self) -> Self {
+        Self {
+            max_retries: 0,
+            ..self.clone()
+        }
+    }
+
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(1 << attempt.min(16))
+            .min(self.max_backoff)
+    }
+
+    /// How long to wait before retrying a response after `attempt` earlier
+    /// retries, `None` if its status isn't transient or retries are used up
+    pub(crate) fn retry_status(
+        &self,
+        attempt: u32,
+        status: u16,
+        headers: &reqwest::header::HeaderMap,
+    ) -> Option<Duration> {
+        if attempt >= self.max_retries || !is_transient(status) {
+            return None;
+        }
+        tracing::debug!(status, "Transient HTTP status");
+        Some(
+            retry_after(headers)
+                .map(|x| x.min(self.max_backoff))
+                .unwrap_or_else(|| self.backoff(attempt)),
+        )
+    }
+
+    /// Like [`Self::retry_status`] for a request that got no response
+    pub(crate) fn retry_error(&self, attempt: u32, e: &reqwest::Error) -> Option<Duration> {
+        // anything else, e.g. a malformed URL, fails the same way every time
+        if attempt >= self.max_retries || !(e.is_connect() || e.is_timeout()) {
+            return None;
+        }
+        tracing::debug!("Transient request error: {e}");
+        Some(self.backoff(attempt))
+    }
+}
+
+/// [`Transport`] over a single pooled `reqwest` client, retrying transient failures.
+///
+/// Empty ranges are answered with an empty 206 without sending anything.
+#[derive(Debug)]
+pub struct HttpTransport {
+    client: reqwest::blocking::Client,
+    options: TransportOptions,
+}
+
+impl HttpTransport {
+    pub fn new(options: TransportOptions) -> Result<Self> {
+        let client = reqwest::blocking::ClientBuilder::new()
+            // .http3_prior_knowledge()
+            .user_agent(APP_USER_AGENT)
+            .connect_timeout(options.connect_timeout)
+            .timeout(options.timeout)
+            .build()
+            .context("building HTTP client")?;
+        Ok(Self { client, options })
+    }
+
+    fn send(
+        &self,
+        url: &str,
+        range: &Option<Range<usize>>,
+    ) -> reqwest::Result<reqwest::blocking::Response> {
+        let mut request = self.client.get(url);
+        if let Some(range) = range {
+            request = request.header(reqwest::header::RANGE, range_header(range));
+        }
+        request.send()
+    }
+
+    fn get_with(
+        &self,
+        url: &str,
+        range: Option<Range<usize>>,
+        options: &TransportOptions,
+    ) -> Result<TransportResponse> {
+        if range.as_ref().is_some_and(|x| x.is_empty()) {
+            // nothing to fetch, and no `Range` header can ask for it
+            return Ok(TransportResponse {
+                status: 206,
+                content_length: Some(0),
+                body: Box::new(std::io::empty()),
+            });
+        }
+        tracing::debug!("Fetching");
+        let mut attempt = 0;
+        loop {
+            let delay = match self.send(url, &range) {
+                Ok(response) => {
+                    let status = response.status().as_u16();
+                    match options.retry_status(attempt, status, response.headers()) {
+                        Some(delay) => delay,
+                        None => {
+                            return Ok(TransportResponse {
+                                status,
+                                content_length: response.content_length(),
+                                body: Box::new(response),
+                            });
+                        }
+                    }
+                }
+                Err(e) => match options.retry_error(attempt, &e) {
+                    Some(delay) => delay,
+                    None => return Err(e).with_context(|| format!("GET {url}")),
+                },
+            };
+            attempt += 1;
+            tracing::info!("Retrying {url} in {delay:?}, attempt {attempt}");
+            std::thread::sleep(delay);
+        }
+    }
+}
+
+/// The `Range` header value for a non-empty `range`
+pub(crate) fn range_header(range: &Range<usize>) -> String {
+    if range.end == usize::MAX {
+        format!("bytes={}-", range.start)
+    } else {
+        format!("bytes={}-{}", range.start, range.end - 1)
+    }
+}
+
+/// Statuses worth retrying against the same host
+fn is_transient(status: u16) -> bool {
+    matches!(status, 408 | 425 | 429 | 500 | 502 | 503 | 504)
+}
+
+/// Parses `Retry-After` as either delay seconds or an HTTP date
+fn retry_after(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    let value = headers
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim();
+    if let Ok(secs) = value.parse() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+impl Transport for HttpTransport {
+    #[tracing::instrument(err, skip(self))]
+    fn get(&self, url: &str, range: Option<Range<usize>>) -> Result<TransportResponse> {
+        self.get_with(url, range, &self.options)
+    }
+
+    #[tracing::instrument(err, skip(self))]
+    fn get_once(&self, url: &str, range: Option<Range<usize>>) -> Result<TransportResponse> {
+        self.get_with(url, range, &self.options.once())
+    }
+}
+
+/// [`Transport`] serving fixed bodies from memory, anything else is a 404
+#[derive(Debug, Default)]
+pub struct MemoryTransport {
+    files: HashMap<String, bytes::Bytes>,
+    requests: Mutex<Vec<String>>,
+}
+
+impl MemoryTransport {
+    pub fn insert(&mut self, url: impl Into<String>, data: impl Into<bytes::Bytes>) {
+        self.files.insert(url.into(), data.into());
+    }
+
+    /// URLs requested so far, in order
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn get(&self, url: &str, range: Option<Range<usize>>) -> Result<TransportResponse> {
+        self.requests.lock().unwrap().push(url.to_owned());
+        let (status, body) = match (self.files.get(url), range) {
+            (None, _) => (404, bytes::Bytes::new()),
+            (Some(data), None) => (200, data.clone()),
+            (Some(data), Some(range)) if range.end == usize::MAX && range.start <= data.len() => {
+                (206, data.slice(range.start..))
+            }
+            (Some(data), Some(range)) if range.end <= data.len() => (206, data.slice(range)),
+            (Some(_), Some(_)) => (416, bytes::Bytes::new()),
+        };
+        Ok(TransportResponse {
+            status,
+            content_length: Some(body.len() as u64),
+            body: Box::new(Cursor::new(body)),
+        })
+    }
+}
+
+/// [`Transport`] sending TACT requests to `url` instead of Blizzard's patch
+/// servers, e.g. a [`crate::serve::CdnServer`], passing the rest to `inner`
+#[derive(Debug)]
+pub struct TactRedirect {
+    inner: Arc<dyn Transport>,
+    url: String,
+}
+
+impl TactRedirect {
+    pub fn new(inner: Arc<dyn Transport>, url: &str) -> Self {
+        Self {
+            inner,
+            url: url.trim_end_matches('/').to_owned(),
+        }
+    }
+}
+
+impl Transport for TactRedirect {
+    fn get(&self, url: &str, range: Option<Range<usize>>) -> Result<TransportResponse> {
+        let tact_path = url
+            .strip_prefix("http://")
+            .and_then(|x| x.split_once('/'))
+            .filter(|(host, _)| host.ends_with(".patch.battle.net:1119"))
+            .map(|(_, path)| path);
+        match tact_path {
+            Some(path) => self.inner.get(&format!("{}/{path}", self.url), range),
+            None => self.inner.get(url, range),
+        }
+    }
+
+    // TACT requests have nowhere else to go, so only CDN requests skip retries
+    fn get_once(&self, url: &str, range: Option<Range<usize>>) -> Result<TransportResponse> {
+        self.inner.get_once(url, range)
+    }
+}
+
+/// [`Transport`] for offline mode, failing every request without touching the network
+#[derive(Debug)]
+pub struct OfflineTransport;
+
+impl Transport for OfflineTransport {
+    fn get(&self, url: &str, _range: Option<Range<usize>>) -> Result<TransportResponse> {
+        anyhow::bail!("{url} is not cached and offline mode is enabled")
+    }
+}
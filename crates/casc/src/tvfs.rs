@@ -0,0 +1,379 @@
+//! TVFS, the virtual file system manifest newer products use as their root.
+//!
+//! A manifest has three tables. The path table is a prefix tree of name
+//! fragments whose leaves point into the VFS table. Each VFS entry is a list
+//! of spans, and each span points into the CFT table for the truncated ekey
+//! and encoded size of that part of the file. A file may itself be another
+//! TVFS manifest, listed under a `vfs-N` key of the build config, whose paths
+//! then continue below it after a `:`.
+
+use std::collections::HashSet;
+
+use anyhow::{Context, Result};
+
+use crate::{
+    CascClient, ContentKey, EncodingKey, FileKeys, TruncatedKey,
+    cursor::Cursor,
+    error::{self, Error, check},
+};
+
+const WHAT: &str = "TVFS";
+
+/// CFT entries carry the content key
+pub const FLAG_INCLUDE_CKEY: u32 = 0x1;
+pub const FLAG_WRITE_SUPPORT: u32 = 0x2;
+pub const FLAG_PATCH_SUPPORT: u32 = 0x4;
+pub const FLAG_LOWERCASE_MANIFEST: u32 = 0x8;
+
+/// Path table node values with this bit are folders, the rest their byte size
+const FOLDER_NODE: u32 = 0x8000_0000;
+/// Span counts above this mark deleted or otherwise unsupported entries
+const MAX_SPANS: u8 = 224;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Span {
+    /// Where in the file's content this span starts
+    pub content_offset: u32,
+    pub content_size: u32,
+    pub ekey: TruncatedKey,
+    pub encoded_size: u32,
+    /// Only present with [`FLAG_INCLUDE_CKEY`]
+    pub ckey: Option<ContentKey>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TvfsFile {
+    /// `/` separated, with a `:` where a nested manifest starts
+    pub path: String,
+    pub spans: Vec<Span>,
+}
+
+impl TvfsFile {
+    pub fn content_size(&self) -> u64 {
+        self.spans.iter().map(|x| u64::from(x.content_size)).sum()
+    }
+}
+
+#[derive(Debug)]
+pub struct Tvfs {
+    pub flags: u32,
+    pub max_depth: u16,
+    pub files: Vec<TvfsFile>,
+}
+
+impl Tvfs {
+    /// Every file with its path, spans and content size
+    pub fn walk(&self) -> impl Iterator<Item = (&str, &[Span], u64)> {
+        self.files
+            .iter()
+            .map(|x| (x.path.as_str(), x.spans.as_slice(), x.content_size()))
+    }
+}
+
+/// Bytes needed to address every offset of a table of `size` bytes
+fn offset_size(size: usize) -> usize {
+    match size {
+        0..=0xff => 1,
+        0x100..=0xffff => 2,
+        0x1_0000..=0xff_ffff => 3,
+        _ => 4,
+    }
+}
+
+fn read_offset(p: &mut Cursor, size: usize) -> Result<usize, Error> {
+    Ok(p.bytes(size)?
+        .iter()
+        .fold(0, |acc, &b| (acc << 8) | usize::from(b)))
+}
+
+/// `data[offset..offset + size]` as its own cursor
+fn table<'a>(
+    data: &'a [u8],
+    offset: u32,
+    size: u32,
+    what: &'static str,
+) -> error::Result<Cursor<'a>> {
+    let (offset, size) = (offset as usize, size as usize);
+    let end = offset
+        .checked_add(size)
+        .filter(|&x| x <= data.len())
+        .ok_or(Error::Truncated { what })?;
+    Ok(Cursor::new(&data[offset..end], what))
+}
+
+#[tracing::instrument(err, skip(data))]
+pub fn parse(data: &[u8]) -> error::Result<Tvfs> {
+    let mut p = Cursor::new(data, WHAT);
+    check!(&p.array()? == b"TVFS", Error::malformed(WHAT, "bad magic"));
+    let version = p.u8()?;
+    check!(
+        version == 1,
+        Error::UnsupportedVersion {
+            what: WHAT,
+            version: version.into(),
+        }
+    );
+    let header_size = p.u8()?;
+    check!(
+        header_size >= 0x26,
+        Error::malformed(WHAT, "header too small")
+    );
+    let ekey_size = p.u8()?;
+    let _patch_key_size = p.u8()?;
+    check!(
+        ekey_size == 9,
+        Error::malformed(WHAT, format!("unsupported ekey size {ekey_size}"))
+    );
+    let flags = p.u32()?;
+    let path_table = table(data, p.u32()?, p.u32()?, "TVFS path table")?;
+    let vfs_table = table(data, p.u32()?, p.u32()?, "TVFS VFS table")?;
+    let cft_table = table(data, p.u32()?, p.u32()?, "TVFS CFT table")?;
+    let max_depth = p.u16()?;
+
+    let mut files = vec![];
+    let path_data = path_table.rest();
+    // folders still open, with where they end and the path length they started at
+    let mut folders = vec![(path_data.len(), 0)];
+    let mut path = String::new();
+    let mut pos = 0;
+    while let Some(&(end, base)) = folders.last() {
+        if pos >= end {
+            folders.pop();
+            if let Some(&(_, parent_base)) = folders.last() {
+                path.truncate(parent_base);
+            }
+            continue;
+        }
+        let mut node = Cursor::new(&path_data[pos..end], "TVFS path table");
+        // a name fragment, optionally wrapped in separators and ending in a node value
+        let pre = node.peek(1)? == [0];
+        if pre {
+            node.skip(1)?;
+        }
+        let mut name = &[][..];
+        if !node.is_empty() && node.peek(1)? != [0xff] {
+            let len = node.u8()?.into();
+            name = node.bytes(len)?;
+        }
+        let mut post = false;
+        if !node.is_empty() && node.peek(1)? == [0] {
+            node.skip(1)?;
+            post = true;
+        }
+        let mut value = None;
+        if !node.is_empty() {
+            if node.peek(1)? == [0xff] {
+                node.skip(1)?;
+                value = Some(node.u32()?);
+            } else {
+                post = true;
+            }
+        }
+        pos = end - node.remaining();
+        if pre {
+            path.push('/');
+        }
+        path.push_str(&String::from_utf8_lossy(name));
+        if post {
+            path.push('/');
+        }
+
+        match value {
+            Some(value) if value & FOLDER_NODE != 0 => {
+                // the folder size counts the node value itself
+                let folder_end = ((value & !FOLDER_NODE) as usize)
+                    .checked_sub(4)
+                    .map(|x| pos + x)
+                    .filter(|&x| x <= end)
+                    .ok_or_else(|| Error::malformed(WHAT, "folder overruns its parent"))?;
+                folders.push((folder_end, path.len()));
+            }
+            Some(value) => {
+                if let Some(spans) = parse_spans(flags, value as usize, vfs_table, cft_table)? {
+                    files.push(TvfsFile {
+                        path: path.clone(),
+                        spans,
+                    });
+                }
+                path.truncate(base);
+            }
+            None => {}
+        }
+    }
+    tracing::debug!(files = files.len(), "Parsed TVFS");
+    Ok(Tvfs {
+        flags,
+        max_depth,
+        files,
+    })
+}
+
+/// The spans of the VFS entry at `offset`, `None` for deleted entries
+fn parse_spans(
+    flags: u32,
+    offset: usize,
+    vfs_table: Cursor,
+    cft_table: Cursor,
+) -> error::Result<Option<Vec<Span>>> {
+    let mut vfs = vfs_table;
+    vfs.skip(offset)?;
+    let count = vfs.u8()?;
+    if count == 0 || count > MAX_SPANS {
+        return Ok(None);
+    }
+    let cft_offset_size = offset_size(cft_table.remaining());
+    let mut spans = Vec::with_capacity(count.into());
+    for _ in 0..count {
+        let content_offset = vfs.u32()?;
+        let content_size = vfs.u32()?;
+        let mut cft = cft_table;
+        cft.skip(read_offset(&mut vfs, cft_offset_size)?)?;
+        let ekey = TruncatedKey::from_bytes(cft.array()?);
+        let encoded_size = cft.u32()?;
+        let ckey = if flags & FLAG_INCLUDE_CKEY != 0 {
+            Some(ContentKey(cft.u128()?))
+        } else {
+            None
+        };
+        spans.push(Span {
+            content_offset,
+            content_size,
+            ekey,
+            encoded_size,
+            ckey,
+        });
+    }
+    Ok(Some(spans))
+}
+
+impl CascClient {
+    /// The ekey a TVFS span names, looked up in encoding
+    pub fn span_ekey(&self, span: &Span) -> Result<EncodingKey> {
+        self.encoding().find_truncated(span.ekey).ok_or_else(|| {
+            Error::NotFound {
+                key: span.ekey.to_string(),
+            }
+            .into()
+        })
+    }
+
+    /// Reads a TVFS file, joining its spans
+    pub fn get_tvfs_file(&self, file: &TvfsFile) -> Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(file.content_size() as usize);
+        for span in &file.spans {
+            data.extend(self.get_by_ekey(self.span_ekey(span)?)?);
+        }
+        Ok(data)
+    }
+
+    /// Every file of the build's TVFS manifests, with nested manifests expanded
+    #[tracing::instrument(err)]
+    pub fn tvfs_files(&self) -> Result<Vec<TvfsFile>> {
+        let root = self
+            .build_config()
+            .get("vfs-root")
+            .context("Missing vfs-root")?
+            .parse::<FileKeys>()?;
+        // nested manifests, every other `vfs-N`
+        let nested = self
+            .build_config()
+            .iter()
+            .filter(|(k, _)| k.starts_with("vfs-") && !k.ends_with("-size") && *k != "vfs-root")
+            .map(|(_, v)| Ok(v.parse::<FileKeys>()?.ekey.truncated()))
+            .collect::<Result<HashSet<_>>>()?;
+
+        let mut files = vec![];
+        let mut seen = HashSet::new();
+        let mut pending = vec![(String::new(), root.ekey)];
+        while let Some((prefix, ekey)) = pending.pop() {
+            if !seen.insert(ekey) {
+                continue;
+            }
+            for mut file in parse(&self.get_by_ekey(ekey)?)?.files {
+                file.path.insert_str(0, &prefix);
+                match file.spans.as_slice() {
+                    [span] if nested.contains(&span.ekey) => {
+                        let ekey = self.span_ekey(span)?;
+                        pending.push((format!("{}:", file.path), ekey));
+                    }
+                    _ => files.push(file),
+                }
+            }
+        }
+        Ok(files)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{MockCdn, ScratchDir, SyntheticBuild, blte, tvfs_manifest};
+
+    #[test]
+    fn parses_paths_and_spans() -> Result<()> {
+        let keys = [0xaa, 0xbb, 0xcc].map(|x| EncodingKey(x << 120 | 0x1234));
+        let tvfs = parse(&tvfs_manifest(&[
+            ("readme.txt", vec![(keys[0], 10, 30)]),
+            ("Data/big.bin", vec![(keys[1], 100, 120), (keys[2], 50, 70)]),
+            ("Data/vfs-1", vec![(keys[2], 50, 70)]),
+        ]))?;
+
+        let paths = tvfs.walk().map(|x| x.0).collect::<Vec<_>>();
+        assert_eq!(paths, ["readme.txt", "Data/big.bin", "Data/vfs-1"]);
+        let big = &tvfs.files[1];
+        assert_eq!(big.content_size(), 150);
+        let spans = big
+            .spans
+            .iter()
+            .map(|x| (x.content_offset, x.content_size, x.encoded_size, x.ckey))
+            .collect::<Vec<_>>();
+        assert_eq!(spans, [(0, 100, 120, None), (100, 50, 70, None)]);
+        assert!(big.spans[0].ekey.matches(keys[1]));
+        assert!(big.spans[1].ekey.matches(keys[2]));
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_tables_past_the_end() {
+        let mut data = tvfs_manifest(&[("a", vec![(EncodingKey(1), 1, 1)])]);
+        data.truncate(data.len() - 1);
+        assert!(matches!(parse(&data), Err(Error::Truncated { .. })));
+    }
+
+    #[test]
+    fn expands_nested_manifests() -> Result<()> {
+        let files = [b"first half ".to_vec(), b"second half".to_vec()];
+        let blobs = files.clone().map(|x| blte(&x));
+        let span = |i: usize| (blobs[i].0, files[i].len() as u32, blobs[i].1.len() as u32);
+        let nested = tvfs_manifest(&[("inner.txt", vec![span(1)])]);
+        let (nested_ekey, nested_blob) = blte(&nested);
+        let root = tvfs_manifest(&[
+            ("Data/joined.bin", vec![span(0), span(1)]),
+            (
+                "nested",
+                vec![(nested_ekey, nested.len() as u32, nested_blob.len() as u32)],
+            ),
+        ]);
+        let build = SyntheticBuild::sample()
+            .file("first", files[0].clone())
+            .loose_file("second", files[1].clone())
+            .config_file("vfs-root", root)
+            .config_file("vfs-1", nested);
+        let cdn = MockCdn::start(&build)?;
+        let cache = ScratchDir::new("client")?;
+        let client = cdn.client(&cache)?;
+
+        let tvfs = client.tvfs_files()?;
+        let paths = tvfs.iter().map(|x| x.path.as_str()).collect::<Vec<_>>();
+        assert_eq!(paths, ["Data/joined.bin", "nested:inner.txt"]);
+        let ekeys = tvfs[0]
+            .spans
+            .iter()
+            .map(|x| client.span_ekey(x))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(ekeys, [blobs[0].0, blobs[1].0]);
+        assert_eq!(client.get_tvfs_file(&tvfs[0])?, files.concat());
+        assert_eq!(client.get_tvfs_file(&tvfs[1])?, files[1]);
+        Ok(())
+    }
+}
@@ -0,0 +1,438 @@
+//! End to end tests against a synthetic build on a loopback mock CDN
+
+use std::{ops::Range, sync::Arc};
+
+use anyhow::Result;
+use casc::{
+    CascClient, ContentKey, Error,
+    audit::AuditOptions,
+    cache::CacheByKey,
+    cdn::CdnPool,
+    download::{DownloadOptions, download},
+    mirror::mirror,
+    source::{ChainedSource, MemorySource},
+    testing::{self, MockCdn, ScratchDir, SyntheticBuild},
+    transport::{MemoryTransport, Transport, TransportResponse},
+};
+
+#[test]
+fn loads_the_current_build() -> Result<()> {
+    let cdn = MockCdn::start(&SyntheticBuild::sample())?;
+    let cache = ScratchDir::new("client")?;
+    let client = cdn.client(&cache)?;
+
+    assert_eq!(client.version(), &cdn.build.version);
+    assert!(client.cdn().primary().starts_with(&cdn.url()));
+    let names = client
+        .install()
+        .files
+        .iter()
+        .map(|x| x.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["Data\\config.txt", "Data\\readme.txt", "Game.exe"]);
+    for file in &cdn.build.files {
+        assert_eq!(client.encoding().c2e(file.ckey)?, file.ekey);
+    }
+    let e = client.get_by_ckey(ContentKey(1)).unwrap_err();
+    assert!(matches!(Error::find(&e), Some(Error::NotFound { .. })));
+    Ok(())
+}
+
+#[test]
+fn reads_loose_and_archived_files() -> Result<()> {
+    let cdn = MockCdn::start(&SyntheticBuild::sample())?;
+    let cache = ScratchDir::new("client")?;
+    let client = cdn.client(&cache)?;
+
+    let loose = cdn.build.file("Data\\readme.txt").unwrap();
+    assert_eq!(client.get_by_ckey(loose.ckey)?, loose.data);
+
+    // not a loose file, so the archive indexes are loaded to find it
+    let archived = cdn.build.file("Game.exe").unwrap();
+    assert_eq!(client.get_by_ckey(archived.ckey)?, archived.data);
+    assert!(client.cache().contains("data", &archived.ekey.to_string()));
+    assert!(
+        client
+            .cache()
+            .contains("data", &format!("{}.index", cdn.build.archive))
+    );
+    Ok(())
+}
+
+#[test]
+fn archive_indexes_can_be_loaded_up_front() -> Result<()> {
+    let cdn = MockCdn::start(&SyntheticBuild::sample())?;
+    let cache = ScratchDir::new("client")?;
+    let client = cdn.client(&cache)?.with_archive_index()?;
+
+    for file in &cdn.build.files {
+        assert_eq!(client.get_by_ckey(file.ckey)?, file.data);
+    }
+    Ok(())
+}
+
+#[test]
+fn refetches_corrupt_cache_entries() -> Result<()> {
+    let cdn = MockCdn::start(&SyntheticBuild::sample())?;
+    let cache = ScratchDir::new("client")?;
+    let client = cdn.client(&cache)?;
+    let file = cdn.build.file("Data\\readme.txt").unwrap();
+    client.get_by_ckey(file.ckey)?;
+
+    // keep the header so only the content check can notice
+    let key = file.ekey.to_string();
+    let path = cache
+        .path()
+        .join("data")
+        .join(&key[0..2])
+        .join(&key[2..4])
+        .join(&key);
+    let mut blob = std::fs::read(&path)?;
+    *blob.last_mut().unwrap() ^= 0xff;
+    std::fs::write(&path, &blob)?;
+
+    assert!(
+        !client
+            .get_by_ckey_unverified(file.ckey)
+            .is_ok_and(|x| x == file.data)
+    );
+    assert_eq!(client.get_by_ckey(file.ckey)?, file.data);
+    assert_ne!(std::fs::read(&path)?, blob);
+    Ok(())
+}
+
+#[test]
+fn complete_part_files_are_refetched() -> Result<()> {
+    let cdn = MockCdn::start(&SyntheticBuild::sample())?;
+    let cache = ScratchDir::new("client")?;
+    let client = cdn.client(&cache)?;
+    let file = cdn.build.file("Data\\readme.txt").unwrap();
+    client.get_by_ckey(file.ckey)?;
+
+    // as if the process died between downloading and renaming
+    let key = file.ekey.to_string();
+    let path = cache
+        .path()
+        .join("data")
+        .join(&key[0..2])
+        .join(&key[2..4])
+        .join(&key);
+    let part = path.with_file_name(format!("{key}.part"));
+    std::fs::rename(&path, &part)?;
+    assert_eq!(client.get_by_ckey(file.ckey)?, file.data);
+
+    let mut longer = std::fs::read(&path)?;
+    longer.extend(b"trailing junk");
+    std::fs::remove_file(&path)?;
+    std::fs::write(&part, longer)?;
+    assert_eq!(client.get_by_ckey(file.ckey)?, file.data);
+    Ok(())
+}
+
+#[test]
+fn busy_part_files_are_left_alone() -> Result<()> {
+    let cdn = MockCdn::start(&SyntheticBuild::sample())?;
+    let cache = ScratchDir::new("client")?;
+    let client = cdn.client(&cache)?;
+    let file = cdn.build.file("Data\\readme.txt").unwrap();
+
+    // another writer is midway through the same download
+    let key = file.ekey.to_string();
+    let dir = cache.path().join("data").join(&key[0..2]).join(&key[2..4]);
+    std::fs::create_dir_all(&dir)?;
+    let part = dir.join(format!("{key}.part"));
+    std::fs::write(&part, b"BLTE")?;
+    let other = std::fs::File::open(&part)?;
+    other.lock()?;
+
+    assert_eq!(client.get_by_ckey(file.ckey)?, file.data);
+    assert_eq!(std::fs::read(&part)?, b"BLTE");
+    let mut names = std::fs::read_dir(&dir)?
+        .map(|entry| Ok(entry?.file_name().into_string().unwrap()))
+        .collect::<Result<Vec<_>>>()?;
+    names.sort();
+    assert_eq!(names, [key.clone(), format!("{key}.part")]);
+    Ok(())
+}
+
+#[test]
+fn concurrent_reads_share_one_cache() -> Result<()> {
+    let cdn = MockCdn::start(&SyntheticBuild::sample())?;
+    let cache = ScratchDir::new("client")?;
+    let client = cdn.client(&cache)?;
+    let file = cdn.build.file("Game.exe").unwrap();
+    std::thread::scope(|s| {
+        let readers = (0..8)
+            .map(|_| s.spawn(|| client.get_by_ckey(file.ckey)))
+            .collect::<Vec<_>>();
+        for reader in readers {
+            assert_eq!(reader.join().unwrap()?, file.data);
+        }
+        Ok(())
+    })
+}
+
+#[test]
+fn archives_are_not_verified_as_blobs() -> Result<()> {
+    let cdn = MockCdn::start(&SyntheticBuild::sample())?;
+    let cache = ScratchDir::new("client")?;
+    let client = cdn.client(&cache)?;
+    let archive = cdn.build.archive.to_string();
+    let data = client.cache().get(client.cdn(), "data", &archive)?;
+    client.cache().verify("data", &archive, &data)?;
+
+    // another handle on the cache learns the archives from cached CDN configs
+    let other = CacheByKey::new(cache.path()).with_verified_reads(true);
+    assert!(other.verify("data", &archive, &data).is_err());
+    other.add_cached_archives()?;
+    assert_eq!(other.read("data", &archive)?, Some(data));
+    Ok(())
+}
+
+#[test]
+fn audit_checks_every_ckey() -> Result<()> {
+    let cdn = MockCdn::start(&SyntheticBuild::sample())?;
+    let cache = ScratchDir::new("client")?;
+    // archived blobs are found once the client loads the archive indexes
+    let client = cdn.client(&cache)?;
+
+    let report = client.audit(&AuditOptions::default())?;
+    assert!(report.failures.is_empty(), "{:?}", report.failures);
+    // the install manifest is listed in encoding too
+    assert_eq!(report.checked, cdn.build.files.len() + 1);
+    assert_eq!(report.archived, 2);
+    assert_eq!(report.loose, 2);
+    Ok(())
+}
+
+#[test]
+fn audit_reads_through_the_client_source() -> Result<()> {
+    let cdn = MockCdn::start(&SyntheticBuild::sample())?;
+    let cache = ScratchDir::new("client")?;
+    let client = cdn.client(&cache)?;
+    // a local copy of one blob that doesn't decode to its ckey
+    let file = cdn.build.file("Game.exe").unwrap();
+    let mut local = MemorySource::default();
+    local.insert(file.ekey, testing::blte(b"stale local copy").1);
+    let fallback = client.source();
+    let client = client.with_source(Arc::new(ChainedSource::new(vec![
+        Arc::new(local),
+        fallback,
+    ])));
+
+    let report = client.audit(&AuditOptions::default())?;
+    let failed = report.failures.iter().map(|x| x.ckey).collect::<Vec<_>>();
+    assert_eq!(failed, [file.ckey]);
+    assert!(!client.cache().contains("data", &file.ekey.to_string()));
+    Ok(())
+}
+
+#[test]
+fn index_only_audit_finds_missing_loose_files() -> Result<()> {
+    let cdn = MockCdn::start(&SyntheticBuild::sample())?;
+    let cache = ScratchDir::new("client")?;
+    let client = cdn.client(&cache)?;
+    let options = AuditOptions {
+        fetch: false,
+        ..Default::default()
+    };
+    assert!(client.audit(&options)?.failures.is_empty());
+
+    let file = cdn.build.file("Data\\readme.txt").unwrap();
+    CacheByKey::new(cdn.dir()).remove("data", &file.ekey.to_string())?;
+    let report = client.audit(&options)?;
+    let failed = report.failures.iter().map(|x| x.ckey).collect::<Vec<_>>();
+    assert_eq!(failed, [file.ckey]);
+    Ok(())
+}
+
+#[test]
+fn offline_client_reads_from_the_cache() -> Result<()> {
+    let cdn = MockCdn::start(&SyntheticBuild::sample())?;
+    let cache = ScratchDir::new("client")?;
+    let file = cdn.build.file("Data\\readme.txt").unwrap();
+    cdn.client(&cache)?.get_by_ckey(file.ckey)?;
+
+    let offline = CascClient::builder("casctest")
+        .cache(CacheByKey::new(cache.path()).with_offline(true))
+        .build()?;
+    assert_eq!(offline.get_by_ckey(file.ckey)?, file.data);
+    Ok(())
+}
+
+#[test]
+fn missing_files_leave_hosts_healthy() -> Result<()> {
+    let mut transport = MemoryTransport::default();
+    transport.insert("http://b.example/tpr/data/ok", "ok");
+    let pool = CdnPool::new(
+        Arc::new(transport),
+        ["http://a.example/tpr/", "http://b.example/tpr/"].map(String::from),
+    )?;
+
+    let e = pool.fetch("data/missing", None).unwrap_err();
+    assert!(matches!(Error::find(&e), Some(Error::NotFound { .. })));
+    assert_eq!(pool.fetch("data/ok", None)?, "ok");
+    assert!(pool.hosts().all(|(_, healthy)| healthy));
+    Ok(())
+}
+
+/// Refuses every request to `a.example`, passing the rest to `inner`
+#[derive(Debug)]
+struct Forbidden(MemoryTransport);
+
+impl Transport for Forbidden {
+    fn get(&self, url: &str, range: Option<Range<usize>>) -> Result<TransportResponse> {
+        if url.starts_with("http://a.example/") {
+            return Ok(TransportResponse {
+                status: 403,
+                content_length: Some(0),
+                body: Box::new(std::io::empty()),
+            });
+        }
+        self.0.get(url, range)
+    }
+}
+
+#[test]
+fn forbidden_hosts_fail_over() -> Result<()> {
+    let mut transport = MemoryTransport::default();
+    transport.insert("http://b.example/tpr/data/ok", "ok");
+    let pool = CdnPool::new(
+        Arc::new(Forbidden(transport)),
+        ["http://a.example/tpr/", "http://b.example/tpr/"].map(String::from),
+    )?;
+
+    assert_eq!(pool.fetch("data/ok", None)?, "ok");
+    let healthy = pool.hosts().map(|(_, healthy)| healthy).collect::<Vec<_>>();
+    assert_eq!(healthy, [false, true]);
+    // a 403 is not a 404, so the file isn't reported as missing
+    let e = pool.fetch("data/missing", None).unwrap_err();
+    assert!(!matches!(Error::find(&e), Some(Error::NotFound { .. })));
+    Ok(())
+}
+
+#[test]
+fn corrupt_archive_entries_fail_on_their_own() -> Result<()> {
+    let cdn = MockCdn::start(&SyntheticBuild::sample())?;
+    let cache = ScratchDir::new("client")?;
+    let client = cdn.client(&cache)?;
+    let index = client.archive_index()?;
+    let good = cdn.build.file("Data\\config.txt").unwrap();
+    let bad = cdn.build.file("Game.exe").unwrap();
+
+    // damage the BLTE header of one entry, leaving its neighbour intact
+    let archive = cdn.build.archive.to_string();
+    let path = cdn
+        .dir()
+        .join("data")
+        .join(&archive[0..2])
+        .join(&archive[2..4])
+        .join(&archive);
+    let mut data = std::fs::read(&path)?;
+    let (_, _, offset) = index.map[&bad.ekey];
+    data[offset + 8] ^= 0xff;
+    std::fs::write(&path, data)?;
+
+    let report = download(
+        client.cdn(),
+        client.cache(),
+        &index,
+        [good.ekey, bad.ekey],
+        &DownloadOptions::default(),
+    )?;
+    assert_eq!(report.requests, 1);
+    assert_eq!(report.downloaded, 1);
+    let failed = report.failed.iter().map(|(k, _)| *k).collect::<Vec<_>>();
+    assert_eq!(failed, [bad.ekey]);
+    assert!(client.cache().contains("data", &good.ekey.to_string()));
+    assert!(!client.cache().contains("data", &bad.ekey.to_string()));
+    Ok(())
+}
+
+#[test]
+fn mirror_copies_the_whole_build() -> Result<()> {
+    let cdn = MockCdn::start(&SyntheticBuild::sample())?;
+    let cache = ScratchDir::new("client")?;
+    let output = ScratchDir::new("mirror")?;
+    // cached archives are copied as they are, and stay cached
+    let archive = cdn.build.archive.to_string();
+    let client = cdn.client(&cache)?;
+    client.cache().get(client.cdn(), "data", &archive)?;
+
+    let report = mirror(
+        cdn.transport()?,
+        CacheByKey::new(cache.path()).with_verified_reads(true),
+        "casctest",
+        "us",
+        None,
+        &[],
+        output.path(),
+        &DownloadOptions::default(),
+    )?;
+    assert!(report.failed.is_empty(), "{:?}", report.failed);
+    assert!(client.cache().contains("data", &archive));
+
+    let mirrored = CacheByKey::new(&report.root);
+    assert!(mirrored.contains("data", &archive));
+    assert!(mirrored.contains("data", &format!("{archive}.index")));
+    for file in cdn.build.files.iter().filter(|x| !x.archived) {
+        assert!(mirrored.contains("data", &file.ekey.to_string()));
+    }
+    Ok(())
+}
+
+#[test]
+fn mirror_skips_corrupt_files() -> Result<()> {
+    let cdn = MockCdn::start(&SyntheticBuild::sample())?;
+    let cache = ScratchDir::new("client")?;
+    let output = ScratchDir::new("mirror")?;
+    let index = cdn.client(&cache)?.archive_index()?;
+
+    // damage a blob inside the archive and a loose blob on the CDN
+    let archived = cdn.build.file("Game.exe").unwrap();
+    let loose = cdn.build.file("Data\\readme.txt").unwrap();
+    let (_, _, offset) = index.map[&archived.ekey];
+    for (key, offset) in [
+        (cdn.build.archive.to_string(), offset),
+        (loose.ekey.to_string(), 0),
+    ] {
+        let path = cdn
+            .dir()
+            .join("data")
+            .join(&key[0..2])
+            .join(&key[2..4])
+            .join(&key);
+        let mut data = std::fs::read(&path)?;
+        data[offset + 8] ^= 0xff;
+        std::fs::write(&path, data)?;
+    }
+
+    let report = mirror(
+        cdn.transport()?,
+        CacheByKey::new(cache.path()),
+        "casctest",
+        "us",
+        None,
+        &[],
+        output.path(),
+        &DownloadOptions::default(),
+    )?;
+    let mut failed = report
+        .failed
+        .iter()
+        .map(|(x, _)| x.clone())
+        .collect::<Vec<_>>();
+    failed.sort();
+    let mut expected = [
+        format!("data/{}", cdn.build.archive),
+        format!("data/{}", loose.ekey),
+    ];
+    expected.sort();
+    assert_eq!(failed, expected);
+
+    let mirrored = CacheByKey::new(&report.root);
+    assert!(!mirrored.contains("data", &cdn.build.archive.to_string()));
+    assert!(!mirrored.contains("data", &loose.ekey.to_string()));
+    assert!(mirrored.contains("data", &format!("{}.index", cdn.build.archive)));
+    Ok(())
+}
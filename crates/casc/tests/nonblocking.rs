@@ -0,0 +1,100 @@
+//! The async client against a synthetic build on a loopback mock CDN
+
+use anyhow::Result;
+use casc::{
+    nonblocking::{AsyncCdn, AsyncTransport},
+    testing::{MockCdn, ScratchDir, SyntheticBuild},
+    transport::TransportOptions,
+};
+
+#[tokio::test]
+async fn loads_the_current_build() -> Result<()> {
+    let cdn = MockCdn::start(&SyntheticBuild::sample())?;
+    let cache = ScratchDir::new("client")?;
+    let client = cdn.async_client(&cache).await?;
+
+    assert_eq!(client.version(), &cdn.build.version);
+    assert_eq!(client.install().files.len(), cdn.build.files.len());
+    for file in &cdn.build.files {
+        assert_eq!(client.encoding().c2e(file.ckey)?, file.ekey);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn reads_loose_and_archived_files() -> Result<()> {
+    let cdn = MockCdn::start(&SyntheticBuild::sample())?;
+    let cache = ScratchDir::new("client")?;
+    let client = cdn.async_client(&cache).await?.with_archive_index().await?;
+
+    for file in &cdn.build.files {
+        assert_eq!(client.get_by_ckey(file.ckey).await?, file.data);
+        assert!(client.cache().contains("data", &file.ekey.to_string()));
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn refetches_corrupt_cache_entries() -> Result<()> {
+    let cdn = MockCdn::start(&SyntheticBuild::sample())?;
+    let cache = ScratchDir::new("client")?;
+    let client = cdn.async_client(&cache).await?;
+    let file = cdn.build.file("Data\\readme.txt").unwrap();
+    client.get_by_ckey(file.ckey).await?;
+
+    // keep the header so only the content check can notice
+    let key = file.ekey.to_string();
+    let path = cache
+        .path()
+        .join("data")
+        .join(&key[0..2])
+        .join(&key[2..4])
+        .join(&key);
+    let mut blob = std::fs::read(&path)?;
+    *blob.last_mut().unwrap() ^= 0xff;
+    std::fs::write(&path, &blob)?;
+
+    assert_eq!(client.get_by_ckey(file.ckey).await?, file.data);
+    assert_ne!(std::fs::read(&path)?, blob);
+    Ok(())
+}
+
+#[tokio::test]
+async fn archived_files_need_the_archive_index() -> Result<()> {
+    let cdn = MockCdn::start(&SyntheticBuild::sample())?;
+    let cache = ScratchDir::new("client")?;
+    let client = cdn.async_client(&cache).await?;
+
+    let loose = cdn.build.file("Data\\readme.txt").unwrap();
+    assert_eq!(client.get_by_ekey(loose.ekey).await?, loose.data);
+    let archived = cdn.build.file("Game.exe").unwrap();
+    assert!(client.get_by_ekey(archived.ekey).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn unreachable_hosts_are_skipped() -> Result<()> {
+    let cdn = MockCdn::start(&SyntheticBuild::sample())?;
+    let cache = ScratchDir::new("client")?;
+    let prefix = cdn.async_client(&cache).await?.cdn().primary().to_owned();
+    // nothing listens here once the listener is dropped
+    let dead = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let transport = AsyncTransport::default().with_options(TransportOptions {
+        max_retries: 0,
+        ..Default::default()
+    });
+    let pool = AsyncCdn::new(transport, vec![format!("http://{dead}/tpr/"), prefix])?;
+
+    let file = cdn.build.file("Data\\readme.txt").unwrap();
+    let key = file.ekey.to_string();
+    let path = format!("data/{}/{}/{key}", &key[0..2], &key[2..4]);
+    assert_eq!(pool.fetch(&path, Some(0..4)).await?, b"BLTE"[..]);
+    assert_eq!(pool.fetch(&path, Some(4..4)).await?, b""[..]);
+    let health = pool.hosts().map(|(_, healthy)| healthy).collect::<Vec<_>>();
+    assert_eq!(health, [false, true]);
+
+    // a 404 from the live host leaves it healthy
+    assert!(pool.fetch("data/missing", None).await.is_err());
+    assert!(pool.hosts().map(|(_, healthy)| healthy).eq([false, true]));
+    Ok(())
+}
@@ -0,0 +1,110 @@
+//! The Ribbit client against a loopback stand-in server
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::TcpListener,
+    thread::JoinHandle,
+};
+
+use anyhow::Result;
+use casc::ribbit::{RibbitClient, Version, parse_v1};
+use sha2::{Digest, Sha256};
+
+const VERSIONS: &str = "Region!STRING:0|BuildConfig!HEX:16|BuildId!DEC:4\n\
+                        ## seqn = 7\n\
+                        us|0123456789abcdef0123456789abcdef|42\n";
+
+/// Answers one connection with `response`, returning the command it was sent
+fn serve_once(response: Vec<u8>) -> Result<(String, JoinHandle<Result<String>>)> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?.to_string();
+    let handle = std::thread::spawn(move || {
+        let (stream, _) = listener.accept()?;
+        let mut command = String::new();
+        BufReader::new(&stream).read_line(&mut command)?;
+        (&stream).write_all(&response)?;
+        Ok(command)
+    });
+    Ok((addr, handle))
+}
+
+/// A v1 MIME response with a data part, a signature and the checksum epilogue
+fn v1_response(data: &str) -> Vec<u8> {
+    let mut message = format!(
+        "MIME-Version: 1.0\r\n\
+         Content-Type: multipart/alternative; boundary=\"RIBBIT\"\r\n\
+         \r\n\
+         --RIBBIT\r\n\
+         Content-Type: text/plain\r\n\
+         Content-Disposition: version\r\n\
+         \r\n\
+         {data}\r\n\
+         --RIBBIT\r\n\
+         Content-Type: application/cms\r\n\
+         Content-Disposition: cms.sgn\r\n\
+         \r\n\
+         AAAA\r\n\
+         BBBB\r\n\
+         --RIBBIT--\r\n"
+    )
+    .into_bytes();
+    let checksum = hex::encode(Sha256::digest(&message));
+    message.extend(format!("Checksum: {checksum}\r\n").into_bytes());
+    message
+}
+
+#[test]
+fn v1_response_is_verified_and_split() -> Result<()> {
+    let raw = v1_response(VERSIONS);
+    let (addr, server) = serve_once(raw.clone())?;
+    let response = RibbitClient::new(addr, Version::V1).versions("wow")?;
+
+    assert_eq!(server.join().unwrap()?, "v1/products/wow/versions\r\n");
+    assert_eq!(response.data, VERSIONS);
+    assert_eq!(response.signature.as_deref(), Some("AAAABBBB"));
+    let checksum_at = raw.len() - "Checksum: \r\n".len() - 64;
+    assert_eq!(
+        response.checksum,
+        Some(Sha256::digest(&raw[..checksum_at]).into())
+    );
+    let psv = response.into_psv();
+    assert_eq!(psv.column("BuildId"), Some(2));
+    Ok(())
+}
+
+#[test]
+fn v1_response_with_bad_checksum_is_rejected() -> Result<()> {
+    let mut raw = v1_response(VERSIONS);
+    // change the payload after the checksum was taken
+    let at = raw.windows(2).position(|x| x == b"42").unwrap();
+    raw[at] = b'7';
+    let (addr, server) = serve_once(raw.clone())?;
+
+    let error = RibbitClient::new(addr, Version::V1)
+        .versions("wow")
+        .unwrap_err();
+    server.join().unwrap()?;
+    assert!(
+        format!("{error:#}").contains("checksum mismatch"),
+        "{error:#}"
+    );
+    Ok(())
+}
+
+#[test]
+fn v1_response_without_checksum_is_rejected() {
+    let raw = v1_response(VERSIONS);
+    let at = raw.windows(10).position(|x| x == b"Checksum: ").unwrap();
+    assert!(parse_v1(&raw[..at]).is_err());
+}
+
+#[test]
+fn v2_response_is_the_bare_text() -> Result<()> {
+    let (addr, server) = serve_once(VERSIONS.as_bytes().to_vec())?;
+    let response = RibbitClient::new(addr, Version::V2).cdns("wow")?;
+
+    assert_eq!(server.join().unwrap()?, "v2/products/wow/cdns\r\n");
+    assert_eq!(response.data, VERSIONS);
+    assert!(response.signature.is_none() && response.checksum.is_none());
+    Ok(())
+}
@@ -0,0 +1,103 @@
+//! Requests a normal client wouldn't send, against the mock CDN's server
+
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream},
+    time::Duration,
+};
+
+use anyhow::Result;
+use casc::{
+    cache::CacheByKey,
+    serve::CdnServer,
+    testing::{MockCdn, SyntheticBuild},
+};
+
+/// Sends `request` on its own connection and returns the whole response
+fn request(addr: SocketAddr, request: &[u8]) -> Result<String> {
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(request)?;
+    let mut response = vec![];
+    stream.read_to_end(&mut response)?;
+    Ok(String::from_utf8_lossy(&response).into_owned())
+}
+
+/// Like [`request`], returning only the status line
+fn status(cdn: &MockCdn, request: &[u8]) -> Result<String> {
+    let response = self::request(cdn.addr, request)?;
+    Ok(response.lines().next().unwrap_or_default().to_owned())
+}
+
+#[test]
+fn product_must_be_a_plain_name() -> Result<()> {
+    let cdn = MockCdn::start(&SyntheticBuild::sample())?;
+    // where `..` as the product would resolve to
+    let outside = cdn.dir().join("us").join("versions");
+    std::fs::create_dir_all(outside.parent().unwrap())?;
+    std::fs::write(&outside, "not a TACT response")?;
+
+    let ok = status(
+        &cdn,
+        b"GET /casctest/versions HTTP/1.1\r\nConnection: close\r\n\r\n",
+    )?;
+    assert_eq!(ok, "HTTP/1.1 200 OK");
+    let escape = status(
+        &cdn,
+        b"GET /../versions HTTP/1.1\r\nConnection: close\r\n\r\n",
+    )?;
+    assert_eq!(escape, "HTTP/1.1 404 Not Found");
+    Ok(())
+}
+
+#[test]
+fn oversized_headers_are_refused() -> Result<()> {
+    let cdn = MockCdn::start(&SyntheticBuild::sample())?;
+    let long = format!(
+        "GET /casctest/versions HTTP/1.1\r\nX: {}\r\n\r\n",
+        "a".repeat(10_000)
+    );
+    assert!(status(&cdn, long.as_bytes())?.starts_with("HTTP/1.1 431"));
+
+    let many = format!(
+        "GET /casctest/versions HTTP/1.1\r\n{}\r\n",
+        "X: a\r\n".repeat(200)
+    );
+    assert!(status(&cdn, many.as_bytes())?.starts_with("HTTP/1.1 431"));
+    Ok(())
+}
+
+#[test]
+fn host_header_cannot_add_cdns_rows() -> Result<()> {
+    let cdn = MockCdn::start(&SyntheticBuild::sample())?;
+    let response = request(
+        cdn.addr,
+        b"GET /casctest/cdns HTTP/1.1\r\nHost: evil|x|y\r\nConnection: close\r\n\r\n",
+    )?;
+    let (_, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(!body.contains("evil"), "{body}");
+    assert!(body.contains(&cdn.addr.to_string()), "{body}");
+    Ok(())
+}
+
+#[test]
+fn connections_are_capped_and_time_out() -> Result<()> {
+    let cdn = MockCdn::start(&SyntheticBuild::sample())?;
+    let server = CdnServer::bind("127.0.0.1:0", CacheByKey::new(cdn.dir()), "us")?
+        .with_max_connections(1)
+        .with_timeout(Duration::from_millis(200));
+    let addr = server.local_addr()?;
+    std::thread::spawn(move || server.run());
+    let versions = b"GET /casctest/versions HTTP/1.1\r\nConnection: close\r\n\r\n";
+
+    // an idle client holds the only slot until it times out
+    let mut idle = TcpStream::connect(addr)?;
+    std::thread::sleep(Duration::from_millis(50));
+    // refused before the request is read, sending one could only race the close
+    let mut refused = String::new();
+    TcpStream::connect(addr)?.read_to_string(&mut refused)?;
+    assert!(refused.starts_with("HTTP/1.1 503"), "{refused}");
+    idle.read_to_end(&mut vec![])?;
+    std::thread::sleep(Duration::from_millis(50));
+    assert!(request(addr, versions)?.starts_with("HTTP/1.1 200"));
+    Ok(())
+}
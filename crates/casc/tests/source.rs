@@ -0,0 +1,83 @@
+//! Refetching blobs that fail to decode
+
+use std::sync::{
+    Arc,
+    atomic::{AtomicUsize, Ordering},
+};
+
+use anyhow::Result;
+use casc::{
+    EncodingKey, Error,
+    source::{BlobSource, ChainedSource},
+};
+
+/// Counts fetches and evictions of a blob it always has
+#[derive(Debug, Default)]
+struct Counting {
+    fetched: AtomicUsize,
+    evicted: AtomicUsize,
+}
+
+impl BlobSource for Counting {
+    fn get_ekey(&self, _ekey: EncodingKey) -> Result<Vec<u8>> {
+        self.fetched.fetch_add(1, Ordering::Relaxed);
+        Ok(b"blob".to_vec())
+    }
+
+    fn has(&self, _ekey: EncodingKey) -> bool {
+        true
+    }
+
+    fn evict(&self, _ekey: EncodingKey) -> Result<bool> {
+        self.evicted.fetch_add(1, Ordering::Relaxed);
+        Ok(true)
+    }
+}
+
+fn missing_key(_: &[u8]) -> Result<Vec<u8>> {
+    Err(Error::MissingDecryptionKey { key_name: 1 }.into())
+}
+
+fn corrupt(_: &[u8]) -> Result<Vec<u8>> {
+    Err(Error::Truncated { what: "BLTE" }.into())
+}
+
+fn counts(source: &Counting) -> (usize, usize) {
+    (
+        source.fetched.load(Ordering::Relaxed),
+        source.evicted.load(Ordering::Relaxed),
+    )
+}
+
+#[test]
+fn only_corrupt_blobs_are_refetched() {
+    let source = Counting::default();
+    assert!(
+        source
+            .get_ekey_checked(EncodingKey(1), &missing_key)
+            .is_err()
+    );
+    assert_eq!(counts(&source), (1, 0));
+
+    let source = Counting::default();
+    assert!(source.get_ekey_checked(EncodingKey(1), &corrupt).is_err());
+    assert_eq!(counts(&source), (2, 1));
+}
+
+#[test]
+fn chains_stop_at_failures_other_sources_would_repeat() {
+    let (first, second) = (Arc::new(Counting::default()), Arc::new(Counting::default()));
+    let chain = ChainedSource::new(vec![first.clone(), second.clone()]);
+
+    let e = chain
+        .get_ekey_checked(EncodingKey(1), &missing_key)
+        .unwrap_err();
+    assert!(matches!(
+        Error::find(&e),
+        Some(Error::MissingDecryptionKey { .. })
+    ));
+    assert_eq!((counts(&first), counts(&second)), ((1, 0), (0, 0)));
+
+    assert!(chain.get_ekey_checked(EncodingKey(1), &corrupt).is_err());
+    assert_eq!((counts(&first), counts(&second)), ((3, 1), (2, 1)));
+}
@@ -0,0 +1,180 @@
+//! The HTTP transport's retry and range handling against loopback servers
+
+use std::{
+    io::{Read, Write},
+    net::TcpListener,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::Result;
+use casc::{
+    cdn::CdnPool,
+    transport::{HttpTransport, Transport, TransportOptions},
+};
+
+fn transport() -> Result<HttpTransport> {
+    HttpTransport::new(TransportOptions {
+        initial_backoff: Duration::from_millis(1),
+        ..Default::default()
+    })
+}
+
+/// Answers each connection with the next of `responses`, repeating the last,
+/// and records when each request arrived
+fn serve(responses: Vec<String>) -> Result<(String, Arc<Mutex<Vec<Instant>>>)> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let url = format!("http://{}/", listener.local_addr()?);
+    let requests = Arc::new(Mutex::new(vec![]));
+    let arrivals = requests.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else { return };
+            let _ = stream.read(&mut [0; 1024]);
+            let mut arrivals = arrivals.lock().unwrap();
+            arrivals.push(Instant::now());
+            let response = &responses[(arrivals.len() - 1).min(responses.len() - 1)];
+            let _ = stream.write_all(response.as_bytes());
+        }
+    });
+    Ok((url, requests))
+}
+
+fn response(status: &str, headers: &str) -> String {
+    format!("HTTP/1.1 {status}\r\n{headers}Content-Length: 2\r\nConnection: close\r\n\r\nok")
+}
+
+fn unavailable(headers: &str) -> String {
+    response("503 Service Unavailable", headers)
+}
+
+fn ok() -> String {
+    response("200 OK", "")
+}
+
+/// Gaps between consecutive requests
+fn delays(requests: &Mutex<Vec<Instant>>) -> Vec<Duration> {
+    let requests = requests.lock().unwrap();
+    requests.windows(2).map(|x| x[1] - x[0]).collect()
+}
+
+#[test]
+fn malformed_responses_are_not_retried() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let url = format!("http://{}/", listener.local_addr()?);
+    let connections = Arc::new(AtomicUsize::new(0));
+    let counter = connections.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else { return };
+            counter.fetch_add(1, Ordering::Relaxed);
+            let _ = stream.read(&mut [0; 1024]);
+            let _ = stream.write_all(b"not http\r\n\r\n");
+        }
+    });
+
+    assert!(transport()?.get(&url, None).is_err());
+    assert_eq!(connections.load(Ordering::Relaxed), 1);
+    Ok(())
+}
+
+#[test]
+fn empty_ranges_need_no_request() -> Result<()> {
+    // nothing listens on port 1, a request would fail
+    let response = transport()?.get("http://127.0.0.1:1/", Some(10..10))?;
+    assert_eq!(response.status, 206);
+    assert!(response.bytes()?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn transient_statuses_are_retried_with_backoff() -> Result<()> {
+    let (url, requests) = serve(vec![
+        unavailable(""),
+        unavailable(""),
+        unavailable(""),
+        ok(),
+    ])?;
+    let transport = HttpTransport::new(TransportOptions {
+        initial_backoff: Duration::from_millis(20),
+        max_backoff: Duration::from_millis(50),
+        ..Default::default()
+    })?;
+
+    let response = transport.get(&url, None)?;
+    assert_eq!(response.status, 200);
+    assert_eq!(response.bytes()?, "ok");
+    // 20ms, 40ms, then capped at 50ms
+    let delays = delays(&requests);
+    assert_eq!(delays.len(), 3);
+    for (delay, min) in delays.iter().zip([20, 40, 50]) {
+        assert!(*delay >= Duration::from_millis(min), "{delays:?}");
+    }
+    Ok(())
+}
+
+#[test]
+fn retries_are_limited() -> Result<()> {
+    let (url, requests) = serve(vec![unavailable("")])?;
+    let transport = HttpTransport::new(TransportOptions {
+        initial_backoff: Duration::from_millis(1),
+        max_retries: 2,
+        ..Default::default()
+    })?;
+
+    assert_eq!(transport.get(&url, None)?.status, 503);
+    assert_eq!(requests.lock().unwrap().len(), 3);
+    assert_eq!(transport.get_once(&url, None)?.status, 503);
+    assert_eq!(requests.lock().unwrap().len(), 4);
+    Ok(())
+}
+
+#[test]
+fn retry_after_seconds_are_honoured() -> Result<()> {
+    let (url, requests) = serve(vec![unavailable("Retry-After: 1\r\n"), ok()])?;
+    assert_eq!(transport()?.get(&url, None)?.status, 200);
+    assert!(delays(&requests)[0] >= Duration::from_secs(1));
+    Ok(())
+}
+
+#[test]
+fn retry_after_dates_are_honoured() -> Result<()> {
+    // dates have whole seconds, so this is between two and three seconds away
+    let date = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(3));
+    let (url, requests) = serve(vec![unavailable(&format!("Retry-After: {date}\r\n")), ok()])?;
+    assert_eq!(transport()?.get(&url, None)?.status, 200);
+    assert!(delays(&requests)[0] >= Duration::from_secs(1));
+    Ok(())
+}
+
+#[test]
+fn retry_after_is_capped_by_max_backoff() -> Result<()> {
+    let (url, requests) = serve(vec![unavailable("Retry-After: 3600\r\n"), ok()])?;
+    let transport = HttpTransport::new(TransportOptions {
+        max_backoff: Duration::from_millis(10),
+        ..Default::default()
+    })?;
+    assert_eq!(transport.get(&url, None)?.status, 200);
+    assert!(delays(&requests)[0] < Duration::from_secs(10));
+    Ok(())
+}
+
+#[test]
+fn struggling_hosts_fail_over_without_retrying() -> Result<()> {
+    let (bad, bad_requests) = serve(vec![unavailable("")])?;
+    let (good, _) = serve(vec![ok()])?;
+    // the default backoff would take seconds if the first host were retried
+    let pool = CdnPool::new(
+        Arc::new(HttpTransport::new(Default::default())?),
+        [bad, good],
+    )?;
+
+    let started = Instant::now();
+    assert_eq!(pool.fetch("data/file", None)?, "ok");
+    assert!(started.elapsed() < Duration::from_millis(400));
+    assert_eq!(bad_requests.lock().unwrap().len(), 1);
+    Ok(())
+}